@@ -0,0 +1,568 @@
+//! Module for the "batch" subcommand, which extracts the keys from many sources (wine prefixes or state files), continuing past failures
+
+use std::{
+	collections::BTreeMap,
+	path::{
+		Path,
+		PathBuf,
+	},
+};
+
+use anyhow::Context;
+use colored::Colorize;
+use libade_extract_key::{
+	error::KeyFormatError,
+	key::KeyVerification,
+	pipeline::PipelineState,
+	secret::SecretString,
+	settings::Settings,
+	store::{
+		KeyStore,
+		StoreAdd,
+	},
+};
+use serde::Deserialize;
+
+use crate::{
+	clap_conf::{
+		BatchCli,
+		CliDerive,
+	},
+	output,
+};
+
+/// Default template for "batch", the source name keeps the keys of different sources apart
+pub const BATCH_TEMPLATE: &str = "{source}_adobekey_{n}_{uuid}.{ext}";
+
+/// A single source to extract the keys from
+/// At least one of "prefix", "bottle" and "state" has to be set, a state is continued in the prefix (or bottle)
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Source {
+	/// Name for the summary and the "{source}" placeholder, defaults to the name of the prefix / state file
+	pub name:   Option<String>,
+	/// The wine prefix ADE is installed in
+	pub prefix: Option<PathBuf>,
+	/// A state file (see "--state") to continue from, like "--resume"
+	pub state:  Option<PathBuf>,
+	/// The wine binary to use for this source
+	pub wine:   Option<PathBuf>,
+	/// The CrossOver bottle of this source, instead of "prefix"
+	pub bottle: Option<String>,
+}
+
+impl Source {
+	/// Get the name of the source
+	fn name(&self) -> String {
+		if let Some(name) = &self.name {
+			return name.clone();
+		}
+
+		if let (None, Some(bottle)) = (&self.prefix, &self.bottle) {
+			return bottle.clone();
+		}
+
+		let from_path = if let Some(prefix) = &self.prefix {
+			prefix.file_name()
+		} else {
+			self.state.as_deref().and_then(Path::file_stem)
+		};
+
+		return from_path.map_or_else(
+			|| return String::from("unknown"),
+			|v| return v.to_string_lossy().into_owned(),
+		);
+	}
+
+	/// Make all relative paths relative to "base" (the directory of the manifest)
+	fn resolve(self, base: &Path) -> Self {
+		return Self {
+			prefix: self.prefix.map(|v| return base.join(v)),
+			state: self.state.map(|v| return base.join(v)),
+			wine: self.wine.map(|v| {
+				// a plain binary name (like "wine64") is looked up in PATH
+				if v.components().count() > 1 {
+					return base.join(v);
+				}
+
+				return v;
+			}),
+			..self
+		};
+	}
+}
+
+/// A batch manifest, in TOML (or JSON with a ".json" extension)
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Manifest {
+	#[serde(default, rename = "source")]
+	sources: Vec<Source>,
+}
+
+/// Load the sources from the manifest or directory at "path"
+/// In a directory, every wine prefix (a directory with a "system.reg") and every state file (".json") is a source
+fn load_sources(path: &Path) -> anyhow::Result<Vec<Source>> {
+	if path.is_dir() {
+		let mut paths = std::fs::read_dir(path)
+			.with_context(|| return format!("Failed to read directory \"{}\"", path.display()))?
+			.map(|v| return v.map(|v| return v.path()))
+			.collect::<Result<Vec<PathBuf>, _>>()
+			.with_context(|| return format!("Failed to read directory \"{}\"", path.display()))?;
+		paths.sort();
+
+		let mut sources = Vec::new();
+
+		for path in paths {
+			if path.join("system.reg").is_file() {
+				sources.push(Source {
+					prefix: Some(path),
+					..Default::default()
+				});
+			} else if path.is_file() && path.extension().is_some_and(|v| return v == "json") {
+				sources.push(Source {
+					state: Some(path),
+					..Default::default()
+				});
+			} else {
+				debug!(
+					"Skipping \"{}\", because it is not a wine prefix or state file",
+					path.display()
+				);
+			}
+		}
+
+		return Ok(sources);
+	}
+
+	let content = std::fs::read_to_string(path)
+		.with_context(|| return format!("Failed to read manifest \"{}\"", path.display()))?;
+
+	let manifest: Manifest = if path.extension().is_some_and(|v| return v == "json") {
+		serde_json::from_str(&content).map_err(|err| {
+			return crate::Error::usage(format!("Failed to parse manifest \"{}\": {err}", path.display()));
+		})?
+	} else {
+		toml::from_str(&content).map_err(|err| {
+			return crate::Error::usage(format!("Failed to parse manifest \"{}\": {err}", path.display()));
+		})?
+	};
+
+	let base = path.parent().unwrap_or(Path::new(""));
+	let mut sources = Vec::with_capacity(manifest.sources.len());
+
+	for (i, source) in manifest.sources.into_iter().enumerate() {
+		if source.prefix.is_none() && source.bottle.is_none() && source.state.is_none() {
+			return Err(crate::Error::usage(format!(
+				"Source {} in manifest \"{}\" needs at least one of \"prefix\", \"bottle\" and \"state\"",
+				i + 1,
+				path.display()
+			))
+			.into());
+		}
+
+		sources.push(source.resolve(base));
+	}
+
+	return Ok(sources);
+}
+
+/// Everything that is the same for all sources
+struct BatchContext<'a> {
+	cli:        &'a CliDerive,
+	output:     &'a Path,
+	template:   &'a str,
+	passphrase: Option<SecretString>,
+	store:      Option<KeyStore>,
+}
+
+/// Extract, verify and write the keys of a single "source", returns the fingerprints of the keys
+fn extract_source(ctx: &mut BatchContext, source: &Source, name: &str) -> anyhow::Result<Vec<String>> {
+	let mut state = match &source.state {
+		Some(path) => PipelineState::load(path)?,
+		None => PipelineState::default(),
+	};
+
+	// the state is not saved, a failed source can simply be run again
+	let final_keys = state.run(|_state, _stage| return Ok(()))?;
+
+	let mut entries = crate::entries_from_state(state, final_keys);
+	let write_options = crate::write_options(ctx.cli, ctx.passphrase.as_ref());
+	let mut fingerprints = Vec::with_capacity(entries.len());
+
+	for (i, entry) in entries.iter_mut().enumerate() {
+		// a state file alone does not say which prefix it came from, a bottle is its own prefix (see "wine_prefix")
+		if source.bottle.is_none() {
+			entry.source_prefix.clone_from(&source.prefix);
+		}
+
+		let verification =
+			libade_extract_key::key::verify_key(entry.key.expose(), entry.license_certificate.as_deref())?;
+
+		status!("Key verification: {verification}");
+
+		if verification == KeyVerification::Mismatch {
+			return Err(crate::Error::from(KeyFormatError::KeyMismatch).into());
+		}
+
+		if let Some(store) = &mut ctx.store {
+			match store.add(entry.key.expose(), entry.metadata())? {
+				StoreAdd::Added(fingerprint) => status!("Added key to the key store as {fingerprint}"),
+				StoreAdd::AlreadyPresent(fingerprint) => status!("Key is already in the key store as {fingerprint}"),
+			}
+		}
+
+		let encoded_key = crate::encode_entry(ctx.cli, entry, ctx.cli.format, ctx.passphrase.as_ref())?;
+		let file_path = output::resolve_output_path(
+			Some(ctx.output),
+			Some(ctx.template),
+			&libade_extract_key::naming::KeyNameInfo {
+				source: Some(name),
+				..entry.name_info(i + 1, ctx.cli.format.into())
+			},
+			true,
+		)?;
+
+		let written = output::write_key_file(&file_path, encoded_key.expose(), entry.key.expose(), write_options)?;
+		crate::print_key_write(&file_path, &written);
+
+		fingerprints.push(libade_extract_key::key::fingerprint(entry.key.expose())?);
+	}
+
+	return Ok(fingerprints);
+}
+
+/// Extract the keys of all sources in "batchcli.sources" and print a summary
+pub fn run(cli: &CliDerive, batchcli: &BatchCli) -> anyhow::Result<()> {
+	let sources = load_sources(&batchcli.sources)?;
+
+	if sources.is_empty() {
+		return Err(crate::Error::usage(format!("No sources found in \"{}\"", batchcli.sources.display())).into());
+	}
+
+	let mut ctx = BatchContext {
+		cli,
+		output: cli.output_file_name.as_deref().unwrap_or(Path::new(".")),
+		template: cli.template.as_deref().unwrap_or(BATCH_TEMPLATE),
+		passphrase: if cli.encrypt {
+			Some(crate::passphrase::get_passphrase(&cli.passphrase, true)?)
+		} else {
+			None
+		},
+		store: if cli.store {
+			Some(KeyStore::open(cli.store_dir())?)
+		} else {
+			None
+		},
+	};
+
+	let base_settings = libade_extract_key::settings::get();
+	let mut results: Vec<(String, anyhow::Result<Vec<String>>)> = Vec::with_capacity(sources.len());
+
+	for source in &sources {
+		let name = source.name();
+		status!("Extracting from source \"{name}\"");
+
+		libade_extract_key::settings::set(source_settings(source, &base_settings));
+
+		let res = extract_source(&mut ctx, source, &name);
+
+		if let Err(err) = &res {
+			eprintln!("Source \"{name}\" failed: {err:#}");
+		}
+
+		results.push((name, res));
+	}
+
+	libade_extract_key::settings::set(base_settings);
+
+	let fingerprints = fingerprint_sources(&results);
+	let failed = results.iter().filter(|(_, res)| return res.is_err()).count();
+
+	println!();
+	println!(
+		"Batch summary: {} source(s), {} succeeded, {failed} failed, {} unique key(s)",
+		results.len(),
+		results.len() - failed,
+		fingerprints.len()
+	);
+
+	for (name, res) in &results {
+		match res {
+			Ok(keys) => println!("  [{}] {name}: {} key(s)", "OK".green(), keys.len()),
+			Err(err) => println!("  [{}] {name}: {err:#}", "FAILED".red()),
+		}
+	}
+
+	let duplicates: Vec<_> = fingerprints
+		.iter()
+		.filter(|(_, names)| return names.len() > 1)
+		.collect();
+
+	if !duplicates.is_empty() {
+		println!("Duplicate fingerprints (the same key from multiple sources):");
+
+		for (fingerprint, names) in duplicates {
+			println!("  {fingerprint}: {}", names.join(", "));
+		}
+	}
+
+	if let Some(err) = batch_error(results) {
+		return Err(err);
+	}
+
+	return Ok(());
+}
+
+/// Get the settings for "source", which replaces the prefix, bottle and wine of the "base" settings (from the CLI)
+fn source_settings(source: &Source, base: &Settings) -> Settings {
+	return Settings {
+		// a bottle of the source replaces the prefix from the CLI (or "WINEPREFIX")
+		prefix: if source.bottle.is_some() {
+			None
+		} else {
+			source.prefix.clone().or_else(|| return base.prefix.clone())
+		},
+		wine: source.wine.clone().or_else(|| return base.wine.clone()),
+		// a prefix of the source replaces the bottle from the CLI
+		bottle: source.bottle.clone().or_else(|| {
+			return source.prefix.is_none().then(|| return base.bottle.clone()).flatten();
+		}),
+		..base.clone()
+	};
+}
+
+/// Get the names of the sources each fingerprint of the "results" was extracted from
+fn fingerprint_sources(results: &[(String, anyhow::Result<Vec<String>>)]) -> BTreeMap<&str, Vec<&str>> {
+	let mut fingerprints: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+
+	for (name, res) in results {
+		for fingerprint in res.iter().flatten() {
+			fingerprints.entry(fingerprint).or_default().push(name);
+		}
+	}
+
+	return fingerprints;
+}
+
+/// Get the error the batch ends with, "None" if no source failed
+/// If all failures have the same category, the error keeps it (like a single extraction would), otherwise it is [libade_extract_key::error::ErrorCategory::Other]
+fn batch_error(results: Vec<(String, anyhow::Result<Vec<String>>)>) -> Option<anyhow::Error> {
+	let total = results.len();
+	let mut failures: Vec<anyhow::Error> = results.into_iter().filter_map(|(_, res)| return res.err()).collect();
+
+	if failures.is_empty() {
+		return None;
+	}
+
+	let message = format!("{} of {total} source(s) failed", failures.len());
+	let category = crate::exit::category(&failures[0]);
+
+	if failures.iter().all(|v| return crate::exit::category(v) == category) {
+		return Some(failures.swap_remove(0).context(message));
+	}
+
+	return Some(crate::Error::other(message).into());
+}
+
+#[cfg(test)]
+mod tests {
+	use libade_extract_key::{
+		error::ErrorCategory,
+		file::TempDir,
+	};
+
+	use super::*;
+
+	/// Write "content" to "name" in "dir" and return the path
+	fn write(dir: &TempDir, name: &str, content: &str) -> PathBuf {
+		let path = dir.path().join(name);
+		std::fs::write(&path, content).unwrap();
+
+		return path;
+	}
+
+	#[test]
+	fn toml_manifest() {
+		let dir = TempDir::new("ade-extract-key-test").unwrap();
+		let manifest = write(
+			&dir,
+			"sources.toml",
+			r#"
+[[source]]
+name = "old-laptop"
+prefix = "prefixes/old-laptop"
+wine = "wine64"
+
+[[source]]
+bottle = "ade"
+wine = "/opt/cxoffice/bin/wine"
+
+[[source]]
+state = "states/desktop.json"
+wine = "wines/proton"
+"#,
+		);
+
+		let sources = load_sources(&manifest).unwrap();
+		let names: Vec<String> = sources.iter().map(Source::name).collect();
+		assert_eq!(names, ["old-laptop", "ade", "desktop"]);
+
+		// relative paths are relative to the manifest, plain binary names are looked up in PATH
+		assert_eq!(sources[0].prefix, Some(dir.path().join("prefixes/old-laptop")));
+		assert_eq!(sources[0].wine, Some(PathBuf::from("wine64")));
+		assert_eq!(sources[1].wine, Some(PathBuf::from("/opt/cxoffice/bin/wine")));
+		assert_eq!(sources[2].state, Some(dir.path().join("states/desktop.json")));
+		assert_eq!(sources[2].wine, Some(dir.path().join("wines/proton")));
+	}
+
+	#[test]
+	fn json_manifest() {
+		let dir = TempDir::new("ade-extract-key-test").unwrap();
+		let manifest = write(
+			&dir,
+			"sources.json",
+			r#"{"source": [{"prefix": "a"}, {"bottle": "b", "name": "named"}]}"#,
+		);
+
+		let sources = load_sources(&manifest).unwrap();
+		assert_eq!(sources.len(), 2);
+		assert_eq!(sources[0].prefix, Some(dir.path().join("a")));
+		assert_eq!(sources[0].name(), "a");
+		assert_eq!(sources[1].name(), "named");
+	}
+
+	#[test]
+	fn invalid_manifest() {
+		let dir = TempDir::new("ade-extract-key-test").unwrap();
+		let cases = [
+			("unknown.toml", "[[source]]\nprefix = \"a\"\nunknown = 1\n"),
+			("syntax.toml", "[[source]\n"),
+			("syntax.json", "{\"source\": ["),
+			("empty_source.toml", "[[source]]\nname = \"nothing\"\n"),
+		];
+
+		for (name, content) in cases {
+			let err = load_sources(&write(&dir, name, content)).unwrap_err();
+			assert_eq!(crate::exit::category(&err), ErrorCategory::Usage, "{name}: {err:#}");
+		}
+
+		// a manifest without sources is not a error here, "run" reports that there are none
+		assert!(load_sources(&write(&dir, "none.toml", "")).unwrap().is_empty());
+	}
+
+	#[test]
+	fn directory_scan() {
+		let dir = TempDir::new("ade-extract-key-test").unwrap();
+
+		for prefix in ["prefix-b", "prefix-a"] {
+			std::fs::create_dir(dir.path().join(prefix)).unwrap();
+			write(&dir, &format!("{prefix}/system.reg"), "WINE REGISTRY Version 2\n");
+		}
+
+		write(&dir, "desktop.json", "{}");
+		// neither a prefix nor a state file
+		std::fs::create_dir(dir.path().join("not-a-prefix")).unwrap();
+		write(&dir, "notes.txt", "");
+
+		let sources = load_sources(dir.path()).unwrap();
+		let names: Vec<String> = sources.iter().map(Source::name).collect();
+		assert_eq!(names, ["desktop", "prefix-a", "prefix-b"]);
+		assert_eq!(sources[0].state, Some(dir.path().join("desktop.json")));
+		assert_eq!(sources[1].prefix, Some(dir.path().join("prefix-a")));
+	}
+
+	#[test]
+	fn bottle_or_prefix() {
+		let base = Settings {
+			prefix: Some(PathBuf::from("/cli/prefix")),
+			wine: Some(PathBuf::from("/cli/wine")),
+			timeout: Some(std::time::Duration::from_secs(5)),
+			..Default::default()
+		};
+		let base_bottle = Settings {
+			bottle: Some(String::from("cli-bottle")),
+			..base.clone()
+		};
+
+		// without a prefix or bottle, the ones from the CLI are used
+		let source = Source {
+			state: Some(PathBuf::from("state.json")),
+			..Default::default()
+		};
+		assert_eq!(source_settings(&source, &base), base);
+		assert_eq!(source_settings(&source, &base_bottle), base_bottle);
+
+		// a bottle of the source replaces the prefix from the CLI
+		let source = Source {
+			bottle: Some(String::from("ade")),
+			..Default::default()
+		};
+		let settings = source_settings(&source, &base);
+		assert_eq!(settings.prefix, None);
+		assert_eq!(settings.bottle.as_deref(), Some("ade"));
+		assert_eq!(settings.timeout, base.timeout);
+
+		// a prefix of the source replaces the bottle from the CLI
+		let source = Source {
+			prefix: Some(PathBuf::from("/source/prefix")),
+			wine: Some(PathBuf::from("wine64")),
+			..Default::default()
+		};
+		let settings = source_settings(&source, &base_bottle);
+		assert_eq!(settings.prefix, Some(PathBuf::from("/source/prefix")));
+		assert_eq!(settings.bottle, None);
+		assert_eq!(settings.wine, Some(PathBuf::from("wine64")));
+	}
+
+	#[test]
+	fn duplicate_fingerprints() {
+		let results: Vec<(String, anyhow::Result<Vec<String>>)> = vec![
+			(String::from("a"), Ok(vec![String::from("f1"), String::from("f2")])),
+			(String::from("b"), Err(anyhow::anyhow!("failed"))),
+			(String::from("c"), Ok(vec![String::from("f2")])),
+		];
+
+		let fingerprints = fingerprint_sources(&results);
+		assert_eq!(fingerprints.len(), 2);
+		assert_eq!(fingerprints["f1"], ["a"]);
+		assert_eq!(fingerprints["f2"], ["a", "c"]);
+	}
+
+	#[test]
+	fn failure_category() {
+		/// Get a result of "name" that failed because its registry key is missing
+		fn registry_failure(name: &str) -> (String, anyhow::Result<Vec<String>>) {
+			return (
+				String::from(name),
+				Err(crate::Error::no_adept_reg_key("Activation").into()),
+			);
+		}
+
+		let ok = (String::from("ok"), Ok(vec![String::from("f1")]));
+		assert!(batch_error(vec![ok]).is_none());
+
+		// the same category for all failures is kept
+		let err = batch_error(vec![registry_failure("a"), registry_failure("b")]).unwrap();
+		assert_eq!(crate::exit::category(&err), ErrorCategory::Registry);
+		assert_eq!(err.to_string(), "2 of 2 source(s) failed");
+
+		let ok = (String::from("ok"), Ok(vec![String::from("f1")]));
+		let err = batch_error(vec![ok, registry_failure("a")]).unwrap();
+		assert_eq!(crate::exit::category(&err), ErrorCategory::Registry);
+		assert_eq!(err.to_string(), "1 of 2 source(s) failed");
+
+		// different categories cannot be combined
+		let io_failure = (
+			String::from("io"),
+			Err(crate::Error::io(
+				"Failed to read",
+				std::io::Error::new(std::io::ErrorKind::NotFound, "not found"),
+			)
+			.into()),
+		);
+		let err = batch_error(vec![registry_failure("a"), io_failure]).unwrap();
+		assert_eq!(crate::exit::category(&err), ErrorCategory::Other);
+		assert_eq!(err.to_string(), "2 of 2 source(s) failed");
+	}
+}
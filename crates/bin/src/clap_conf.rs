@@ -1,10 +1,26 @@
 use std::path::PathBuf;
 
 use clap::{
+	parser::ValueSource,
 	ArgAction,
+	ArgMatches,
+	Args,
+	CommandFactory,
+	FromArgMatches,
 	Parser,
 	Subcommand,
+	ValueEnum,
 };
+use libade_extract_key::{
+	format::{
+		Kdf,
+		KeyFormat,
+	},
+	naming::KeyNameInfo,
+};
+use serde::Deserialize;
+
+use crate::config::Config;
 
 /// Trait to check and transform all Command Structures
 trait Check {
@@ -26,8 +42,91 @@ pub struct CliDerive {
 	#[arg(long)]
 	#[cfg(debug_assertions)]
 	pub debugger:         bool,
-	/// Change output file name / directory
+	/// Dont mask anything that looks like key material (long hex / base64) in log messages
+	#[arg(long, global = true)]
+	pub log_secrets:      bool,
+	/// Set how a error is printed (on stderr), the exit code is the same for both
+	#[arg(long, global = true, value_enum, default_value_t)]
+	pub error_format:     ErrorFormat,
+	/// Change output file name / directory, "-" writes the key to stdout.
+	/// Always used as a directory when multiple keys are extracted
 	pub output_file_name: Option<PathBuf>,
+	/// Same as OUTPUT_FILE_NAME
+	#[arg(short, long, value_name = "OUTPUT_FILE_NAME", conflicts_with = "output_file_name")]
+	pub output:           Option<PathBuf>,
+	/// Overwrite existing key files that contain a different key
+	#[arg(long)]
+	pub force:            bool,
+	/// Keep a timestamped copy ("<file>.<timestamp>.bak") of key files before overwriting them
+	#[arg(long)]
+	pub backup:           bool,
+	/// Template for the key file name(s), like "{adobe_id}-{uuid}.{ext}".
+	/// Placeholders are "n" (number of the key), "uuid", "adobe_id", "method", "source" (name of the wine prefix directory) and "ext".
+	/// Defaults to DeDRM's "adobekey_<n>_<uuid>.<ext>" if the account is known, otherwise "ade_key.<ext>"
+	#[arg(long)]
+	pub template:         Option<String>,
+	/// Set the format the key is written as
+	#[arg(short, long, value_enum, default_value_t)]
+	pub format:           FormatArg,
+	/// Encrypt the key with a passphrase as PKCS#8 (PBES2 with AES-256-CBC).
+	/// Only works with "--format der" or "--format pem-pkcs8"
+	#[arg(long)]
+	pub encrypt:          bool,
+	/// Set the key derivation function used for "--encrypt"
+	#[arg(long, value_enum, default_value_t, requires = "encrypt")]
+	pub kdf:              KdfArg,
+	#[command(flatten)]
+	pub passphrase:       PassphraseArgs,
+	/// Save the state to this file after each completed stage, so that a failed extraction can be resumed with "--resume".
+	/// The file contains secrets (like the decrypted device key), so it is only readable by the current user and removed after the extraction completed
+	#[arg(long, value_name = "FILE", conflicts_with = "resume")]
+	pub state:            Option<PathBuf>,
+	/// Continue a extraction from the given state file (see "--state"), after the last completed stage.
+	/// The state file is updated with the new progress
+	#[arg(long, value_name = "FILE")]
+	pub resume:           Option<PathBuf>,
+	/// Dont remove the state file after the extraction completed
+	#[arg(long)]
+	pub keep_state:       bool,
+	/// Add the extracted key(s) to the key store (see subcommand "keys")
+	#[arg(long)]
+	pub store:            bool,
+	/// Path to the key store directory, defaults to the platform data directory
+	#[arg(long, global = true, env = "ADE_EXTRACT_KEY_STORE")]
+	pub store_dir:        Option<PathBuf>,
+	/// Path to the configuration file, defaults to "config.toml" in the platform configuration directory
+	#[arg(long, global = true)]
+	pub config:           Option<PathBuf>,
+	/// The wine prefix ADE is installed in.
+	/// Defaults to the configuration file, then "WINEPREFIX", then wine's default "~/.wine"
+	#[arg(long, global = true)]
+	pub prefix:           Option<PathBuf>,
+	/// The wine binary (like "wine64"), or a wine installation directory (like of Proton or CrossOver), defaults to "wine" from PATH
+	#[arg(long, global = true, env = "WINE")]
+	pub wine:             Option<PathBuf>,
+	/// The CrossOver bottle ADE is installed in, used instead of "--prefix"
+	#[arg(long, global = true)]
+	pub bottle:           Option<String>,
+	/// Path of the winapi-bin, defaults to "ade-extract-winapi-bin.exe" in the current directory
+	#[arg(long, global = true)]
+	pub winapi_bin:       Option<PathBuf>,
+	/// Seconds a single command (like wine or the winapi-bin) may run before it is stopped, "0" disables the timeout.
+	/// Defaults to 120, enough for wine to set up a new prefix
+	#[arg(long, global = true, value_name = "SECONDS")]
+	pub timeout:          Option<u64>,
+	/// Record all commands that are run (with their output) into this bundle, for bug reports.
+	/// The bundle contains secrets (like the decrypted device key), unless "--redact" is given
+	#[arg(long, global = true, value_name = "FILE", conflicts_with = "replay")]
+	pub record:           Option<PathBuf>,
+	/// Remove the secrets and the home directory from the "--record" bundle, it can then only be replayed up to the registry export
+	#[arg(long, global = true, requires = "record")]
+	pub redact:           bool,
+	/// Run against the commands recorded in this bundle (see "--record"), instead of running wine
+	#[arg(long, global = true, value_name = "FILE")]
+	pub replay:           Option<PathBuf>,
+	/// The log level, only set from the configuration file
+	#[arg(skip)]
+	pub log_level:        Option<String>,
 
 	#[command(subcommand)]
 	pub subcommands: Option<SubCommands>,
@@ -35,13 +134,91 @@ pub struct CliDerive {
 
 impl CliDerive {
 	/// Execute clap::Parser::parse and apply custom validation and transformation logic
-	#[must_use]
-	pub fn custom_parse() -> Self {
-		let mut parsed = Self::parse();
+	/// "--help" and "--version" still exit directly, all other errors are returned (as [clap::Error] for invalid arguments)
+	pub fn custom_parse() -> anyhow::Result<Self> {
+		return Self::custom_parse_from(std::env::args_os());
+	}
+
+	/// Like [Self::custom_parse], but with the given arguments (including the binary name)
+	fn custom_parse_from<I, T>(args: I) -> anyhow::Result<Self>
+	where
+		I: IntoIterator<Item = T>,
+		T: Into<std::ffi::OsString> + Clone,
+	{
+		let matches = Self::command().try_get_matches_from(args).map_err(|err| {
+			if !err.use_stderr() {
+				err.exit();
+			}
 
-		Check::check(&mut parsed).expect("Expected the check to not fail"); // TODO: this should maybe be actually handled
+			return err;
+		})?;
+		let mut parsed = Self::from_arg_matches(&matches).map_err(|err| return err.format(&mut Self::command()))?;
 
-		return parsed;
+		parsed.merge_config(&matches)?;
+		Check::check(&mut parsed)?;
+
+		return Ok(parsed);
+	}
+
+	/// Fill in everything that was not given on the CLI (or via environment variables) from the configuration file
+	/// "WINEPREFIX" is not read here, so that the configured prefix takes precedence over it (see [libade_extract_key::decrypt::wine_prefix])
+	fn merge_config(&mut self, matches: &ArgMatches) -> Result<(), crate::Error> {
+		let path = if let Some(path) = &self.config {
+			// a explicitly given configuration file has to exist
+			Some(path.clone())
+		} else {
+			crate::config::find_config_file().filter(|v| return v.is_file())
+		};
+
+		let Some(path) = path else {
+			return Ok(());
+		};
+
+		debug!("Loading configuration file \"{}\"", path.display());
+
+		let config = Config::load(&path)?;
+
+		self.prefix = self.prefix.take().or(config.prefix);
+		self.wine = self.wine.take().or(config.wine);
+		self.bottle = self.bottle.take().or(config.bottle);
+		self.winapi_bin = self.winapi_bin.take().or(config.winapi_bin);
+		self.timeout = self.timeout.take().or(config.timeout);
+		self.template = self.template.take().or(config.template);
+		self.log_level = self.log_level.take().or(config.log_level);
+
+		if self.output_file_name.is_none() && self.output.is_none() {
+			self.output_file_name = config.output;
+		}
+
+		// "format" always has a value, so only the source tells if it was given
+		if let Some(format) = config.format {
+			if matches.value_source("format") != Some(ValueSource::CommandLine) {
+				self.format = format;
+			}
+		}
+
+		if let Some(SubCommands::ExportDedrm(exportcli)) = &mut self.subcommands {
+			exportcli.calibre_config = exportcli.calibre_config.take().or(config.dedrm.calibre_config);
+		}
+
+		return Ok(());
+	}
+
+	/// Get the path of the state file, which is the resumed one if "--resume" is used
+	/// "None" if neither "--state" nor "--resume" is given, then no state is saved
+	#[must_use]
+	pub fn state_path(&self) -> Option<&std::path::Path> {
+		return self.resume.as_deref().or(self.state.as_deref());
+	}
+
+	/// Get the key store directory
+	/// Only call this when the key store is used, because only then it is set by the check
+	#[must_use]
+	pub fn store_dir(&self) -> &std::path::Path {
+		return self
+			.store_dir
+			.as_deref()
+			.expect("Expected store_dir to be set at this point");
 	}
 
 	/// Get if debug is enabled
@@ -53,50 +230,479 @@ impl CliDerive {
 	}
 }
 
-/// Default filename for the output
-const KEY_DEFAULT_FILENAME: &str = "ade_key.der";
-
 impl Check for CliDerive {
 	fn check(&mut self) -> Result<(), crate::Error> {
-		if let Some(p) = self.output_file_name.as_mut() {
-			if p.file_name().is_none() {
-				info!("Output Path was set, but without filename");
-				p.set_file_name(KEY_DEFAULT_FILENAME);
+		if let Some(output) = self.output.take() {
+			self.output_file_name = Some(output);
+		}
+
+		if self.keep_state && self.state_path().is_none() {
+			return Err(crate::Error::usage(
+				"\"--keep-state\" requires \"--state\" or \"--resume\"",
+			));
+		}
+
+		if let Some(resume) = &self.resume {
+			if !resume.is_file() {
+				return Err(crate::Error::usage(format!(
+					"State file \"{}\" does not exist or is not a file",
+					resume.display()
+				)));
+			}
+
+			let resumable = match &self.subcommands {
+				None | Some(SubCommands::AES(_)) => true,
+				Some(SubCommands::ExportDedrm(v)) => v.key.is_none(),
+				Some(_) => false,
+			};
+
+			if !resumable {
+				return Err(crate::Error::usage(
+					"\"--resume\" can only be used for a extraction (no subcommand, \"aes\" or \"export-dedrm\")",
+				));
 			}
 		}
-		if self.output_file_name.is_none() {
-			self.output_file_name = Some(PathBuf::from(KEY_DEFAULT_FILENAME));
+
+		if let Some(SubCommands::AES(aescli)) = &self.subcommands {
+			if self.resume.is_some() {
+				if aescli.adept_key.is_some() {
+					return Err(crate::Error::usage(
+						"ADEPT_KEY cannot be given with \"--resume\", the adept keys of all activations are taken from the state",
+					));
+				}
+			} else if aescli.key.is_none() || aescli.adept_key.is_none() {
+				return Err(crate::Error::usage(
+					"KEY and ADEPT_KEY are required, unless \"--resume\" is used",
+				));
+			}
+		}
+
+		if let Some(template) = &self.template {
+			// render once with dummy values, to catch invalid templates before doing any work
+			libade_extract_key::naming::render_template(
+				template,
+				&KeyNameInfo {
+					ext: KeyFormat::from(self.format).extension(),
+					..Default::default()
+				},
+			)?;
+		}
+
+		if self.encrypt {
+			if !self.format.encryptable() {
+				return Err(crate::Error::usage(
+					"\"--encrypt\" only works with \"--format der\" or \"--format pem-pkcs8\"",
+				));
+			}
+
+			if matches!(self.subcommands, Some(SubCommands::DecryptKeyfile(_))) {
+				return Err(crate::Error::usage(
+					"\"--encrypt\" cannot be used with \"decrypt-keyfile\"",
+				));
+			}
+		}
+
+		if matches!(self.subcommands, Some(SubCommands::Batch(_)))
+			&& crate::output::is_stdout(self.output_file_name.as_deref())
+		{
+			return Err(crate::Error::usage(
+				"\"batch\" cannot write the keys to stdout, use a directory instead",
+			));
+		}
+
+		if self.store && matches!(self.subcommands, Some(SubCommands::Keys(_))) {
+			return Err(crate::Error::usage("\"--store\" cannot be used with \"keys\""));
+		}
+
+		if self.store || matches!(self.subcommands, Some(SubCommands::Keys(_))) {
+			if self.store_dir.is_none() {
+				self.store_dir = libade_extract_key::store::find_store_dir();
+			}
+
+			if self.store_dir.is_none() {
+				return Err(crate::Error::usage(
+					"Could not find the key store directory, use \"--store-dir\"",
+				));
+			}
+		}
+
+		// the key store is optional for "identify", so there is no error if it cannot be found
+		if matches!(self.subcommands, Some(SubCommands::Identify(_))) && self.store_dir.is_none() {
+			self.store_dir = libade_extract_key::store::find_store_dir();
+		}
+
+		match self.subcommands.as_mut() {
+			Some(SubCommands::AES(v)) => v.check()?,
+			Some(SubCommands::DecryptKeyfile(v)) => v.check()?,
+			Some(SubCommands::ExportDedrm(v)) => v.check()?,
+			Some(SubCommands::Keys(v)) => v.check()?,
+			Some(SubCommands::Batch(v)) => v.check()?,
+			Some(SubCommands::Identify(v)) => v.check()?,
+			Some(SubCommands::Info(_) | SubCommands::Doctor | SubCommands::Completions(_) | SubCommands::Man(_)) => (),
+			#[cfg(feature = "tui")]
+			Some(SubCommands::Tui) => (),
+			None => (),
 		}
 
 		return Ok(());
 	}
 }
 
+/// All formats the key can be written as
+#[derive(Debug, Clone, Copy, PartialEq, Default, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FormatArg {
+	/// Raw PKCS#1 DER, which is what DeDRM expects
+	#[default]
+	Der,
+	/// PKCS#1 PEM ("BEGIN RSA PRIVATE KEY")
+	PemPkcs1,
+	/// PKCS#8 PEM ("BEGIN PRIVATE KEY")
+	PemPkcs8,
+	/// JSON Web Key
+	Jwk,
+	/// Hex encoded PKCS#1 DER
+	Hex,
+	/// Base64 encoded PKCS#1 DER
+	Base64,
+}
+
+impl FormatArg {
+	/// Get if the key can be written encrypted ("--encrypt") in this format
+	#[must_use]
+	pub fn encryptable(self) -> bool {
+		return matches!(self, Self::Der | Self::PemPkcs8);
+	}
+}
+
+/// All formats a error can be printed as
+#[derive(Debug, Clone, Copy, PartialEq, Default, ValueEnum)]
+pub enum ErrorFormat {
+	/// Human-readable message with its causes
+	#[default]
+	Text,
+	/// A single JSON object with the category, exit code, message and causes
+	Json,
+}
+
+/// All key derivation functions that can be used for "--encrypt"
+#[derive(Debug, Clone, Copy, PartialEq, Default, ValueEnum)]
+pub enum KdfArg {
+	/// scrypt (N=2^14, r=8, p=1)
+	#[default]
+	Scrypt,
+	/// PBKDF2 with HMAC-SHA256
+	Pbkdf2,
+}
+
+impl From<KdfArg> for Kdf {
+	fn from(value: KdfArg) -> Self {
+		return match value {
+			KdfArg::Scrypt => Self::Scrypt,
+			KdfArg::Pbkdf2 => Self::Pbkdf2,
+		};
+	}
+}
+
+/// Options for where to get a passphrase from, if none is set, it will be prompted for
+#[derive(Debug, Args, Clone, PartialEq)]
+pub struct PassphraseArgs {
+	/// Read the passphrase from the given environment variable instead of prompting for it
+	#[arg(long, global = true, conflicts_with = "passphrase_file")]
+	pub passphrase_env:  Option<String>,
+	/// Read the passphrase from the first line of the given file instead of prompting for it
+	#[arg(long, global = true)]
+	pub passphrase_file: Option<PathBuf>,
+}
+
+impl From<FormatArg> for KeyFormat {
+	fn from(value: FormatArg) -> Self {
+		return match value {
+			FormatArg::Der => Self::Der,
+			FormatArg::PemPkcs1 => Self::PemPkcs1,
+			FormatArg::PemPkcs8 => Self::PemPkcs8,
+			FormatArg::Jwk => Self::Jwk,
+			FormatArg::Hex => Self::Hex,
+			FormatArg::Base64 => Self::Base64,
+		};
+	}
+}
+
 #[derive(Debug, Subcommand, Clone, PartialEq)]
 #[allow(clippy::upper_case_acronyms)]
 pub enum SubCommands {
 	AES(AESCli),
+	DecryptKeyfile(DecryptKeyfileCli),
+	ExportDedrm(ExportDedrmCli),
+	Keys(KeysCli),
+	Batch(BatchCli),
+	Identify(IdentifyCli),
+	Info(InfoCli),
+	/// Check the environment (wine, prefix, ADE, winapi-bin) step by step, without extracting anything
+	Doctor,
+	Completions(CompletionsCli),
+	Man(ManCli),
+	/// Interactive wizard: choose the prefix, check the environment, choose the accounts, format and destination
+	#[cfg(feature = "tui")]
+	Tui,
 }
 
-/// Resume at the AES decryption stage with the winapi decrypted key
+/// Run only the "aes" stage, with the winapi decrypted key and adept key or with a state file from "--resume"
 #[derive(Debug, Parser, Clone, PartialEq)]
 pub struct AESCli {
-	/// The key from the winapi-bin
-	pub key:       String,
-	/// The adept key from a previous run of the binary
-	pub adept_key: String,
+	/// The key from the winapi-bin, replaces the "dpapi" stage result of the "--resume" state file if given
+	pub key:                 Option<String>,
+	/// The adept key (privateLicenseKey), not needed with "--resume"
+	pub adept_key:           Option<String>,
+	/// The licenseCertificate (base64) to verify the final key against
+	#[arg(long)]
+	pub license_certificate: Option<String>,
 }
 
 impl Check for AESCli {
 	fn check(&mut self) -> Result<(), crate::Error> {
-		if self.key.is_empty() {
-			return Err(crate::Error::other("Key cannot be empty"));
+		if self.key.as_ref().is_some_and(String::is_empty) {
+			return Err(crate::Error::usage("Key cannot be empty"));
+		}
+
+		if self.adept_key.as_ref().is_some_and(String::is_empty) {
+			return Err(crate::Error::usage("Adept Key cannot be empty"));
+		}
+
+		return Ok(());
+	}
+}
+
+/// Decrypt a key written with "--encrypt" back to a plain key (like DER for DeDRM)
+#[derive(Debug, Parser, Clone, PartialEq)]
+pub struct DecryptKeyfileCli {
+	/// The encrypted key file (PEM or DER)
+	pub keyfile: PathBuf,
+}
+
+impl Check for DecryptKeyfileCli {
+	fn check(&mut self) -> Result<(), crate::Error> {
+		if !self.keyfile.is_file() {
+			return Err(crate::Error::usage(format!(
+				"Keyfile \"{}\" does not exist or is not a file",
+				self.keyfile.display()
+			)));
 		}
 
-		if self.adept_key.is_empty() {
-			return Err(crate::Error::other("Adept Key cannot be empty"));
+		return Ok(());
+	}
+}
+
+/// Add the key to the calibre DeDRM plugin configuration ("dedrm.json") instead of writing a key file
+#[derive(Debug, Parser, Clone, PartialEq)]
+pub struct ExportDedrmCli {
+	/// Add this key file (DER) instead of extracting the key, requires "--name"
+	#[arg(long, requires = "name")]
+	pub key:            Option<PathBuf>,
+	/// Name to add the key as, defaults to the Adobe ID of the account
+	#[arg(long)]
+	pub name:           Option<String>,
+	/// Path to the calibre configuration directory, defaults to calibre's default location
+	#[arg(long, env = "CALIBRE_CONFIG_DIRECTORY")]
+	pub calibre_config: Option<PathBuf>,
+}
+
+impl Check for ExportDedrmCli {
+	fn check(&mut self) -> Result<(), crate::Error> {
+		if self.name.as_ref().is_some_and(String::is_empty) {
+			return Err(crate::Error::usage("Name cannot be empty"));
+		}
+
+		if self.calibre_config.is_none() {
+			self.calibre_config = libade_extract_key::dedrm::find_calibre_config_dir();
+		}
+
+		if self.calibre_config.is_none() {
+			return Err(crate::Error::usage(
+				"Could not find the calibre configuration directory, use \"--calibre-config\"",
+			));
 		}
 
 		return Ok(());
 	}
 }
+
+/// Manage the key store
+#[derive(Debug, Parser, Clone, PartialEq)]
+pub struct KeysCli {
+	#[command(subcommand)]
+	pub action: KeysAction,
+}
+
+impl Check for KeysCli {
+	fn check(&mut self) -> Result<(), crate::Error> {
+		let queries: &[String] = match &self.action {
+			KeysAction::List => &[],
+			KeysAction::Show(v) | KeysAction::Remove(v) => std::slice::from_ref(&v.key),
+			KeysAction::Export(v) => &v.keys,
+		};
+
+		if queries.iter().any(String::is_empty) {
+			return Err(crate::Error::usage("Key query cannot be empty"));
+		}
+
+		return Ok(());
+	}
+}
+
+#[derive(Debug, Subcommand, Clone, PartialEq)]
+pub enum KeysAction {
+	/// List all keys in the key store
+	List,
+	/// Show all information about a key in the key store
+	Show(KeysQueryCli),
+	/// Remove a key from the key store
+	Remove(KeysQueryCli),
+	/// Write keys from the key store like a extracted key (with "--format", "--template", etc)
+	Export(KeysExportCli),
+}
+
+/// Select a single key from the key store
+#[derive(Debug, Parser, Clone, PartialEq)]
+pub struct KeysQueryCli {
+	/// Fingerprint (or a unique prefix of it), Adobe ID or user uuid of the key
+	pub key: String,
+}
+
+/// Select keys from the key store to export
+#[derive(Debug, Parser, Clone, PartialEq)]
+pub struct KeysExportCli {
+	/// Fingerprints (or unique prefixes of them), Adobe IDs or user uuids of the keys, exports all keys if none are given
+	pub keys: Vec<String>,
+}
+
+/// Extract the keys from many sources (wine prefixes or state files), continuing past failures.
+/// OUTPUT_FILE_NAME is the directory the keys are written to, "--template" defaults to "<source>_adobekey_<n>_<uuid>.<ext>"
+#[derive(Debug, Parser, Clone, PartialEq)]
+pub struct BatchCli {
+	/// A manifest (TOML, or JSON with a ".json" extension) with a "[[source]]" list, or a directory of wine prefixes and state files
+	pub sources: PathBuf,
+}
+
+impl Check for BatchCli {
+	fn check(&mut self) -> Result<(), crate::Error> {
+		if !self.sources.exists() {
+			return Err(crate::Error::usage(format!(
+				"Path \"{}\" does not exist",
+				self.sources.display()
+			)));
+		}
+
+		return Ok(());
+	}
+}
+
+/// Identify which account / key ADEPT protected ebooks (EPUB, PDF) belong to, only the license metadata is read
+#[derive(Debug, Parser, Clone, PartialEq)]
+pub struct IdentifyCli {
+	/// Ebooks or directories to scan recursively (like a ADE library folder)
+	#[arg(required = true)]
+	pub paths:          Vec<PathBuf>,
+	/// Dont compare with the activations of the current wine prefix, only with the key store
+	#[arg(long)]
+	pub no_activations: bool,
+}
+
+impl Check for IdentifyCli {
+	fn check(&mut self) -> Result<(), crate::Error> {
+		for path in &self.paths {
+			if !path.exists() {
+				return Err(crate::Error::usage(format!(
+					"Path \"{}\" does not exist",
+					path.display()
+				)));
+			}
+		}
+
+		return Ok(());
+	}
+}
+
+/// Print everything that is gathered for the extraction (drive, cpu, username, activations, entropy), without decrypting anything
+#[derive(Debug, Parser, Clone, PartialEq)]
+pub struct InfoCli {
+	/// Print as JSON instead of human-readable text
+	#[arg(long)]
+	pub json:         bool,
+	/// Show secrets (entropy, device key, private license keys) instead of redacting them
+	#[arg(long)]
+	pub show_secrets: bool,
+}
+
+/// Print the shell completion script for the given shell to stdout
+#[derive(Debug, Parser, Clone, PartialEq)]
+pub struct CompletionsCli {
+	/// The shell to generate the completions for
+	#[arg(value_enum)]
+	pub shell: clap_complete::Shell,
+}
+
+/// Write man pages (roff) for the command and every subcommand
+#[derive(Debug, Parser, Clone, PartialEq)]
+pub struct ManCli {
+	/// Directory to write the man pages to
+	#[arg(default_value = ".")]
+	pub out_dir: PathBuf,
+}
+
+#[cfg(test)]
+mod tests {
+	use libade_extract_key::file::TempDir;
+
+	use super::*;
+
+	/// Parse "args" (without the binary name) with the configuration file "config"
+	fn parse_with_config(dir: &TempDir, config: &str, args: &[&str]) -> CliDerive {
+		let config_path = dir.path().join("config.toml");
+		std::fs::write(&config_path, config).unwrap();
+
+		let mut all_args = vec!["ade-extract-key", "--config", config_path.to_str().unwrap()];
+		all_args.extend_from_slice(args);
+
+		return CliDerive::custom_parse_from(all_args).unwrap();
+	}
+
+	#[test]
+	fn cli_before_config() {
+		let dir = TempDir::new("ade-extract-key-test").unwrap();
+		let config = "prefix = \"/config/prefix\"\ntimeout = 30\nformat = \"pem-pkcs8\"\n";
+
+		let cli = parse_with_config(
+			&dir,
+			config,
+			&["--prefix", "/cli/prefix", "--timeout", "10", "--format", "jwk"],
+		);
+		assert_eq!(cli.prefix, Some(PathBuf::from("/cli/prefix")));
+		assert_eq!(cli.timeout, Some(10));
+		assert_eq!(cli.format, FormatArg::Jwk);
+
+		let cli = parse_with_config(&dir, config, &[]);
+		assert_eq!(cli.prefix, Some(PathBuf::from("/config/prefix")));
+		assert_eq!(cli.timeout, Some(30));
+		assert_eq!(cli.format, FormatArg::PemPkcs8);
+
+		// without either, the prefix is left to "WINEPREFIX" and wine's default
+		let cli = parse_with_config(&dir, "", &[]);
+		assert_eq!(cli.prefix, None);
+		assert_eq!(cli.timeout, None);
+		assert_eq!(cli.format, FormatArg::default());
+	}
+
+	#[test]
+	fn config_before_wineprefix() {
+		let dir = TempDir::new("ade-extract-key-test").unwrap();
+
+		// "WINEPREFIX" is not a default of "--prefix", so it cannot replace the configured prefix
+		let command = CliDerive::command();
+		let prefix_arg = command.get_arguments().find(|v| return v.get_id() == "prefix").unwrap();
+		assert_eq!(prefix_arg.get_env(), None);
+
+		let cli = parse_with_config(&dir, "prefix = \"/config/prefix\"\n", &[]);
+		assert_eq!(cli.prefix, Some(PathBuf::from("/config/prefix")));
+	}
+}
@@ -0,0 +1,89 @@
+//! Module for the configuration file, which provides defaults for the CLI
+
+use std::path::{
+	Path,
+	PathBuf,
+};
+
+use serde::Deserialize;
+
+use crate::clap_conf::FormatArg;
+
+/// The configuration file, all values are optional and CLI flags take precedence
+/// Relative paths are relative to the current directory, like on the CLI
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+	/// The wine prefix ADE is installed in
+	pub prefix:     Option<PathBuf>,
+	/// The wine binary or installation directory
+	pub wine:       Option<PathBuf>,
+	/// The CrossOver bottle
+	pub bottle:     Option<String>,
+	/// Path of the winapi-bin
+	pub winapi_bin: Option<PathBuf>,
+	/// Seconds a single command may run
+	pub timeout:    Option<u64>,
+	/// Output file name / directory
+	pub output:     Option<PathBuf>,
+	/// Template for the key file name(s)
+	pub template:   Option<String>,
+	/// Format the key is written as
+	pub format:     Option<FormatArg>,
+	/// Log level (like "info"), used when "-v" and "RUST_LOG" are not set
+	pub log_level:  Option<String>,
+	/// Defaults for "export-dedrm"
+	pub dedrm:      DeDrmConfig,
+}
+
+/// Defaults for "export-dedrm"
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeDrmConfig {
+	/// Path to the calibre configuration directory
+	pub calibre_config: Option<PathBuf>,
+}
+
+/// Find the default configuration file, it does not need to exist
+/// Order: the platform configuration directory, then "HOME" based fallbacks
+pub fn find_config_file() -> Option<PathBuf> {
+	#[cfg(windows)]
+	{
+		return std::env::var_os("APPDATA")
+			.map(|v| return PathBuf::from(v).join("ade-extract-key").join("config.toml"));
+	}
+
+	#[cfg(target_os = "macos")]
+	{
+		return std::env::var_os("HOME")
+			.map(|v| return PathBuf::from(v).join("Library/Application Support/ade-extract-key/config.toml"));
+	}
+
+	#[cfg(not(any(windows, target_os = "macos")))]
+	{
+		if let Some(dir) = std::env::var_os("XDG_CONFIG_HOME").filter(|v| return !v.is_empty()) {
+			return Some(PathBuf::from(dir).join("ade-extract-key").join("config.toml"));
+		}
+
+		return std::env::var_os("HOME").map(|v| return PathBuf::from(v).join(".config/ade-extract-key/config.toml"));
+	}
+}
+
+impl Config {
+	/// Load the configuration file at "path"
+	pub fn load(path: &Path) -> Result<Self, crate::Error> {
+		let content = std::fs::read_to_string(path).map_err(|err| {
+			return crate::Error::usage(format!(
+				"Failed to read configuration file \"{}\": {err}",
+				path.display()
+			));
+		})?;
+
+		return toml::from_str(&content).map_err(|err| {
+			return crate::Error::usage(format!(
+				"Failed to parse configuration file \"{}\": {err}",
+				path.display()
+			));
+		});
+	}
+}
@@ -0,0 +1,175 @@
+//! Module for printing errors and mapping them to the documented exit codes
+
+use std::process::ExitCode;
+
+use libade_extract_key::error::ErrorCategory;
+
+use crate::clap_conf::ErrorFormat;
+
+/// Get the "--error-format" from the raw arguments, for errors that happen before (or while) the arguments are parsed
+pub fn error_format_from_args() -> ErrorFormat {
+	let mut args = std::env::args_os();
+
+	while let Some(arg) = args.next() {
+		let value = if arg == "--error-format" {
+			args.next()
+		} else {
+			arg.to_str()
+				.and_then(|v| return v.strip_prefix("--error-format="))
+				.map(Into::into)
+		};
+
+		if value.is_some_and(|v| return v == "json") {
+			return ErrorFormat::Json;
+		}
+	}
+
+	return ErrorFormat::Text;
+}
+
+/// Get the category of "err", invalid arguments are always [ErrorCategory::Usage]
+pub fn category(err: &anyhow::Error) -> ErrorCategory {
+	if err.is::<clap::Error>() {
+		return ErrorCategory::Usage;
+	}
+
+	return ErrorCategory::of(err.as_ref());
+}
+
+/// Get "err" of "category" as the JSON object that is printed for "--error-format json"
+fn to_json(err: &anyhow::Error, category: ErrorCategory) -> serde_json::Value {
+	let message = if let Some(clap_err) = err.downcast_ref::<clap::Error>() {
+		// only the first line, without the usage
+		let rendered = clap_err.to_string();
+		let first_line = rendered.lines().next().unwrap_or_default();
+
+		first_line.strip_prefix("error: ").unwrap_or(first_line).to_owned()
+	} else {
+		err.to_string()
+	};
+
+	return serde_json::json!({
+		"error": {
+			"category": category.name(),
+			"exit_code": category.exit_code(),
+			"message": message,
+			"causes": err.chain().skip(1).map(ToString::to_string).collect::<Vec<_>>(),
+		},
+	});
+}
+
+/// Print "err" in the given "format" to stderr and return the exit code of its category
+pub fn report(err: &anyhow::Error, format: ErrorFormat) -> ExitCode {
+	let category = category(err);
+
+	match format {
+		ErrorFormat::Text => {
+			if let Some(clap_err) = err.downcast_ref::<clap::Error>() {
+				// keep the formatting (like the usage) of clap
+				let _ = clap_err.print();
+			} else {
+				// same as returning the error from "main"
+				eprintln!("Error: {err:?}");
+			}
+		},
+		ErrorFormat::Json => eprintln!("{}", to_json(err, category)),
+	}
+
+	return ExitCode::from(category.exit_code());
+}
+
+#[cfg(test)]
+mod tests {
+	use clap::CommandFactory;
+	use libade_extract_key::error::{
+		KeyFormatError,
+		WinapiProtocolError,
+	};
+
+	use super::*;
+	use crate::clap_conf::CliDerive;
+
+	/// Get the error of parsing "args" (without the binary name)
+	fn clap_error(args: &[&str]) -> anyhow::Error {
+		return CliDerive::command()
+			.try_get_matches_from(std::iter::once("ade-extract-key").chain(args.iter().copied()))
+			.unwrap_err()
+			.into();
+	}
+
+	#[test]
+	fn exit_codes() {
+		let not_found = || return std::io::Error::new(std::io::ErrorKind::NotFound, "not found");
+		let cases: [(anyhow::Error, ErrorCategory, u8); 9] = [
+			(anyhow::anyhow!("something"), ErrorCategory::Other, 1),
+			(crate::Error::usage("wrong").into(), ErrorCategory::Usage, 2),
+			(clap_error(&["--unknown"]), ErrorCategory::Usage, 2),
+			(
+				crate::Error::environment("no wine").into(),
+				ErrorCategory::Environment,
+				3,
+			),
+			(
+				crate::Error::no_adept_reg_key("Activation").into(),
+				ErrorCategory::Registry,
+				4,
+			),
+			(
+				crate::Error::dpapi("winapi-bin", WinapiProtocolError::MissingResult.into()).into(),
+				ErrorCategory::Dpapi,
+				5,
+			),
+			(
+				crate::Error::from(KeyFormatError::KeyMismatch).into(),
+				ErrorCategory::Format,
+				6,
+			),
+			(
+				crate::Error::io("Failed to read", not_found()).into(),
+				ErrorCategory::Io,
+				7,
+			),
+			// the category is found behind added context
+			(
+				anyhow::Error::from(not_found()).context("Failed to write"),
+				ErrorCategory::Io,
+				7,
+			),
+		];
+
+		for (err, expected, code) in cases {
+			assert_eq!(category(&err), expected, "{err:?}");
+			assert_eq!(expected.exit_code(), code);
+			assert_eq!(report(&err, ErrorFormat::Json), ExitCode::from(code));
+		}
+	}
+
+	#[test]
+	fn json_shape() {
+		let err = anyhow::Error::from(crate::Error::io(
+			"Failed to read \"key.der\"",
+			std::io::Error::new(std::io::ErrorKind::NotFound, "not found"),
+		))
+		.context("Failed to load the key");
+
+		assert_eq!(
+			to_json(&err, category(&err)),
+			serde_json::json!({
+				"error": {
+					"category": "io",
+					"exit_code": 7,
+					"message": "Failed to load the key",
+					"causes": ["Failed to read \"key.der\"", "not found"],
+				},
+			})
+		);
+
+		// only the message of clap, without the usage
+		let err = clap_error(&["--unknown"]);
+		let json = to_json(&err, category(&err));
+		assert_eq!(json["error"]["category"], "usage");
+		assert_eq!(json["error"]["exit_code"], 2);
+		assert_eq!(json["error"]["message"], "unexpected argument '--unknown' found");
+		assert_eq!(json["error"]["causes"], serde_json::json!([]));
+	}
+}
@@ -0,0 +1,78 @@
+//! Module for generating shell completions and man pages from the CLI definition
+
+use std::path::Path;
+
+use anyhow::Context;
+use clap::CommandFactory;
+
+use crate::clap_conf::CliDerive;
+
+/// Name of the binary, which the completions and man pages are generated for
+const BIN_NAME: &str = "ade-extract-key";
+
+/// Get the CLI definition named like the binary (instead of the package)
+fn command() -> clap::Command {
+	return CliDerive::command().name(BIN_NAME);
+}
+
+/// Write the completion script for "shell" to stdout
+pub fn completions(shell: clap_complete::Shell) {
+	clap_complete::generate(shell, &mut command(), BIN_NAME, &mut std::io::stdout());
+}
+
+/// Write a man page for the command and every subcommand (like "ade-extract-key-keys-list.1") to "out_dir"
+pub fn man(out_dir: &Path) -> anyhow::Result<()> {
+	if !out_dir.is_dir() {
+		std::fs::create_dir_all(out_dir)
+			.with_context(|| return format!("Failed to create output directory \"{}\"", out_dir.display()))?;
+	}
+
+	clap_mangen::generate_to(command(), out_dir)
+		.with_context(|| return format!("Failed to write man pages to \"{}\"", out_dir.display()))?;
+
+	println!("Wrote man pages to {}", out_dir.display());
+
+	return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+	use clap::ValueEnum;
+	use libade_extract_key::file::TempDir;
+
+	use super::*;
+
+	#[test]
+	fn cli_definition() {
+		command().debug_assert();
+	}
+
+	#[test]
+	fn completions_for_all_shells() {
+		for shell in clap_complete::Shell::value_variants() {
+			let mut script = Vec::new();
+			clap_complete::generate(*shell, &mut command(), BIN_NAME, &mut script);
+
+			let script = String::from_utf8(script).unwrap();
+			// completions are registered for the binary, not the package
+			assert!(script.contains(BIN_NAME), "{shell}");
+			assert!(!script.contains("ade_key_extractor"), "{shell}");
+		}
+	}
+
+	#[test]
+	fn man_pages() {
+		let dir = TempDir::new("ade-extract-key-test").unwrap();
+		let out_dir = dir.path().join("man");
+
+		man(&out_dir).unwrap();
+		for page in [
+			"ade-extract-key.1",
+			"ade-extract-key-keys-list.1",
+			"ade-extract-key-decrypt-keyfile.1",
+		] {
+			let content = std::fs::read_to_string(out_dir.join(page)).unwrap();
+			assert!(content.starts_with(".ie"), "{page}");
+		}
+	}
+}
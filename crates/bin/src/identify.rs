@@ -0,0 +1,106 @@
+//! Module for the "identify" subcommand, which finds out which account / key ebooks belong to
+
+use std::path::PathBuf;
+
+use libade_extract_key::{
+	decrypt::{
+		self,
+		AdeptInformation,
+	},
+	ebook,
+	error::display_chain,
+	naming::user_uuid,
+	store::KeyStore,
+};
+
+use crate::clap_conf::IdentifyCli;
+
+/// Compare the ADEPT license of all ebooks in "cli.paths" with the activations and the key "store"
+pub fn run(cli: &IdentifyCli, store: Option<&KeyStore>) -> anyhow::Result<()> {
+	let mut ebooks: Vec<PathBuf> = Vec::new();
+
+	for path in &cli.paths {
+		if path.is_dir() {
+			ebooks.extend(ebook::find_ebooks(path)?);
+		} else {
+			ebooks.push(path.clone());
+		}
+	}
+
+	let activations: Vec<AdeptInformation> = if cli.no_activations {
+		Vec::new()
+	} else {
+		// the key store may still be enough, so failing to get the activations is not fatal
+		match decrypt::get_all_adept_information() {
+			Ok(v) => v,
+			Err(err) => {
+				warn!(
+					"Could not get the activations, only comparing with the key store: {}",
+					display_chain(&err)
+				);
+				Vec::new()
+			},
+		}
+	};
+
+	let mut matched = 0usize;
+	let mut unmatched = 0usize;
+	let mut unprotected = 0usize;
+	let mut failed = 0usize;
+
+	for path in &ebooks {
+		let license = match ebook::read_license(path) {
+			Ok(Some(v)) => v,
+			Ok(None) => {
+				println!("{}: not ADEPT protected", path.display());
+				unprotected += 1;
+				continue;
+			},
+			Err(err) => {
+				println!("{}: failed: {}", path.display(), display_chain(&err));
+				failed += 1;
+				continue;
+			},
+		};
+
+		println!("{}: licensed to {}", path.display(), license.user);
+
+		let mut found = false;
+		let uuid = user_uuid(&license.user);
+
+		for activation in activations.iter().filter(|v| return user_uuid(&v.user) == uuid) {
+			println!(
+				"  matches activation \"{}\" ({})",
+				activation.username.1, activation.username.0
+			);
+			found = true;
+		}
+
+		for stored in store.map(|v| return v.find_by_user(&license.user)).unwrap_or_default() {
+			println!(
+				"  matches key {} (\"{}\") in the key store",
+				stored.fingerprint,
+				stored.adobe_id.as_deref().unwrap_or("unknown")
+			);
+			found = true;
+		}
+
+		if found {
+			matched += 1;
+		} else {
+			println!("  no matching activation or key found");
+			unmatched += 1;
+		}
+	}
+
+	println!(
+		"Checked {} ebook(s): {matched} matched, {unmatched} without matching key, {unprotected} not ADEPT protected, {failed} failed",
+		ebooks.len()
+	);
+
+	if failed > 0 {
+		return Err(crate::Error::other(format!("Failed to read the license of {failed} ebook(s)")).into());
+	}
+
+	return Ok(());
+}
@@ -0,0 +1,121 @@
+//! Module for the "keys" subcommand, which manages the key store
+
+use libade_extract_key::store::{
+	KeyStore,
+	StoredKey,
+};
+
+use crate::KeyEntry;
+
+/// Length of the fingerprint shown in lists, full fingerprints are shown with "keys show"
+const SHORT_FINGERPRINT_LEN: usize = 16;
+
+/// Placeholder for unknown values
+const UNKNOWN: &str = "unknown";
+
+/// List all keys in the "store"
+pub fn list(store: &KeyStore) -> anyhow::Result<()> {
+	if store.keys().is_empty() {
+		println!("No keys in the key store at {}", store.dir().display());
+
+		return Ok(());
+	}
+
+	println!(
+		"{:<16}  {:<20}  {:<36}  {:<20}  SOURCE PREFIX",
+		"FINGERPRINT", "EXTRACTED AT", "USER UUID", "ADOBE ID"
+	);
+
+	for entry in store.keys() {
+		println!(
+			"{:<16}  {:<20}  {:<36}  {:<20}  {}",
+			&entry.fingerprint[..SHORT_FINGERPRINT_LEN.min(entry.fingerprint.len())],
+			entry.extracted_at,
+			entry
+				.user
+				.as_deref()
+				.map_or(UNKNOWN, libade_extract_key::naming::user_uuid),
+			entry.adobe_id.as_deref().unwrap_or(UNKNOWN),
+			entry
+				.source_prefix
+				.as_ref()
+				.map_or_else(|| return UNKNOWN.into(), |v| return v.to_string_lossy()),
+		);
+	}
+
+	return Ok(());
+}
+
+/// Show all information about the key matching "query"
+pub fn show(store: &KeyStore, query: &str) -> anyhow::Result<()> {
+	let entry = store.find(query)?;
+
+	println!("Fingerprint:   {}", entry.fingerprint);
+	println!("Adobe ID:      {}", entry.adobe_id.as_deref().unwrap_or(UNKNOWN));
+	println!("Method:        {}", entry.method.as_deref().unwrap_or(UNKNOWN));
+	println!("User:          {}", entry.user.as_deref().unwrap_or(UNKNOWN));
+	println!(
+		"Source Prefix: {}",
+		entry
+			.source_prefix
+			.as_ref()
+			.map_or_else(|| return UNKNOWN.into(), |v| return v.to_string_lossy())
+	);
+	println!("Extracted At:  {}", entry.extracted_at);
+	println!("File:          {}", store.dir().join(&entry.file).display());
+
+	return Ok(());
+}
+
+/// Remove the key matching "query"
+pub fn remove(store: &mut KeyStore, query: &str) -> anyhow::Result<()> {
+	let fingerprint = store.find(query)?.fingerprint.clone();
+
+	let entry = store.remove(&fingerprint)?;
+
+	println!(
+		"Removed key {} ({}) from the key store",
+		entry.fingerprint,
+		entry.adobe_id.as_deref().unwrap_or(UNKNOWN)
+	);
+
+	return Ok(());
+}
+
+/// Get the keys matching "queries" (or all if empty) to be written like extracted keys
+pub fn export(store: &KeyStore, queries: &[String]) -> anyhow::Result<Vec<KeyEntry>> {
+	let entries: Vec<&StoredKey> = if queries.is_empty() {
+		store.keys().iter().collect()
+	} else {
+		let mut entries = Vec::with_capacity(queries.len());
+
+		for query in queries {
+			let entry = store.find(query)?;
+
+			// dont export the same key twice, if multiple queries match it
+			if !entries.contains(&entry) {
+				entries.push(entry);
+			}
+		}
+
+		entries
+	};
+
+	if entries.is_empty() {
+		return Err(crate::Error::usage(format!("No keys in the key store at {}", store.dir().display())).into());
+	}
+
+	let mut keys = Vec::with_capacity(entries.len());
+
+	for entry in entries {
+		keys.push(KeyEntry {
+			user: entry.user.clone(),
+			adobe_id: entry.adobe_id.clone(),
+			method: entry.method.clone(),
+			source_prefix: entry.source_prefix.clone(),
+			..KeyEntry::from_key(store.read_key(entry)?)
+		});
+	}
+
+	return Ok(keys);
+}
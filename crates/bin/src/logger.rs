@@ -11,7 +11,64 @@ use flexi_logger::{
 	LoggerHandle,
 	Record,
 };
-use std::io::Error as ioError;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use std::{
+	borrow::Cow,
+	io::Error as ioError,
+	sync::atomic::{
+		AtomicBool,
+		Ordering,
+	},
+};
+
+/// Set by "--log-secrets", to not mask anything in log messages
+static LOG_SECRETS: AtomicBool = AtomicBool::new(false);
+
+/// Set if secrets should be logged as-is instead of being masked
+pub fn set_log_secrets(value: bool) {
+	LOG_SECRETS.store(value, Ordering::Relaxed);
+}
+
+/// Regex for anything that looks like key material, which are long runs of hex or base64
+static SECRET_REGEX: Lazy<Regex> = Lazy::new(|| {
+	return Regex::new(r"[0-9A-Za-z+/]{32,}={0,2}").unwrap();
+});
+
+/// Get if "found" (a match of [SECRET_REGEX]) looks like key material instead of a long word or path
+/// That is if it contains both letters and digits, has base64 padding, or mixes upper and lower case like random base64 does
+fn is_key_material(found: &str) -> bool {
+	let digits = found.bytes().filter(u8::is_ascii_digit).count();
+	let upper = found.bytes().filter(u8::is_ascii_uppercase).count();
+	let lower = found.bytes().filter(u8::is_ascii_lowercase).count();
+	let letters = upper + lower;
+
+	return (digits > 0 && letters > 0) || found.ends_with('=') || (upper * 4 >= letters && lower * 4 >= letters);
+}
+
+/// Mask anything in "message" that looks like key material
+fn mask_secrets(message: &str) -> Cow<'_, str> {
+	return SECRET_REGEX.replace_all(message, |caps: &regex::Captures| {
+		let found = &caps[0];
+
+		if is_key_material(found) {
+			return String::from(libade_extract_key::secret::REDACTED);
+		}
+
+		return found.to_owned();
+	});
+}
+
+/// Get the message of "record", with key material masked unless "--log-secrets" is set
+fn record_message(record: &Record) -> String {
+	let message = record.args().to_string();
+
+	if LOG_SECRETS.load(Ordering::Relaxed) {
+		return message;
+	}
+
+	return mask_secrets(&message).into_owned();
+}
 
 /// Function for setting up the logger
 /// This function is mainly to keep the code structured and sorted
@@ -40,7 +97,7 @@ pub fn log_format(w: &mut dyn std::io::Write, now: &mut DeferredNow, record: &Re
 		now.format_rfc3339(),
 		record.level(),
 		record.module_path().unwrap_or("<unnamed module>"),
-		&record.args()
+		record_message(record)
 	);
 }
 
@@ -62,6 +119,66 @@ pub fn color_log_format(
 		now.format_rfc3339().color(Color::BrightBlack), // Bright Black = Grey
 		style(level).paint(format!("{level:5}")), // pad level to 2 characters, cannot be done in the string itself, because of the color characters
 		record.module_path().unwrap_or("<unnamed module>"),
-		&record.args() // dont apply any color to the input, so that the input can dynamically set the color
+		record_message(record) // dont apply any color to the input, so that the input can dynamically set the color
 	);
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn masks_key_material() {
+		let cases = [
+			// hex, like the decrypted device key
+			"000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f",
+			"00112233445566778899AABBCCDDEEFF",
+			// base64 with padding, like a "privateLicenseKey"
+			"MIICdgIBADANBgkqhkiG9w0BAQEFAASCAmAwggJcAgEAAoGBAL==",
+			"cHJpdmF0ZUxpY2Vuc2VLZXlQcml2YXRl=",
+			// base64 without any digits
+			"QUJDREVGR0hJSktMTU5PUFFSU1RVVldYWVpBQkNERUZH",
+			"cHJpdmF0ZUxpY2Vuc2VLZXlQcml2YXRlTGljZW5zZUtleQ",
+		];
+
+		for case in cases {
+			assert_eq!(
+				mask_secrets(&format!("decrypted {case} done")),
+				format!("decrypted {} done", libade_extract_key::secret::REDACTED),
+				"{case}"
+			);
+		}
+	}
+
+	#[test]
+	fn keeps_other_text() {
+		let cases = [
+			// too short
+			"key 0011aabb",
+			"cHJpdmF0ZQ==",
+			// long words and paths
+			"/usr/share/applications/somethinglonger/than/needed",
+			"/Users/Someone/Library/ApplicationSupport/Somewhere",
+			"pneumonoultramicroscopicsilicovolcanoconiosis",
+			"SOMELONGCONSTANTNAMEWITHOUTANYSEPARATORS",
+			"Completed stage \"dpapi\", saved state to \"ade_key_state.json\"",
+		];
+
+		for case in cases {
+			assert_eq!(mask_secrets(case), case);
+		}
+	}
+
+	#[test]
+	fn log_secrets_bypass() {
+		let secret = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+		assert!(!record_message(&Record::builder().args(format_args!("key {secret}")).build()).contains(secret));
+
+		set_log_secrets(true);
+		let message = record_message(&Record::builder().args(format_args!("key {secret}")).build());
+		set_log_secrets(false);
+
+		assert_eq!(message, format!("key {secret}"));
+	}
+}
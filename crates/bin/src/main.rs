@@ -1,25 +1,99 @@
 #[macro_use]
 extern crate log;
 
-use std::io::{
-	BufWriter,
-	Write,
+use std::{
+	path::{
+		Path,
+		PathBuf,
+	},
+	process::ExitCode,
+	sync::Arc,
+	time::Duration,
 };
 
 use anyhow::Context;
-use flexi_logger::LogSpecification;
-use libade_extract_key::decrypt;
+use colored::Colorize;
+use flexi_logger::{
+	LogSpecification,
+	LoggerHandle,
+};
+use libade_extract_key::{
+	decrypt,
+	dedrm,
+	doctor::CheckStatus,
+	error::{
+		display_chain,
+		KeyFormatError,
+	},
+	key::KeyVerification,
+	naming::KeyNameInfo,
+	pipeline::{
+		PipelineState,
+		Stage,
+	},
+	record::{
+		Bundle,
+		RecordingRunner,
+		ReplayRunner,
+	},
+	runner::ProcessRunner,
+	secret::{
+		SecretBytes,
+		SecretString,
+	},
+	settings::Settings,
+	store::{
+		KeyMetadata,
+		KeyStore,
+		StoreAdd,
+	},
+};
+
+/// Print a status message to stdout, or to stderr when stdout is used for the key
+macro_rules! status {
+	($($arg:tt)*) => {
+		if crate::output::key_to_stdout() {
+			eprintln!($($arg)*);
+		} else {
+			println!($($arg)*);
+		}
+	};
+}
 
+mod batch;
 mod clap_conf;
+mod config;
+mod exit;
+mod generate;
+mod identify;
+mod keys;
 mod logger;
+mod output;
+mod passphrase;
+#[cfg(feature = "tui")]
+mod tui;
 
 pub type Error = libade_extract_key::error::ExtractorError;
 
-fn main() -> anyhow::Result<()> {
+fn main() -> ExitCode {
+	// until the arguments are parsed, the error format can only be taken from the raw arguments
+	let mut error_format = exit::error_format_from_args();
+
+	return match run(&mut error_format) {
+		Ok(()) => ExitCode::SUCCESS,
+		Err(err) => exit::report(&err, error_format),
+	};
+}
+
+/// Parse the arguments and run the requested command, "error_format" is updated after the arguments are parsed
+fn run(error_format: &mut clap_conf::ErrorFormat) -> anyhow::Result<()> {
 	let logger_handle = logger::setup_logger().context("Failed to set-up logger")?;
 	flexi_logger::Logger::try_with_env()?;
 
-	let cli_matches = clap_conf::CliDerive::custom_parse();
+	let cli_matches = clap_conf::CliDerive::custom_parse()?;
+	*error_format = cli_matches.error_format;
+
+	logger::set_log_secrets(cli_matches.log_secrets);
 
 	#[cfg(debug_assertions)]
 	if cli_matches.debug_enabled() {
@@ -40,46 +114,493 @@ fn main() -> anyhow::Result<()> {
 				3 => LogSpecification::parse("trace"),
 				_ => {
 					return Err(
-						crate::Error::other("Expected verbosity integer range between 0 and 3 (inclusive)").into(),
+						crate::Error::usage("Expected verbosity integer range between 0 and 3 (inclusive)").into(),
 					)
 				},
 			}
 			.expect("Expected LogSpecification to parse correctly"),
 		);
+	} else if let Some(log_level) = &cli_matches.log_level {
+		// "RUST_LOG" is more specific than the configuration file
+		if std::env::var_os("RUST_LOG").is_none() {
+			logger_handle.set_new_spec(
+				LogSpecification::parse(log_level)
+					.with_context(|| return format!("Invalid \"log_level\" \"{log_level}\" in configuration file"))?,
+			);
+		}
 	}
 
+	libade_extract_key::settings::set(Settings {
+		wine:       cli_matches.wine.clone(),
+		bottle:     cli_matches.bottle.clone(),
+		prefix:     cli_matches.prefix.clone(),
+		winapi_bin: cli_matches.winapi_bin.clone(),
+		timeout:    cli_matches.timeout.map(Duration::from_secs),
+	});
+
+	let recorder = match (&cli_matches.record, &cli_matches.replay) {
+		(Some(_), _) => {
+			let recorder = Arc::new(RecordingRunner::new(ProcessRunner));
+			libade_extract_key::runner::set(recorder.clone());
+
+			Some(recorder)
+		},
+		(None, Some(replay)) => {
+			libade_extract_key::runner::set(Arc::new(ReplayRunner::new(Bundle::load(replay)?)));
+
+			None
+		},
+		(None, None) => None,
+	};
+
+	let res = run_command(&cli_matches, &logger_handle);
+
+	// also (and especially) record failed runs
+	if let (Some(recorder), Some(record_path)) = (recorder, &cli_matches.record) {
+		let mut bundle = recorder.bundle();
+
+		if cli_matches.redact {
+			bundle.redact();
+		}
+
+		match bundle.save(record_path) {
+			Ok(()) => eprintln!(
+				"Recorded {} command(s) to {}",
+				bundle.commands.len(),
+				record_path.display()
+			),
+			// dont hide the error of the run
+			Err(err) if res.is_err() => error!("Failed to save the recording: {}", display_chain(&err)),
+			Err(err) => return Err(err.into()),
+		}
+	}
+
+	return res;
+}
+
+/// Run the requested command, after everything is set-up
+// "logger_handle" is only needed for the terminal ui
+#[cfg_attr(not(feature = "tui"), allow(unused_variables))]
+fn run_command(cli_matches: &clap_conf::CliDerive, logger_handle: &LoggerHandle) -> anyhow::Result<()> {
+	output::set_key_to_stdout(output::is_stdout(cli_matches.output_file_name.as_deref()));
+
 	trace!("CLI setup done");
 
-	let key: Vec<u8>;
+	let keys: Vec<KeyEntry>;
 
-	if let Some(subcommand) = cli_matches.subcommands {
+	if let Some(subcommand) = &cli_matches.subcommands {
 		trace!("subcommand given");
 
 		match subcommand {
 			clap_conf::SubCommands::AES(aescli) => {
-				println!("Only running AES decrypt");
+				status!("Only running AES decrypt");
+
+				if let Some(state_path) = &cli_matches.resume {
+					let mut state = PipelineState::load(state_path)?;
+
+					// the key from a manual run of the winapi-bin replaces the "dpapi" stage
+					if let Some(key) = &aescli.key {
+						state.dpapi = Some(SecretString::from(key.as_str()));
+					}
+
+					let final_keys = state.run_aes()?;
+					keys = entries_from_state(state, final_keys);
+
+					finish_state(cli_matches, state_path)?;
+				} else {
+					keys = vec![KeyEntry {
+						license_certificate: aescli.license_certificate.clone(),
+						..KeyEntry::from_key(decrypt::aes_decrypt(
+							aescli.key.as_deref().expect("Expected key to be set at this point"),
+							aescli
+								.adept_key
+								.as_deref()
+								.expect("Expected adept_key to be set at this point"),
+						)?)
+					}];
+				}
+			},
+			clap_conf::SubCommands::DecryptKeyfile(decryptcli) => {
+				let data = std::fs::read(&decryptcli.keyfile).context("Failed to read keyfile")?;
+				let passphrase = passphrase::get_passphrase(&cli_matches.passphrase, false)?;
+
+				keys = vec![KeyEntry::from_key(libade_extract_key::format::decrypt_keyfile(
+					&data,
+					passphrase.expose().as_bytes(),
+				)?)];
+			},
+			clap_conf::SubCommands::ExportDedrm(exportcli) => {
+				if let Some(key_path) = &exportcli.key {
+					keys = vec![KeyEntry::from_key(SecretBytes::from(
+						std::fs::read(key_path).context("Failed to read key file")?,
+					))];
+				} else {
+					keys = extract_keys(cli_matches)?;
+				}
+			},
+			clap_conf::SubCommands::Keys(keyscli) => {
+				let mut store = KeyStore::open(cli_matches.store_dir())?;
+
+				match &keyscli.action {
+					clap_conf::KeysAction::List => return keys::list(&store),
+					clap_conf::KeysAction::Show(querycli) => return keys::show(&store, &querycli.key),
+					clap_conf::KeysAction::Remove(querycli) => return keys::remove(&mut store, &querycli.key),
+					clap_conf::KeysAction::Export(exportcli) => keys = keys::export(&store, &exportcli.keys)?,
+				}
+			},
+			clap_conf::SubCommands::Batch(batchcli) => return batch::run(cli_matches, batchcli),
+			clap_conf::SubCommands::Identify(identifycli) => {
+				let store = cli_matches.store_dir.as_deref().map(KeyStore::open).transpose()?;
+
+				return identify::run(identifycli, store.as_ref());
+			},
+			clap_conf::SubCommands::Doctor => return doctor(),
+			clap_conf::SubCommands::Completions(completionscli) => {
+				generate::completions(completionscli.shell);
+
+				return Ok(());
+			},
+			clap_conf::SubCommands::Man(mancli) => return generate::man(&mancli.out_dir),
+			#[cfg(feature = "tui")]
+			clap_conf::SubCommands::Tui => {
+				// log lines would corrupt the terminal ui, errors are shown in the ui instead
+				logger_handle.set_new_spec(LogSpecification::off());
 
-				key = decrypt::aes_decrypt(&aescli.key, &aescli.adept_key)?;
+				return tui::run(cli_matches);
+			},
+			clap_conf::SubCommands::Info(infocli) => {
+				let info = libade_extract_key::info::gather_info()?;
+
+				if infocli.json {
+					println!(
+						"{}",
+						serde_json::to_string_pretty(&info.to_json(infocli.show_secrets))
+							.context("Failed to serialize info")?
+					);
+				} else {
+					print!("{}", info.to_text(infocli.show_secrets));
+				}
+
+				return Ok(());
 			},
 		}
 	} else {
-		let drive_info = decrypt::get_drive_info()?;
-		let cpu_info = decrypt::get_cpu_info()?;
-		let username = decrypt::get_win_username()?;
-		let adept_info = decrypt::get_adept_information()?;
+		keys = extract_keys(cli_matches)?;
+	}
 
-		key = decrypt::decrypt(&drive_info, &cpu_info, &username, &adept_info, true)?;
+	for entry in &keys {
+		let verification =
+			libade_extract_key::key::verify_key(entry.key.expose(), entry.license_certificate.as_deref())?;
+
+		status!("Key verification: {verification}");
+
+		if verification == KeyVerification::Mismatch {
+			return Err(crate::Error::from(KeyFormatError::KeyMismatch).into());
+		}
 	}
 
-	let file_path = cli_matches
-		.output_file_name
-		.expect("Expected output_file_name to be set at this point");
+	if cli_matches.store {
+		let mut store = KeyStore::open(cli_matches.store_dir())?;
 
-	let mut file = BufWriter::new(std::fs::File::create(&file_path)?);
+		for entry in &keys {
+			match store.add(entry.key.expose(), entry.metadata())? {
+				StoreAdd::Added(fingerprint) => status!("Added key to the key store as {fingerprint}"),
+				StoreAdd::AlreadyPresent(fingerprint) => status!("Key is already in the key store as {fingerprint}"),
+			}
+		}
+	}
 
-	file.write_all(&key)?;
+	if let Some(clap_conf::SubCommands::ExportDedrm(exportcli)) = &cli_matches.subcommands {
+		for entry in &keys {
+			export_dedrm(exportcli, entry)?;
+		}
 
-	println!("Wrote key to {}", file_path.to_string_lossy());
+		return Ok(());
+	}
+
+	let passphrase = if cli_matches.encrypt {
+		Some(passphrase::get_passphrase(&cli_matches.passphrase, true)?)
+	} else {
+		None
+	};
+
+	let multiple = keys.len() > 1;
+
+	if multiple && output::key_to_stdout() {
+		return Err(crate::Error::usage(format!(
+			"{} keys were extracted, they cannot all be written to stdout, use a directory instead",
+			keys.len()
+		))
+		.into());
+	}
+
+	let write_options = write_options(cli_matches, passphrase.as_ref());
+
+	for (i, entry) in keys.iter().enumerate() {
+		let encoded_key = encode_entry(cli_matches, entry, cli_matches.format, passphrase.as_ref())?;
+
+		if output::key_to_stdout() {
+			output::write_key_stdout(encoded_key.expose())?;
+
+			continue;
+		}
+
+		let file_path = output::resolve_output_path(
+			cli_matches.output_file_name.as_deref(),
+			cli_matches.template.as_deref(),
+			&entry.name_info(i + 1, cli_matches.format.into()),
+			multiple,
+		)?;
+
+		let written = output::write_key_file(&file_path, encoded_key.expose(), entry.key.expose(), write_options)?;
+		print_key_write(&file_path, &written);
+	}
+
+	return Ok(());
+}
+
+/// Get the options for writing key files from the "cli", with the "passphrase" the keys are encrypted with (if any)
+fn write_options<'a>(cli: &clap_conf::CliDerive, passphrase: Option<&'a SecretString>) -> output::WriteOptions<'a> {
+	return output::WriteOptions {
+		force:      cli.force,
+		backup:     cli.backup,
+		passphrase: passphrase.map(|v| return v.expose().as_bytes()),
+	};
+}
+
+/// Encode the key of "entry" in "format", encrypted with the KDF from the "cli" if a "passphrase" is given
+fn encode_entry(
+	cli: &clap_conf::CliDerive,
+	entry: &KeyEntry,
+	format: clap_conf::FormatArg,
+	passphrase: Option<&SecretString>,
+) -> anyhow::Result<SecretBytes> {
+	if let Some(passphrase) = passphrase {
+		return Ok(libade_extract_key::format::encrypt_key(
+			entry.key.expose(),
+			passphrase.expose().as_bytes(),
+			cli.kdf.into(),
+			format == clap_conf::FormatArg::PemPkcs8,
+		)?);
+	}
+
+	return Ok(libade_extract_key::format::encode_key(
+		entry.key.expose(),
+		format.into(),
+	)?);
+}
+
+/// Print what [output::write_key_file] did for "file_path"
+fn print_key_write(file_path: &Path, written: &output::KeyWrite) {
+	match written {
+		output::KeyWrite::Written(backup) => {
+			if let Some(backup) = backup {
+				println!("Kept the previous key file as {}", backup.to_string_lossy());
+			}
+
+			println!("Wrote key to {}", file_path.to_string_lossy());
+		},
+		output::KeyWrite::Unchanged => println!("Key file {} is already up to date", file_path.to_string_lossy()),
+	}
+}
+
+/// A key and what is known about the account it belongs to
+pub struct KeyEntry {
+	/// The PKCS#1 DER key
+	pub key:                 SecretBytes,
+	/// The certificate to verify the key against, if known
+	pub license_certificate: Option<String>,
+	/// The "urn:uuid" of the account, if known
+	pub user:                Option<String>,
+	/// The AdobeID of the account, if known
+	pub adobe_id:            Option<String>,
+	/// The method of the account (like "AdobeID"), if known
+	pub method:              Option<String>,
+	/// The wine prefix the key was extracted from, if known
+	pub source_prefix:       Option<PathBuf>,
+}
+
+impl KeyEntry {
+	/// Create a entry where nothing is known except the key itself
+	pub fn from_key(key: SecretBytes) -> Self {
+		return Self {
+			key,
+			license_certificate: None,
+			user: None,
+			adobe_id: None,
+			method: None,
+			source_prefix: None,
+		};
+	}
+
+	/// Get the information for naming the key file
+	fn name_info(&self, n: usize, format: libade_extract_key::format::KeyFormat) -> KeyNameInfo<'_> {
+		return KeyNameInfo {
+			n,
+			user: self.user.as_deref(),
+			adobe_id: self.adobe_id.as_deref(),
+			method: self.method.as_deref(),
+			source: self
+				.source_prefix
+				.as_deref()
+				.and_then(Path::file_name)
+				.and_then(std::ffi::OsStr::to_str),
+			ext: format.extension(),
+		};
+	}
+
+	/// Get the metadata for adding the key to the key store
+	fn metadata(&self) -> KeyMetadata {
+		return KeyMetadata {
+			adobe_id:      self.adobe_id.clone(),
+			method:        self.method.clone(),
+			user:          self.user.clone(),
+			source_prefix: self.source_prefix.clone(),
+		};
+	}
+}
+
+/// Run the full extraction for all activations, as stages that are saved to the state file (if any) after each one
+fn extract_keys(cli: &clap_conf::CliDerive) -> anyhow::Result<Vec<KeyEntry>> {
+	let state_path = cli.state_path();
+
+	let mut state = if let Some(resume_path) = &cli.resume {
+		let state = PipelineState::load(resume_path)?;
+
+		if let Some(stage) = state.last_completed() {
+			status!("Resuming after stage \"{stage}\"");
+		}
+
+		state
+	} else {
+		PipelineState::default()
+	};
+
+	let res = state.run(|state, stage| {
+		return save_state(state, stage, state_path);
+	});
+
+	let final_keys = match res {
+		Ok(v) => v,
+		Err(err) => {
+			match (state.last_completed(), state_path) {
+				(Some(stage), Some(state_path)) => eprintln!(
+					"Progress up to stage \"{stage}\" was saved, continue with \"--resume {}\"",
+					state_path.display()
+				),
+				(Some(_), None) => eprintln!("Use \"--state FILE\" to save the progress, so that it can be continued"),
+				(None, _) => (),
+			}
+
+			return Err(err.into());
+		},
+	};
+
+	if let Some(state_path) = state_path {
+		finish_state(cli, state_path)?;
+	}
+
+	return Ok(entries_from_state(state, final_keys));
+}
+
+/// Save the "state" after the completed "stage" to "state_path", if it is set
+fn save_state(state: &PipelineState, stage: Stage, state_path: Option<&Path>) -> Result<(), libade_extract_key::Error> {
+	let Some(state_path) = state_path else {
+		return Ok(());
+	};
+
+	state.save(state_path)?;
+	info!(
+		"Completed stage \"{stage}\", saved state to \"{}\"",
+		state_path.display()
+	);
+
+	return Ok(());
+}
+
+/// Remove the state file after the extraction completed, because it contains secrets
+fn finish_state(cli: &clap_conf::CliDerive, state_path: &Path) -> anyhow::Result<()> {
+	if cli.keep_state || !state_path.exists() {
+		return Ok(());
+	}
+
+	std::fs::remove_file(state_path)
+		.with_context(|| return format!("Failed to remove state file \"{}\"", state_path.display()))?;
+
+	return Ok(());
+}
+
+/// Combine the activations from the "state" with the "final_keys" from the "aes" stage
+fn entries_from_state(state: PipelineState, final_keys: Vec<SecretBytes>) -> Vec<KeyEntry> {
+	let adept_infos = state.gather.map(|v| return v.adept_infos).unwrap_or_default();
+	let source_prefix = decrypt::wine_prefix();
+
+	return final_keys
+		.into_iter()
+		.zip(adept_infos)
+		.map(|(key, adept_info)| {
+			let (method, adobe_id) = adept_info.username;
+
+			return KeyEntry {
+				key,
+				license_certificate: adept_info.license_certificate,
+				user: Some(adept_info.user),
+				adobe_id: Some(adobe_id),
+				method: Some(method),
+				source_prefix: source_prefix.clone(),
+			};
+		})
+		.collect();
+}
+
+/// Run all environment checks and print their results
+fn doctor() -> anyhow::Result<()> {
+	let results = libade_extract_key::doctor::run_checks();
+
+	for result in &results {
+		let status = match result.status {
+			CheckStatus::Pass => result.status.to_string().green(),
+			CheckStatus::Warn => result.status.to_string().yellow(),
+			CheckStatus::Fail => result.status.to_string().red(),
+		};
+
+		println!("[{status}] {}: {}", result.name, result.message);
+
+		if let Some(hint) = result.hint {
+			println!("       hint: {hint}");
+		}
+	}
+
+	if results.iter().any(|v| return v.status == CheckStatus::Fail) {
+		return Err(crate::Error::environment("A check failed, see the hint above").into());
+	}
+
+	println!("All checks passed, the key can be extracted");
+
+	return Ok(());
+}
+
+/// Add the key of "entry" to the DeDRM plugin configuration
+fn export_dedrm(exportcli: &clap_conf::ExportDedrmCli, entry: &KeyEntry) -> anyhow::Result<()> {
+	let name = exportcli
+		.name
+		.as_deref()
+		.or(entry.adobe_id.as_deref())
+		.ok_or_else(|| return crate::Error::usage("No name for the key, use \"--name\""))?;
+
+	let dedrm_json = dedrm::dedrm_json_path(
+		exportcli
+			.calibre_config
+			.as_ref()
+			.expect("Expected calibre_config to be set at this point"),
+	);
+
+	match dedrm::add_adept_key(&dedrm_json, name, entry.key.expose())? {
+		dedrm::DeDrmExport::Added(name) => println!("Added key \"{name}\" to {}", dedrm_json.display()),
+		dedrm::DeDrmExport::AlreadyPresent(name) => {
+			println!("Key is already present as \"{name}\" in {}", dedrm_json.display());
+		},
+	}
 
 	return Ok(());
 }
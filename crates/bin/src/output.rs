@@ -0,0 +1,386 @@
+//! Module for deciding where key files are written to
+
+use std::{
+	io::Write,
+	path::{
+		Path,
+		PathBuf,
+	},
+	sync::atomic::{
+		AtomicBool,
+		Ordering,
+	},
+};
+
+use anyhow::Context;
+use libade_extract_key::{
+	format::KeyFormat,
+	naming::{
+		self,
+		KeyNameInfo,
+	},
+	secret::SecretBytes,
+};
+
+/// Output path that writes the key to stdout instead of a file
+pub const STDOUT_PATH: &str = "-";
+
+/// Set when the key is written to stdout, so that status messages go to stderr instead
+static KEY_TO_STDOUT: AtomicBool = AtomicBool::new(false);
+
+/// Get if the key is written to stdout
+pub fn key_to_stdout() -> bool {
+	return KEY_TO_STDOUT.load(Ordering::Relaxed);
+}
+
+/// Set if the key is written to stdout
+pub fn set_key_to_stdout(value: bool) {
+	KEY_TO_STDOUT.store(value, Ordering::Relaxed);
+}
+
+/// Get if the "output" path means stdout
+pub fn is_stdout(output: Option<&Path>) -> bool {
+	return output.is_some_and(|v| return v.as_os_str() == STDOUT_PATH);
+}
+
+/// Get if the given path should be treated as a directory (exists as one or ends with a separator)
+fn is_dir_like(path: &Path) -> bool {
+	return path.is_dir() || path.as_os_str().to_string_lossy().ends_with(std::path::is_separator);
+}
+
+/// Get the file name for the key described by "info"
+/// Uses the "template" if set, otherwise the default template if the account is known, otherwise "ade_key.<ext>"
+fn file_name(template: Option<&str>, info: &KeyNameInfo) -> anyhow::Result<String> {
+	if let Some(template) = template {
+		return Ok(naming::render_template(template, info)?);
+	}
+
+	if info.user.is_some() {
+		return Ok(naming::render_template(naming::DEFAULT_TEMPLATE, info)?);
+	}
+
+	return Ok(format!("{}.{}", naming::FALLBACK_FILE_STEM, info.ext));
+}
+
+/// Resolve the path the key described by "info" should be written to
+/// "output" is used as a directory if "multiple" keys are written or if it looks like a directory, otherwise as the file path
+pub fn resolve_output_path(
+	output: Option<&Path>,
+	template: Option<&str>,
+	info: &KeyNameInfo,
+	multiple: bool,
+) -> anyhow::Result<PathBuf> {
+	let Some(output) = output else {
+		return Ok(PathBuf::from(file_name(template, info)?));
+	};
+
+	if !multiple && !is_dir_like(output) {
+		return Ok(output.to_path_buf());
+	}
+
+	if !output.is_dir() {
+		info!("Creating output directory \"{}\"", output.display());
+		std::fs::create_dir_all(output)
+			.with_context(|| return format!("Failed to create output directory \"{}\"", output.display()))?;
+	}
+
+	return Ok(output.join(file_name(template, info)?));
+}
+
+/// Options for writing a key file
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WriteOptions<'a> {
+	/// Overwrite a existing file that contains a different key
+	pub force:      bool,
+	/// Keep a timestamped copy of a existing file before overwriting it
+	pub backup:     bool,
+	/// The passphrase the key is encrypted with, to compare against a existing encrypted file
+	pub passphrase: Option<&'a [u8]>,
+}
+
+/// What [write_key_file] did
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyWrite {
+	/// The file was written, with the path of the backup of the previous file if one was made
+	Written(Option<PathBuf>),
+	/// The file already had the exact same content, so it was not written
+	Unchanged,
+}
+
+/// Get if the "existing" file content contains the PKCS#1 DER "key", in any format or encrypted with "passphrase"
+fn contains_key(existing: &[u8], key: &[u8], passphrase: Option<&[u8]>) -> bool {
+	// all formats except encryption are deterministic, so encoding the key is enough to compare
+	let in_any_format = KeyFormat::ALL.iter().any(|v| {
+		return libade_extract_key::format::encode_key(key, *v).is_ok_and(|v| return v.expose() == existing);
+	});
+
+	return in_any_format
+		|| passphrase.is_some_and(|v| {
+			return libade_extract_key::format::decrypt_keyfile(existing, v).is_ok_and(|v| return v.expose() == key);
+		});
+}
+
+/// Write the "content" (the encoded PKCS#1 DER "key") to "path" atomically, only readable by the current user
+/// Refuses to overwrite a existing file with a different key, unless "options.force" is set
+pub fn write_key_file(path: &Path, content: &[u8], key: &[u8], options: WriteOptions) -> anyhow::Result<KeyWrite> {
+	let mut backup = None;
+
+	if path.exists() {
+		let existing = SecretBytes::from(
+			std::fs::read(path).with_context(|| return format!("Failed to read \"{}\"", path.display()))?,
+		);
+
+		if existing.expose() == content {
+			return Ok(KeyWrite::Unchanged);
+		}
+
+		if !options.force && !contains_key(existing.expose(), key, options.passphrase) {
+			return Err(crate::Error::usage(format!(
+				"\"{}\" already exists and does not contain the same key, use \"--force\" to overwrite it",
+				path.display()
+			))
+			.into());
+		}
+
+		if options.backup {
+			backup = Some(libade_extract_key::file::backup_file(path)?);
+		}
+	}
+
+	libade_extract_key::file::write_atomic(path, content)?;
+
+	return Ok(KeyWrite::Written(backup));
+}
+
+/// Write the "content" to stdout
+pub fn write_key_stdout(content: &[u8]) -> anyhow::Result<()> {
+	let mut stdout = std::io::stdout().lock();
+
+	stdout.write_all(content).context("Failed to write key to stdout")?;
+	stdout.flush().context("Failed to write key to stdout")?;
+
+	return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+	use libade_extract_key::{
+		error::ErrorCategory,
+		file::TempDir,
+		format::{
+			encode_key,
+			encrypt_key,
+			Kdf,
+		},
+	};
+
+	use super::*;
+
+	/// A PKCS#1 DER key
+	const KEY: &[u8] = include_bytes!("../../lib/tests/fixtures/key.der");
+
+	/// Get a [KeyNameInfo] for the "n"th key of a known account
+	fn info(n: usize) -> KeyNameInfo<'static> {
+		return KeyNameInfo {
+			n,
+			user: Some("urn:uuid:0000-1111"),
+			adobe_id: Some("reader@example.com"),
+			method: Some("AdobeID"),
+			source: None,
+			ext: "der",
+		};
+	}
+
+	#[test]
+	fn output_path_without_output() {
+		assert_eq!(
+			resolve_output_path(None, None, &info(1), false).unwrap(),
+			PathBuf::from("adobekey_1_0000-1111.der")
+		);
+		assert_eq!(
+			resolve_output_path(None, Some("{adobe_id}.{ext}"), &info(1), true).unwrap(),
+			PathBuf::from("reader@example.com.der")
+		);
+		// nothing known about the account
+		assert_eq!(
+			resolve_output_path(
+				None,
+				None,
+				&KeyNameInfo {
+					n: 1,
+					ext: "pem",
+					..Default::default()
+				},
+				false
+			)
+			.unwrap(),
+			PathBuf::from("ade_key.pem")
+		);
+	}
+
+	#[test]
+	fn output_path_single_key() {
+		let dir = TempDir::new("ade-extract-key-test").unwrap();
+
+		// a file path is used as it is, also with a template
+		let file = dir.path().join("key.der");
+		assert_eq!(resolve_output_path(Some(&file), None, &info(1), false).unwrap(), file);
+		assert_eq!(
+			resolve_output_path(Some(&file), Some("{n}.{ext}"), &info(1), false).unwrap(),
+			file
+		);
+
+		// a existing directory gets the key named after the account
+		assert_eq!(
+			resolve_output_path(Some(dir.path()), None, &info(1), false).unwrap(),
+			dir.path().join("adobekey_1_0000-1111.der")
+		);
+
+		// a path ending with a separator is a directory, which is created
+		let new_dir = dir.path().join("new");
+		let mut new_dir_arg = new_dir.clone().into_os_string();
+		new_dir_arg.push(std::path::MAIN_SEPARATOR_STR);
+		assert_eq!(
+			resolve_output_path(Some(Path::new(&new_dir_arg)), None, &info(1), false).unwrap(),
+			new_dir.join("adobekey_1_0000-1111.der")
+		);
+		assert!(new_dir.is_dir());
+	}
+
+	#[test]
+	fn output_path_multiple_keys() {
+		let dir = TempDir::new("ade-extract-key-test").unwrap();
+
+		// with multiple keys, the output is always a directory, which is created
+		let output = dir.path().join("keys");
+		let paths: Vec<PathBuf> = (1..=2)
+			.map(|n| return resolve_output_path(Some(&output), Some("{n}.{ext}"), &info(n), true).unwrap())
+			.collect();
+
+		assert_eq!(paths, [output.join("1.der"), output.join("2.der")]);
+		assert!(output.is_dir());
+
+		// a existing file cannot be used as the directory
+		let file = dir.path().join("file");
+		std::fs::write(&file, "").unwrap();
+		assert!(resolve_output_path(Some(&file), None, &info(1), true).is_err());
+	}
+
+	#[test]
+	fn stdout_path() {
+		assert!(is_stdout(Some(Path::new("-"))));
+		assert!(!is_stdout(Some(Path::new("./-"))));
+		assert!(!is_stdout(None));
+	}
+
+	/// Get the content of the file at "path"
+	fn read(path: &Path) -> Vec<u8> {
+		return std::fs::read(path).unwrap();
+	}
+
+	#[test]
+	fn write_new_and_unchanged() {
+		let dir = TempDir::new("ade-extract-key-test").unwrap();
+		let path = dir.path().join("key.der");
+
+		assert_eq!(
+			write_key_file(&path, KEY, KEY, WriteOptions::default()).unwrap(),
+			KeyWrite::Written(None)
+		);
+		assert_eq!(read(&path), KEY);
+
+		#[cfg(unix)]
+		{
+			use std::os::unix::fs::PermissionsExt;
+			assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+		}
+
+		// the same content is not written again, so no backup is made either
+		let options = WriteOptions {
+			backup: true,
+			..Default::default()
+		};
+		assert_eq!(write_key_file(&path, KEY, KEY, options).unwrap(), KeyWrite::Unchanged);
+		assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+	}
+
+	#[test]
+	fn write_other_key() {
+		let dir = TempDir::new("ade-extract-key-test").unwrap();
+		let path = dir.path().join("key.der");
+		std::fs::write(&path, b"some other key").unwrap();
+
+		// a different key is not overwritten
+		let err = write_key_file(&path, KEY, KEY, WriteOptions::default()).unwrap_err();
+		assert_eq!(crate::exit::category(&err), ErrorCategory::Usage);
+		assert!(err.to_string().contains("--force"), "{err}");
+		assert_eq!(read(&path), b"some other key");
+
+		// unless forced, with a backup of the other key
+		let options = WriteOptions {
+			force: true,
+			backup: true,
+			..Default::default()
+		};
+		let KeyWrite::Written(Some(backup)) = write_key_file(&path, KEY, KEY, options).unwrap() else {
+			panic!("Expected the file to be written with a backup");
+		};
+		assert_eq!(read(&path), KEY);
+		assert_eq!(read(&backup), b"some other key");
+		assert!(backup.to_string_lossy().ends_with(".bak"), "{}", backup.display());
+	}
+
+	#[test]
+	fn write_same_key_other_format() {
+		let dir = TempDir::new("ade-extract-key-test").unwrap();
+		let path = dir.path().join("key");
+
+		// the same key in any other format may be replaced without "--force"
+		for format in KeyFormat::ALL {
+			let existing = encode_key(KEY, format).unwrap();
+			std::fs::write(&path, existing.expose()).unwrap();
+
+			let content = encode_key(KEY, KeyFormat::Jwk).unwrap();
+			let expected = if format == KeyFormat::Jwk {
+				KeyWrite::Unchanged
+			} else {
+				KeyWrite::Written(None)
+			};
+			assert_eq!(
+				write_key_file(&path, content.expose(), KEY, WriteOptions::default()).unwrap(),
+				expected,
+				"{format:?}"
+			);
+			assert_eq!(read(&path), content.expose());
+		}
+	}
+
+	#[test]
+	fn write_over_encrypted_key() {
+		let dir = TempDir::new("ade-extract-key-test").unwrap();
+		let path = dir.path().join("key.pem");
+		let existing = encrypt_key(KEY, b"passphrase", Kdf::Scrypt, true).unwrap();
+		std::fs::write(&path, existing.expose()).unwrap();
+
+		// encrypting again gives a different file, which has to be decrypted to compare the key
+		let content = encrypt_key(KEY, b"passphrase", Kdf::Scrypt, true).unwrap();
+		assert!(write_key_file(&path, content.expose(), KEY, WriteOptions::default()).is_err());
+
+		let options = WriteOptions {
+			passphrase: Some(b"other passphrase"),
+			..Default::default()
+		};
+		assert!(write_key_file(&path, content.expose(), KEY, options).is_err());
+		assert_eq!(read(&path), existing.expose());
+
+		let options = WriteOptions {
+			passphrase: Some(b"passphrase"),
+			..Default::default()
+		};
+		assert_eq!(
+			write_key_file(&path, content.expose(), KEY, options).unwrap(),
+			KeyWrite::Written(None)
+		);
+		assert_eq!(read(&path), content.expose());
+	}
+}
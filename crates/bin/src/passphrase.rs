@@ -0,0 +1,46 @@
+//! Module for getting a passphrase from the configured source
+
+use anyhow::Context;
+use libade_extract_key::secret::SecretString;
+
+use crate::clap_conf::PassphraseArgs;
+
+/// Get the passphrase from the environment variable, file or prompt (in that order)
+/// "confirm" will prompt a second time to confirm the passphrase (only applies to prompting)
+pub fn get_passphrase(args: &PassphraseArgs, confirm: bool) -> anyhow::Result<SecretString> {
+	let passphrase = if let Some(var) = &args.passphrase_env {
+		SecretString::from(
+			std::env::var(var)
+				.with_context(|| return format!("Failed to read passphrase from environment variable \"{var}\""))?,
+		)
+	} else if let Some(path) = &args.passphrase_file {
+		let content = SecretString::from(
+			std::fs::read_to_string(path)
+				.with_context(|| return format!("Failed to read passphrase file \"{}\"", path.display()))?,
+		);
+
+		// only the first line is used, so that trailing newlines dont become part of the passphrase
+		SecretString::from(content.expose().lines().next().unwrap_or_default())
+	} else {
+		let passphrase =
+			SecretString::from(rpassword::prompt_password("Passphrase: ").context("Failed to prompt for passphrase")?);
+
+		if confirm {
+			let confirmation = SecretString::from(
+				rpassword::prompt_password("Confirm Passphrase: ").context("Failed to prompt for passphrase")?,
+			);
+
+			if passphrase != confirmation {
+				return Err(crate::Error::usage("Passphrases do not match").into());
+			}
+		}
+
+		passphrase
+	};
+
+	if passphrase.expose().is_empty() {
+		return Err(crate::Error::usage("Passphrase cannot be empty").into());
+	}
+
+	return Ok(passphrase);
+}
@@ -0,0 +1,709 @@
+//! Module for the "tui" subcommand, a interactive wizard that guides through the extraction step by step
+//! Every step maps onto the same library calls the other subcommands use
+
+use std::path::{
+	Path,
+	PathBuf,
+};
+
+use clap::ValueEnum;
+use libade_extract_key::{
+	decrypt::{
+		AdeptInformation,
+		WineSnapshot,
+	},
+	doctor::{
+		CheckResult,
+		CheckStatus,
+	},
+	error::display_chain,
+	key::KeyVerification,
+	pipeline::{
+		GatherResult,
+		PipelineState,
+	},
+	prefix::PrefixCandidate,
+	secret::SecretString,
+	store::{
+		KeyStore,
+		StoreAdd,
+	},
+};
+use ratatui::{
+	crossterm::event::{
+		self,
+		Event,
+		KeyCode,
+		KeyEventKind,
+		KeyModifiers,
+	},
+	layout::{
+		Constraint,
+		Layout,
+		Rect,
+	},
+	style::{
+		Color,
+		Style,
+		Stylize,
+	},
+	text::{
+		Line,
+		Span,
+	},
+	widgets::{
+		Block,
+		List,
+		ListItem,
+		ListState,
+		Paragraph,
+		Wrap,
+	},
+	DefaultTerminal,
+	Frame,
+};
+
+use crate::{
+	clap_conf::{
+		CliDerive,
+		FormatArg,
+	},
+	output,
+};
+
+/// The steps of the wizard, in order
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Step {
+	/// Choose the wine prefix ADE is installed in
+	Prefix,
+	/// Check the environment, like "doctor"
+	Checks,
+	/// Choose the accounts (activations) to extract the keys of
+	Accounts,
+	/// Choose the format the keys are written as
+	Format,
+	/// Choose the directory the keys are written to
+	Destination,
+	/// Show the written keys and their fingerprints
+	Result,
+}
+
+impl Step {
+	/// Get the title of the step
+	fn title(self) -> &'static str {
+		return match self {
+			Self::Prefix => "1/6 Wine Prefix",
+			Self::Checks => "2/6 Environment",
+			Self::Accounts => "3/6 Accounts",
+			Self::Format => "4/6 Format",
+			Self::Destination => "5/6 Destination",
+			Self::Result => "6/6 Result",
+		};
+	}
+
+	/// Get the key hints of the step
+	fn hints(self) -> &'static str {
+		return match self {
+			Self::Prefix | Self::Format => "Up/Down: select, Enter: continue, Esc: back, q: quit",
+			Self::Checks => "Enter: continue, r: re-run checks, Esc: back, q: quit",
+			Self::Accounts => "Up/Down: select, Space: toggle, Enter: continue, Esc: back, q: quit",
+			Self::Destination => "Type the directory, Enter: extract, Esc: back, Ctrl+C: quit",
+			Self::Result => "Enter / q: quit",
+		};
+	}
+}
+
+/// The state of the wizard
+struct App<'a> {
+	cli:           &'a CliDerive,
+	step:          Step,
+	/// Set when the wizard should exit
+	quit:          bool,
+	/// Error of the last action, shown until the next action
+	error:         Option<String>,
+	prefixes:      Vec<PrefixCandidate>,
+	prefix_state:  ListState,
+	checks:        Vec<CheckResult>,
+	/// The snapshot of the last checks, reused for the extraction so that wine is only started once
+	snapshot:      Option<WineSnapshot>,
+	/// Result of the "gather" stage from "snapshot", which has the activations
+	gather:        Option<GatherResult>,
+	/// Which of the activations are selected, same order
+	selected:      Vec<bool>,
+	account_state: ListState,
+	format_state:  ListState,
+	destination:   String,
+	/// The passphrase the keys are encrypted with ("--encrypt"), asked for before the wizard starts
+	passphrase:    Option<SecretString>,
+	/// One line per key that was handled in the "Result" step
+	result_lines:  Vec<Line<'static>>,
+}
+
+/// Move the selection of "state" by "delta" in a list of "len" items, without wrapping around
+fn move_selection(state: &mut ListState, len: usize, delta: isize) {
+	if len == 0 {
+		return;
+	}
+
+	let current = state.selected().unwrap_or(0);
+	state.select(Some(current.saturating_add_signed(delta).min(len - 1)));
+}
+
+impl<'a> App<'a> {
+	fn new(cli: &'a CliDerive, passphrase: Option<SecretString>) -> Self {
+		let prefixes = libade_extract_key::prefix::discover_prefixes();
+		// preselect the first prefix ADE is installed in
+		let prefix_index = prefixes.iter().position(|v| return v.has_ade).unwrap_or(0);
+		let format_index = FormatArg::value_variants()
+			.iter()
+			.position(|v| return *v == cli.format)
+			.unwrap_or(0);
+
+		let mut app = Self {
+			cli,
+			// there are no prefixes on windows, so that step is skipped
+			step: if cfg!(windows) { Step::Checks } else { Step::Prefix },
+			quit: false,
+			error: None,
+			prefixes,
+			prefix_state: ListState::default().with_selected(Some(prefix_index)),
+			checks: Vec::new(),
+			snapshot: None,
+			gather: None,
+			selected: Vec::new(),
+			account_state: ListState::default().with_selected(Some(0)),
+			format_state: ListState::default().with_selected(Some(format_index)),
+			destination: cli
+				.output_file_name
+				.as_ref()
+				.map_or_else(|| return String::from("."), |v| return v.display().to_string()),
+			passphrase,
+			result_lines: Vec::new(),
+		};
+
+		// there is no prefix to choose on windows, so the checks run right away
+		if cfg!(windows) {
+			app.run_checks();
+		}
+
+		return app;
+	}
+
+	/// Get the activations of the "gather" stage, empty if it did not run yet
+	fn activations(&self) -> &[AdeptInformation] {
+		return self.gather.as_ref().map_or(&[], |v| return &v.adept_infos);
+	}
+
+	/// Get the format that is currently selected
+	fn format(&self) -> FormatArg {
+		return FormatArg::value_variants()[self.format_state.selected().unwrap_or(0)];
+	}
+
+	/// Handle a key press
+	fn on_key(&mut self, code: KeyCode, modifiers: KeyModifiers, terminal: &mut DefaultTerminal) {
+		if modifiers.contains(KeyModifiers::CONTROL) && code == KeyCode::Char('c') {
+			self.quit = true;
+			return;
+		}
+
+		// "q" is a valid character for the destination
+		if code == KeyCode::Char('q') && self.step != Step::Destination {
+			self.quit = true;
+			return;
+		}
+
+		match (self.step, code) {
+			(Step::Prefix, KeyCode::Up) => move_selection(&mut self.prefix_state, self.prefixes.len(), -1),
+			(Step::Prefix, KeyCode::Down) => move_selection(&mut self.prefix_state, self.prefixes.len(), 1),
+			(Step::Prefix, KeyCode::Enter) => self.choose_prefix(),
+			(Step::Prefix, KeyCode::Esc) => self.quit = true,
+			(Step::Checks, KeyCode::Char('r')) => self.run_checks(),
+			(Step::Checks, KeyCode::Enter) => self.load_accounts(),
+			(Step::Checks, KeyCode::Esc) => self.back(),
+			(Step::Accounts, KeyCode::Up) => move_selection(&mut self.account_state, self.selected.len(), -1),
+			(Step::Accounts, KeyCode::Down) => move_selection(&mut self.account_state, self.selected.len(), 1),
+			(Step::Accounts, KeyCode::Char(' ')) => {
+				if let Some(selected) = self
+					.account_state
+					.selected()
+					.and_then(|v| return self.selected.get_mut(v))
+				{
+					*selected = !*selected;
+				}
+			},
+			(Step::Accounts, KeyCode::Enter) => {
+				if self.selected.iter().any(|v| return *v) {
+					self.next(Step::Format);
+				} else {
+					self.error = Some(String::from("Select at least one account"));
+				}
+			},
+			(Step::Accounts, KeyCode::Esc) => self.back(),
+			(Step::Format, KeyCode::Up) => {
+				move_selection(&mut self.format_state, FormatArg::value_variants().len(), -1);
+			},
+			(Step::Format, KeyCode::Down) => {
+				move_selection(&mut self.format_state, FormatArg::value_variants().len(), 1);
+			},
+			(Step::Format, KeyCode::Enter) => {
+				if self.passphrase.is_some() && !self.format().encryptable() {
+					self.error = Some(String::from(
+						"\"--encrypt\" only works with \"der\" or \"pem-pkcs8\", select one of them",
+					));
+				} else {
+					self.next(Step::Destination);
+				}
+			},
+			(Step::Format, KeyCode::Esc) => self.back(),
+			(Step::Destination, KeyCode::Char(c)) => self.destination.push(c),
+			(Step::Destination, KeyCode::Backspace) => {
+				self.destination.pop();
+			},
+			(Step::Destination, KeyCode::Enter) => {
+				if self.destination.trim().is_empty() {
+					self.error = Some(String::from("The destination cannot be empty"));
+					return;
+				}
+
+				// the extraction takes a moment, so show that it is running
+				self.error = None;
+				let _ = terminal.draw(|frame| {
+					self.draw(frame);
+					draw_notice(frame, "Extracting, this may take a moment...");
+				});
+
+				self.extract();
+			},
+			(Step::Destination, KeyCode::Esc) => self.back(),
+			(Step::Result, KeyCode::Enter) => self.quit = true,
+			_ => (),
+		}
+	}
+
+	/// Go to the "step" and clear the error
+	fn next(&mut self, step: Step) {
+		self.error = None;
+		self.step = step;
+	}
+
+	/// Go to the previous step
+	fn back(&mut self) {
+		let previous = match self.step {
+			Step::Prefix | Step::Checks if cfg!(windows) => None,
+			Step::Prefix => None,
+			Step::Checks => Some(Step::Prefix),
+			Step::Accounts => Some(Step::Checks),
+			Step::Format => Some(Step::Accounts),
+			Step::Destination => Some(Step::Format),
+			Step::Result => Some(Step::Destination),
+		};
+
+		match previous {
+			Some(step) => self.next(step),
+			None => self.quit = true,
+		}
+	}
+
+	/// Use the selected prefix for everything from now on and check the environment
+	fn choose_prefix(&mut self) {
+		let Some(candidate) = self.prefix_state.selected().and_then(|v| return self.prefixes.get(v)) else {
+			self.error = Some(String::from(
+				"No wine prefix found, use \"--prefix\" to set it directly",
+			));
+			return;
+		};
+
+		libade_extract_key::settings::set(libade_extract_key::settings::Settings {
+			prefix: Some(candidate.path.clone()),
+			// the bottle would take precedence over the chosen prefix
+			bottle: None,
+			..libade_extract_key::settings::get()
+		});
+
+		self.run_checks();
+	}
+
+	/// Run the environment checks and go to the "Checks" step
+	fn run_checks(&mut self) {
+		(self.checks, self.snapshot) = libade_extract_key::doctor::run_checks_with_snapshot();
+		self.gather = None;
+		self.next(Step::Checks);
+	}
+
+	/// Get the activations and go to the "Accounts" step, only if all checks passed
+	fn load_accounts(&mut self) {
+		if self.checks.iter().any(|v| return v.status == CheckStatus::Fail) {
+			self.error = Some(String::from("A check failed, fix it (see the hint) and press \"r\""));
+			return;
+		}
+
+		let Some(snapshot) = &self.snapshot else {
+			self.error = Some(String::from("The checks did not run yet, press \"r\" to run them"));
+			return;
+		};
+
+		match libade_extract_key::pipeline::gather_from_snapshot(snapshot) {
+			Ok(gather) => {
+				self.selected = vec![true; gather.adept_infos.len()];
+				self.gather = Some(gather);
+				self.account_state.select(Some(0));
+				self.next(Step::Accounts);
+			},
+			Err(err) => self.error = Some(format!("Could not get the accounts: {}", display_chain(&err))),
+		}
+	}
+
+	/// Run the extraction for the selected accounts, write the keys and go to the "Result" step
+	fn extract(&mut self) {
+		match self.try_extract() {
+			Ok(lines) => {
+				self.result_lines = lines;
+				self.next(Step::Result);
+			},
+			Err(err) => self.error = Some(format!("Extraction failed: {err:#}")),
+		}
+	}
+
+	/// Run the extraction for the selected accounts and write the keys, returns a line per key
+	fn try_extract(&self) -> anyhow::Result<Vec<Line<'static>>> {
+		let state_path = self.cli.state_path();
+		// the "gather" stage already ran for the "Accounts" step, only the selected accounts are decrypted
+		let mut gather = self
+			.gather
+			.clone()
+			.ok_or_else(|| return anyhow::anyhow!("The accounts were not loaded"))?;
+		gather.adept_infos = gather
+			.adept_infos
+			.into_iter()
+			.zip(&self.selected)
+			.filter(|(_, selected)| return **selected)
+			.map(|(v, _)| return v)
+			.collect();
+
+		let mut state = PipelineState::default();
+		state.gather = Some(gather);
+
+		let final_keys = state.run(|state, stage| {
+			return crate::save_state(state, stage, state_path);
+		})?;
+
+		if let Some(state_path) = state_path {
+			crate::finish_state(self.cli, state_path)?;
+		}
+
+		let mut store = if self.cli.store {
+			Some(KeyStore::open(self.cli.store_dir())?)
+		} else {
+			None
+		};
+		let format = self.format();
+		let write_options = crate::write_options(self.cli, self.passphrase.as_ref());
+		let destination = PathBuf::from(self.destination.trim());
+		let mut lines = Vec::new();
+
+		for (i, entry) in crate::entries_from_state(state, final_keys).iter().enumerate() {
+			let name = entry.adobe_id.clone().unwrap_or_else(|| return String::from("unknown"));
+			let verification =
+				libade_extract_key::key::verify_key(entry.key.expose(), entry.license_certificate.as_deref())?;
+
+			if verification == KeyVerification::Mismatch {
+				lines.push(Line::from(vec![
+					Span::raw(format!("{name}: ")),
+					Span::styled(
+						"key does not match the licenseCertificate, not written",
+						Style::new().fg(Color::Red),
+					),
+				]));
+				continue;
+			}
+
+			let stored = match &mut store {
+				Some(store) => match store.add(entry.key.expose(), entry.metadata())? {
+					StoreAdd::Added(_) => Some("added to the key store"),
+					StoreAdd::AlreadyPresent(_) => Some("already in the key store"),
+				},
+				None => None,
+			};
+
+			let encoded_key = crate::encode_entry(self.cli, entry, format, self.passphrase.as_ref())?;
+			let file_path = output::resolve_output_path(
+				Some(Path::new(&destination)),
+				self.cli.template.as_deref(),
+				&entry.name_info(i + 1, format.into()),
+				true,
+			)?;
+
+			let written =
+				match output::write_key_file(&file_path, encoded_key.expose(), entry.key.expose(), write_options) {
+					Ok(output::KeyWrite::Written(None)) => format!("written to {}", file_path.display()),
+					Ok(output::KeyWrite::Written(Some(backup))) => format!(
+						"written to {}, kept the previous file as {}",
+						file_path.display(),
+						backup.display()
+					),
+					Ok(output::KeyWrite::Unchanged) => format!("{} is already up to date", file_path.display()),
+					Err(err) => {
+						lines.push(Line::from(vec![
+							Span::raw(format!("{name}: ")),
+							Span::styled(format!("{err:#}"), Style::new().fg(Color::Red)),
+						]));
+						continue;
+					},
+				};
+
+			let fingerprint = libade_extract_key::key::fingerprint(entry.key.expose())?;
+
+			lines.push(Line::from(vec![
+				Span::styled(format!("{name}: "), Style::new().bold()),
+				Span::styled(format!("{written} ({verification})"), Style::new().fg(Color::Green)),
+			]));
+			lines.push(Line::from(format!("  fingerprint {fingerprint}")));
+
+			if let Some(stored) = stored {
+				lines.push(Line::from(format!("  {stored}")));
+			}
+		}
+
+		return Ok(lines);
+	}
+
+	/// Draw the current step
+	fn draw(&mut self, frame: &mut Frame) {
+		let [header, body, error, footer] = Layout::vertical([
+			Constraint::Length(1),
+			Constraint::Min(3),
+			Constraint::Length(if self.error.is_some() { 3 } else { 0 }),
+			Constraint::Length(1),
+		])
+		.areas(frame.area());
+
+		frame.render_widget(
+			Line::from(vec![
+				Span::styled("ade-extract-key", Style::new().bold()),
+				Span::raw(format!(" - {}", self.step.title())),
+			]),
+			header,
+		);
+
+		let block = Block::bordered().title(self.step.title());
+
+		match self.step {
+			Step::Prefix => {
+				let items: Vec<ListItem> = self
+					.prefixes
+					.iter()
+					.map(|v| {
+						let status = if v.has_ade {
+							Span::styled("[ADE]  ", Style::new().fg(Color::Green))
+						} else {
+							Span::styled("[no ADE] ", Style::new().fg(Color::DarkGray))
+						};
+
+						return ListItem::new(Line::from(vec![status, Span::raw(v.path.display().to_string())]));
+					})
+					.collect();
+
+				if items.is_empty() {
+					frame.render_widget(
+						Paragraph::new("No wine prefix found, use \"--prefix\" to set it directly").block(block),
+						body,
+					);
+				} else {
+					frame.render_stateful_widget(list(items, block), body, &mut self.prefix_state);
+				}
+			},
+			Step::Checks => {
+				let mut lines = Vec::new();
+
+				for check in &self.checks {
+					let color = match check.status {
+						CheckStatus::Pass => Color::Green,
+						CheckStatus::Warn => Color::Yellow,
+						CheckStatus::Fail => Color::Red,
+					};
+
+					lines.push(Line::from(vec![
+						Span::styled(format!("[{}] ", check.status), Style::new().fg(color)),
+						Span::styled(format!("{}: ", check.name), Style::new().bold()),
+						Span::raw(check.message.clone()),
+					]));
+
+					if let Some(hint) = check.hint {
+						lines.push(Line::from(format!("       hint: {hint}")));
+					}
+				}
+
+				frame.render_widget(Paragraph::new(lines).wrap(Wrap { trim: false }).block(block), body);
+			},
+			Step::Accounts => {
+				let items: Vec<ListItem> = self
+					.activations()
+					.iter()
+					.zip(&self.selected)
+					.map(|(v, selected)| {
+						return ListItem::new(vec![
+							Line::from(format!(
+								"[{}] {} ({})",
+								if *selected { "x" } else { " " },
+								v.username.1,
+								v.username.0
+							)),
+							Line::from(format!(
+								"    {}, licenseCertificate {}",
+								v.user,
+								if v.license_certificate.is_some() {
+									"present"
+								} else {
+									"missing"
+								}
+							))
+							.fg(Color::DarkGray),
+						]);
+					})
+					.collect();
+
+				frame.render_stateful_widget(list(items, block), body, &mut self.account_state);
+			},
+			Step::Format => {
+				let items: Vec<ListItem> = FormatArg::value_variants()
+					.iter()
+					.filter_map(|v| return v.to_possible_value())
+					.map(|v| {
+						return ListItem::new(Line::from(vec![
+							Span::styled(format!("{:10} ", v.get_name()), Style::new().bold()),
+							Span::raw(v.get_help().map(ToString::to_string).unwrap_or_default()),
+						]));
+					})
+					.collect();
+
+				frame.render_stateful_widget(list(items, block), body, &mut self.format_state);
+			},
+			Step::Destination => {
+				let lines = vec![
+					Line::from("Directory the key file(s) are written to (created if missing):"),
+					Line::from(""),
+					Line::from(vec![
+						Span::raw("> "),
+						Span::styled(self.destination.clone(), Style::new().bold()),
+						Span::raw("_"),
+					]),
+				];
+
+				frame.render_widget(Paragraph::new(lines).block(block), body);
+			},
+			Step::Result => {
+				frame.render_widget(
+					Paragraph::new(self.result_lines.clone())
+						.wrap(Wrap { trim: false })
+						.block(block),
+					body,
+				);
+			},
+		}
+
+		if let Some(err) = &self.error {
+			frame.render_widget(
+				Paragraph::new(err.as_str())
+					.wrap(Wrap { trim: false })
+					.fg(Color::Red)
+					.block(Block::bordered().title("Error")),
+				error,
+			);
+		}
+
+		frame.render_widget(Line::from(self.step.hints()).fg(Color::DarkGray), footer);
+	}
+}
+
+/// Create a list with the style all lists of the wizard use
+fn list<'a>(items: Vec<ListItem<'a>>, block: Block<'a>) -> List<'a> {
+	return List::new(items)
+		.block(block)
+		.highlight_symbol("> ")
+		.highlight_style(Style::new().reversed());
+}
+
+/// Draw a notice in the middle of the frame, over everything else
+fn draw_notice(frame: &mut Frame, text: &str) {
+	let area = frame.area();
+	let width = (text.len() as u16 + 4).min(area.width);
+	let notice = Rect {
+		x: area.x + (area.width - width) / 2,
+		y: area.y + area.height / 2,
+		width,
+		height: 3.min(area.height),
+	};
+
+	frame.render_widget(ratatui::widgets::Clear, notice);
+	frame.render_widget(Paragraph::new(text).block(Block::bordered()), notice);
+}
+
+/// Run the wizard until the user quits
+pub fn run(cli: &CliDerive) -> anyhow::Result<()> {
+	// the passphrase cannot be prompted for while the wizard is drawn
+	let passphrase = if cli.encrypt {
+		Some(crate::passphrase::get_passphrase(&cli.passphrase, true)?)
+	} else {
+		None
+	};
+
+	let mut terminal = ratatui::try_init()?;
+
+	let res = run_app(&mut terminal, cli, passphrase);
+
+	ratatui::try_restore()?;
+
+	let app = res?;
+
+	// keep the result visible after the terminal was restored
+	for line in &app.result_lines {
+		println!("{line}");
+	}
+
+	return Ok(());
+}
+
+/// Run the event loop of the wizard and return the final state
+fn run_app<'a>(
+	terminal: &mut DefaultTerminal,
+	cli: &'a CliDerive,
+	passphrase: Option<SecretString>,
+) -> anyhow::Result<App<'a>> {
+	let mut app = App::new(cli, passphrase);
+
+	while !app.quit {
+		terminal.draw(|frame| app.draw(frame))?;
+
+		if let Event::Key(key) = event::read()? {
+			// ignore key releases (windows reports both)
+			if key.kind == KeyEventKind::Press {
+				app.on_key(key.code, key.modifiers, terminal);
+			}
+		}
+	}
+
+	return Ok(app);
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn selection_stays_in_bounds() {
+		let mut state = ListState::default();
+
+		// nothing to select in a empty list
+		move_selection(&mut state, 0, 1);
+		assert_eq!(state.selected(), None);
+
+		move_selection(&mut state, 3, 1);
+		assert_eq!(state.selected(), Some(1));
+		move_selection(&mut state, 3, 5);
+		assert_eq!(state.selected(), Some(2));
+		move_selection(&mut state, 3, -1);
+		assert_eq!(state.selected(), Some(1));
+		move_selection(&mut state, 3, -5);
+		assert_eq!(state.selected(), Some(0));
+	}
+}
@@ -1,13 +1,14 @@
 use std::{
 	arch::x86_64::__cpuid,
+	ffi::OsStr,
 	io::Write,
-	process::{
-		Command,
-		Stdio,
+	path::{
+		Path,
+		PathBuf,
 	},
+	process::Command,
 };
 
-use anyhow::Context;
 use base64::Engine;
 use byteorder::{
 	BigEndian,
@@ -15,33 +16,90 @@ use byteorder::{
 };
 use once_cell::sync::Lazy;
 use regex::Regex;
+use serde::{
+	Deserialize,
+	Serialize,
+};
 use sysinfo::{
 	CpuRefreshKind,
 	RefreshKind,
 };
 
+use zeroize::Zeroizing;
+
+use crate::{
+	error::{
+		HexError,
+		KeyFormatError,
+		WinapiProtocolError,
+	},
+	regfile::{
+		RegExport,
+		RegKey,
+	},
+	secret::{
+		SecretBytes,
+		SecretString,
+	},
+};
+
+const ADEPT_KEY_PATH: &str = r"HKEY_CURRENT_USER\Software\Adobe\Adept";
 const DEVICE_KEY_PATH: &str = r"HKEY_CURRENT_USER\Software\Adobe\Adept\Device";
 const ACTIVATION_KEY_PATH: &str = r"HKEY_CURRENT_USER\Software\Adobe\Adept\Activation";
 
+/// File name of the winapi-bin, which is expected in the current directory by default
+pub(crate) const WINAPI_BIN_NAME: &str = "ade-extract-winapi-bin.exe";
+
 /// Create a new instance of [Command]
-fn new_command(cmd: &str) -> Command {
+#[cfg(windows)]
+fn new_command<S: AsRef<OsStr>>(cmd: S) -> Command {
 	return Command::new(cmd);
 }
 
-/// Create a new instance of [Command] with "wine"
-#[cfg(not(windows))]
-fn new_wine_cmd() -> Command {
-	return new_command("wine");
+/// Get the path of the winapi-bin, either the configured one or "ade-extract-winapi-bin.exe" in the current directory
+pub fn winapi_bin_path() -> PathBuf {
+	return crate::settings::get()
+		.winapi_bin
+		.unwrap_or_else(|| return Path::new(".").join(WINAPI_BIN_NAME));
+}
+
+/// Get the wine prefix the commands run in (the CrossOver bottle, the configured one, "WINEPREFIX" or wine's default "~/.wine")
+/// Always "None" on windows, because there is no prefix
+pub fn wine_prefix() -> Option<PathBuf> {
+	#[cfg(windows)]
+	{
+		return None;
+	}
+
+	#[cfg(not(windows))]
+	{
+		let settings = crate::settings::get();
+
+		// same order as [crate::wine::command], the bottle decides the prefix for CrossOver
+		if let Some(bottle) = &settings.bottle {
+			return crate::wine::bottle_dir(bottle);
+		}
+
+		if let Some(prefix) = settings.prefix {
+			return Some(prefix);
+		}
+
+		if let Some(prefix) = std::env::var_os("WINEPREFIX").filter(|v| return !v.is_empty()) {
+			return Some(PathBuf::from(prefix));
+		}
+
+		return std::env::var_os("HOME").map(|v| return PathBuf::from(v).join(".wine"));
+	}
 }
 
 /// Execute a command inside wine
 #[cfg(not(windows))]
-fn do_wine_like_cmd(cmd_i: &str) -> Command {
+fn do_wine_like_cmd<S: AsRef<OsStr>>(cmd_i: S) -> Command {
 	// pass-through to direct exec
 	// return new_command(cmd);
 
 	// use wine first
-	let mut cmd = new_wine_cmd();
+	let mut cmd = crate::wine::command();
 	cmd.arg(cmd_i);
 
 	return cmd;
@@ -49,96 +107,145 @@ fn do_wine_like_cmd(cmd_i: &str) -> Command {
 
 /// Execute a command, without wine as we already are in a windows environment
 #[cfg(windows)]
-fn do_wine_like_cmd(cmd_i: &str) -> Command {
+fn do_wine_like_cmd<S: AsRef<OsStr>>(cmd_i: S) -> Command {
 	return new_command(cmd_i);
 }
 
-/// execute a given wine command and print surrounding information
-fn exec_cmd_and_wait(mut cmd: Command) -> anyhow::Result<String> {
-	let cmd_out = cmd
-		.stderr(Stdio::null())
-		.stdin(Stdio::null())
-		.stdout(Stdio::piped())
-		.spawn()
-		.context("Failed to spawn command")?
-		.wait_with_output()
-		.context("Failed to wait for output of command")?;
-
-	let as_string = String::from_utf8(cmd_out.stdout).context("Failed converting output to utf8 string")?;
-
-	return Ok(as_string);
-}
-
 /// Regex for parsing output from "vol"
 static PARSE_SERIAL_REGEX: Lazy<Regex> = Lazy::new(|| {
 	return Regex::new(r"(?mi)Volume Serial Number is ([^\r\n]+)").unwrap();
 });
 
+/// Marker for the echoed "SystemRoot" in the output of the snapshot command
+pub(crate) const SYSTEM_ROOT_MARKER: &str = "ade-extract-key-root=";
+/// Marker for the echoed "USERNAME" in the output of the snapshot command
+pub(crate) const USERNAME_MARKER: &str = "ade-extract-key-user=";
+/// File name of the registry export of [ADEPT_KEY_PATH], relative to the working directory of the snapshot command
+const ADEPT_EXPORT_FILE: &str = "adept.reg";
+
+/// Everything the extraction needs from windows / wine, gathered by a single command (see [WineSnapshot::take])
 #[derive(Debug)]
-pub struct DriveInfo {
-	/// Drive letter where the system is installed (mostly "C:")
-	pub win_system_drive:         String,
-	/// The Volume Serial Number of the System Drive
-	pub win_system_volume_serial: u32,
+pub struct WineSnapshot {
+	/// Output of the snapshot command, which includes the output of "vol"
+	output: String,
+	/// The exported [ADEPT_KEY_PATH], "None" if it does not exist (like when ADE was never started)
+	adept:  Option<RegExport>,
 }
 
-/// Retrieves and parses all information related to drives
-pub fn get_drive_info() -> anyhow::Result<DriveInfo> {
-	// exec and parse output
-	// wine cmd "/k echo %SystemRoot% && exit"
-	// expected output:
-	// C:\windows
-	// required output:
-	// C:\\
+impl WineSnapshot {
+	/// Run a single command (one wine start) that gets the system root, the volume serial, the windows username and exports the whole [ADEPT_KEY_PATH]
+	/// The export is written to a temporary directory and parsed locally
+	pub fn take() -> Result<Self, crate::Error> {
+		// the export is written to the working directory, so that the command line is the same every time (for "--replay")
+		let tmp_dir = crate::file::TempDir::new("ade-extract-key")?;
+
+		let mut snapshot_cmd = do_wine_like_cmd("cmd");
+		snapshot_cmd.current_dir(tmp_dir.path()).args([
+			"/c",
+			"echo",
+			&format!("{SYSTEM_ROOT_MARKER}%SystemRoot%"),
+			"&",
+			"vol",
+			"%SystemDrive%",
+			"&",
+			"echo",
+			&format!("{USERNAME_MARKER}%USERNAME%"),
+			"&",
+			"reg",
+			"export",
+			ADEPT_KEY_PATH,
+			ADEPT_EXPORT_FILE,
+			"/y",
+		]);
+		let cmd_line = crate::runner::command_line(&snapshot_cmd);
+
+		let (output, export) =
+			crate::runner::get().run_with_output_file(snapshot_cmd, None, &tmp_dir.path().join(ADEPT_EXPORT_FILE))?;
+		let export = export.map(Zeroizing::new);
+
+		// the exit code is the one of "reg export", which fails if ADE was never started
+		if find_marker(&output.stdout, SYSTEM_ROOT_MARKER).is_none() {
+			return Err(crate::Error::CommandStatus {
+				cmd:    cmd_line,
+				code:   output.code,
+				stderr: crate::runner::stderr_tail(&output.stderr),
+			});
+		}
 
-	// exec and parse output (replace ROOT with last output's root)
-	// wine cmd "/c" "vol ROOT"
-	// expected output:
-	// Volume in drive c has no label.
-	// Volume Serial Number is 4300-0000
-	// required output:
-	// 1124073472L (convert number to decimal from hex)
-	let root_dir: String;
-	{
-		let mut root_dir_cmd = do_wine_like_cmd("cmd");
-		root_dir_cmd.args(["/c echo %SystemRoot%"]);
+		if export.is_none() {
+			debug!("\"{ADEPT_KEY_PATH}\" was not exported: {}", output.stderr.trim());
+		}
+
+		let adept = export.as_deref().map(|v| return RegExport::from_bytes(v)).transpose()?;
 
-		let root_dir_out = exec_cmd_and_wait(root_dir_cmd).context("cmd /c echo %SystemRoot%")?;
-		root_dir = root_dir_out
+		return Ok(Self {
+			output: output.stdout,
+			adept,
+		});
+	}
+
+	/// Get the drive information, from the output of "echo %SystemRoot%" and "vol"
+	pub fn drive_info(&self) -> Result<DriveInfo, crate::Error> {
+		// expected output:
+		// C:\windows
+		// required output:
+		// C:
+		let system_root = find_marker(&self.output, SYSTEM_ROOT_MARKER).unwrap_or_default();
+		let root_dir = system_root
 			.split('\\')
 			.next()
-			.ok_or_else(|| return crate::Error::other(format!("Failed to split at \"\\\" with \"{}\"", root_dir_out)))?
+			.filter(|v| return !v.is_empty())
+			.ok_or_else(|| return crate::Error::other(format!("Failed to split at \"\\\" with \"{system_root}\"")))?
 			.to_owned();
-	}
-	info!("Got RootDir \"{root_dir}\"");
-
-	let serial: u32;
-	{
-		let mut serial_cmd = do_wine_like_cmd("cmd");
-		serial_cmd.args(["/c", &format!("vol {root_dir}")]);
-
-		let serial_out = exec_cmd_and_wait(serial_cmd).context("cmd /c vol")?;
-
-		let caps = PARSE_SERIAL_REGEX.captures(&serial_out).ok_or_else(|| {
+		info!("Got RootDir \"{root_dir}\"");
+
+		// expected output:
+		// Volume in drive c has no label.
+		// Volume Serial Number is 4300-0000
+		// required output:
+		// 1124073472 (convert number to decimal from hex)
+		let caps = PARSE_SERIAL_REGEX.captures(&self.output).ok_or_else(|| {
 			return crate::Error::no_captures("Volume Serial Number");
 		})?;
 		let serial_hex = &caps[1].replace('-', "");
 
 		trace!("Volume serial: {serial_hex}");
 
-		serial = u32::from_str_radix(serial_hex.trim(), 16)?;
+		let serial = u32::from_str_radix(serial_hex.trim(), 16)
+			.map_err(|err| return crate::Error::parse(format!("Volume Serial Number \"{serial_hex}\""), err))?;
+		info!("Got Volume Serial \"{serial}\"");
+
+		return Ok(DriveInfo {
+			win_system_drive:         root_dir,
+			win_system_volume_serial: serial,
+		});
 	}
-	info!("Got Volume Serial \"{serial}\"");
+}
 
-	return Ok(DriveInfo {
-		win_system_drive:         root_dir,
-		win_system_volume_serial: serial,
-	});
+/// Get the value after "marker" in "output" (like "ade-extract-key-root=C:\windows"), if it was echoed
+fn find_marker<'a>(output: &'a str, marker: &str) -> Option<&'a str> {
+	return output
+		.lines()
+		.find_map(|v| return v.trim().strip_prefix(marker))
+		.map(str::trim);
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DriveInfo {
+	/// Drive letter where the system is installed (mostly "C:")
+	pub win_system_drive:         String,
+	/// The Volume Serial Number of the System Drive
+	pub win_system_volume_serial: u32,
+}
+
+/// Retrieves and parses all information related to drives, see [WineSnapshot::drive_info] to not take a new snapshot
+pub fn get_drive_info() -> Result<DriveInfo, crate::Error> {
+	return WineSnapshot::take()?.drive_info();
 }
 
 // This is a old way, but keeping this for reference
 // /// Get the vendor-id via `lscpu` linux command
-// fn get_vendor_id_lscpu() -> anyhow::Result<String> {
+// fn get_vendor_id_lscpu() -> Result<String, crate::Error> {
 // 	/// Regex for parsing output from "lscpu"
 // 	static LSCPU_VENDOR_REGEX: Lazy<Regex> = Lazy::new(|| {
 // 		return Regex::new(r"(?mi)Vendor ID:\s+([^\r\n]+)").unwrap();
@@ -153,7 +260,7 @@ pub fn get_drive_info() -> anyhow::Result<DriveInfo> {
 // 	return Ok(caps[1].to_owned());
 // }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CpuInfo {
 	/// The Vendor of the CPU
 	pub cpu_vendor:       String,
@@ -162,7 +269,7 @@ pub struct CpuInfo {
 }
 
 /// Retrieves and parses all information related to cpu
-pub fn get_cpu_info() -> anyhow::Result<CpuInfo> {
+pub fn get_cpu_info() -> Result<CpuInfo, crate::Error> {
 	// exec and parse output
 	// lscpu | sed -n "s/Vendor ID:[ \t]*//p"
 	// expected output:
@@ -191,6 +298,8 @@ pub fn get_cpu_info() -> anyhow::Result<CpuInfo> {
 		// x86_64
 		#[cfg(target_arch = "x86_64")]
 		{
+			// "__cpuid" is only safe to call since rust 1.87, but MSRV requires the unsafe block
+			#[allow(unused_unsafe)]
 			let res = unsafe { __cpuid(0x00001) };
 			let eax_bytes = res.eax.to_be_bytes();
 			trace!("Raw CPU Magic number: {:#?}", eax_bytes);
@@ -200,8 +309,7 @@ pub fn get_cpu_info() -> anyhow::Result<CpuInfo> {
 				return Err(crate::Error::assertion_failed(format!(
 					"Expected cpuid EAX bytes to be length 4, got {}",
 					eax_bytes.len()
-				))
-				.into());
+				)));
 			}
 
 			// skip first byte, because ADE does not use it
@@ -216,262 +324,205 @@ pub fn get_cpu_info() -> anyhow::Result<CpuInfo> {
 	});
 }
 
-/// Regex for parsing output from "cpuid"
-static ADEPT_USERNAME_REGEX: Lazy<Regex> = Lazy::new(|| {
-	return Regex::new(r"(?mi)username\s+REG_SZ\s+([^\r\n]+)").unwrap();
-});
-
-/// Try to get the username that Adobe used
-fn get_win_username_adept() -> anyhow::Result<String> {
-	// exec and parse output
-	// wine reg query HKEY_CURRENT_USER\\Software\\Adobe\\Adept\\Device /v username
-	// expected output:
-	// HKEY_CURRENT_USER\Software\Adobe\Adept\Device
-	//     username    REG_SZ    userNameHere
-	// required output:
-	// userNameHere
-	let mut adept_username_cmd = do_wine_like_cmd("reg");
-	adept_username_cmd.args([
-		"query",
-		DEVICE_KEY_PATH,
-		// "HKEY_CURRENT_USER\\Software\\Adobe\\Adept\\Device",
-		"/v",
-		"username",
-	]);
-
-	let adept_username_out = exec_cmd_and_wait(adept_username_cmd).context("reg query DEVICE_KEY_PATH username")?;
-	let caps = ADEPT_USERNAME_REGEX.captures(&adept_username_out).ok_or_else(|| {
-		return crate::Error::no_captures("adept username");
-	})?;
-
-	let username = caps[1].to_owned();
-
-	info!("Got username from Adept \"{username}\"");
-	return Ok(username);
+#[derive(Debug)]
+struct AdeptInformationSubEntry {
+	/// The "urn:uuid" of the used account
+	user:                String,
+	/// The method & AdobeID that is used (method, id)
+	username:            (String, String),
+	/// The raw key
+	key:                 SecretString,
+	/// The certificate containing the public key for "key", if found
+	license_certificate: Option<String>,
 }
 
-/// Try to get the username from a environment variable
-fn get_win_username_echo() -> anyhow::Result<String> {
-	// exec and parse output
-	// wine cmd "/k echo %username% && exit"
-	// expected output:
-	// userNameHere
-	// required output:
-	// userNameHere
-	let mut echo_username_cmd = do_wine_like_cmd("cmd");
-	echo_username_cmd.args(["/c", "echo", "%username%"]);
-
-	let username = {
-		let mut tmp = exec_cmd_and_wait(echo_username_cmd).context("cmd /c echo %username%")?;
-		let trim_len = tmp.trim_end().len();
-		tmp.truncate(trim_len);
-		tmp
-	};
-
-	info!("Got username from echo \"{username}\"");
-	return Ok(username);
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdeptInformation {
+	/// The "urn:uuid" of the used account
+	pub user:                String,
+	/// The method & AdobeID that is used (method, id)
+	pub username:            (String, String),
+	/// The raw key
+	pub key:                 SecretString,
+	/// The key of the device
+	pub device_key:          SecretString,
+	/// The certificate (base64) containing the public key for "key", if found
+	pub license_certificate: Option<String>,
 }
 
-/// Get the username from Adobe, and fallback to environment variable if not found
-pub fn get_win_username() -> anyhow::Result<String> {
-	let adept_res = get_win_username_adept();
+impl WineSnapshot {
+	/// Get the key at "path" from the Adept export
+	fn adept_key(&self, path: &str) -> Option<&RegKey> {
+		return self.adept.as_ref()?.key(path);
+	}
 
-	let Err(adept_err) = adept_res else {
-		return adept_res;
-	};
-	info!("Adept username failed {}", adept_err);
+	/// Get the username that Adobe used, and fallback to the "USERNAME" environment variable if not found
+	pub fn win_username(&self) -> Result<String, crate::Error> {
+		if let Some(username) = self
+			.adept_key(DEVICE_KEY_PATH)
+			.and_then(|v| return v.string("username"))
+		{
+			info!("Got username from Adept \"{username}\"");
 
-	return get_win_username_echo();
-}
+			return Ok(username.to_owned());
+		}
+		info!("Adept username not found");
 
-/// Regex for parsing output from "cpuid"
-static ADEPT_PARSE_VALUE_REGEX: Lazy<Regex> = Lazy::new(|| {
-	return Regex::new(r"(?mis)^\s+value\s+REG_SZ\s+([^\r\n]+)").unwrap();
-});
+		let username = find_marker(&self.output, USERNAME_MARKER)
+			.filter(|v| return !v.is_empty())
+			.ok_or_else(|| return crate::Error::no_captures("username"))?
+			.to_owned();
 
-/// Helper function to parse the "user" reg-entry
-fn adept_information_parse_user(val: &str) -> Option<String> {
-	let caps = ADEPT_PARSE_VALUE_REGEX
-		.captures(val)
-		.ok_or_else(|| {
-			return crate::Error::no_captures("adept-parse \"user\"");
-		})
-		.ok()?;
-	return Some(caps[1].to_owned());
-}
+		info!("Got username from echo \"{username}\"");
+		return Ok(username);
+	}
 
-/// Regex for parsing output from "cpuid"
-static ADEPT_PARSE_USERNAME_REGEX: Lazy<Regex> = Lazy::new(|| {
-	return Regex::new(r"(?mis)\s+method\s+REG_SZ\s+([^\r\n]+)\r?\n\s+value\s+REG_SZ\s+([^\r\n]+)").unwrap();
-});
+	/// Get the activations (sub-keys of [ACTIVATION_KEY_PATH]) with credentials, which are the authorized accounts
+	fn activations(&self) -> impl Iterator<Item = &RegKey> {
+		return self
+			.adept
+			.iter()
+			.flat_map(|v| return v.subkeys(ACTIVATION_KEY_PATH))
+			.filter(|v| return v.string("") == Some("credentials"));
+	}
 
-/// Helper function to parse the "username" reg-entry
-fn adept_information_parse_username(val: &str) -> Option<(String, String)> {
-	let caps = ADEPT_PARSE_USERNAME_REGEX
-		.captures(val)
-		.ok_or_else(|| {
-			return crate::Error::no_captures("adept-parse \"username\"");
-		})
-		.ok()?;
-	return Some((caps[1].to_owned(), caps[2].to_owned()));
-}
+	/// Check if ADE has a device key (was installed and started at least once), without using the key
+	#[must_use]
+	pub fn has_device_key(&self) -> bool {
+		return self
+			.adept_key(DEVICE_KEY_PATH)
+			.is_some_and(|v| return v.binary("key").is_some());
+	}
 
-/// Helper function to parse the "privateLicenseKey" reg-entry
-fn adept_information_parse_key(val: &str) -> Option<String> {
-	let caps = ADEPT_PARSE_VALUE_REGEX
-		.captures(val)
-		.ok_or_else(|| {
-			return crate::Error::no_captures("adept-parse \"key\"");
-		})
-		.ok()?;
-	return Some(caps[1].to_owned());
-}
+	/// Count the activations with credentials (authorized accounts), without reading them
+	#[must_use]
+	pub fn count_activations(&self) -> usize {
+		return self.activations().count();
+	}
 
-/// Regex for parsing output from "cpuid"
-static ADEPT_SUBENTRY_FILTER_REGEX: Lazy<Regex> = Lazy::new(|| {
-	return Regex::new(
-		r"(?mis)\\\d+\s+\(Default\)\s+REG_SZ\s+((?:username)|(?:user)|(?:privateLicenseKey))\r?\n(.+?)\r?\n\r?\n",
-	)
-	.unwrap();
-});
+	/// Parse the sub-entries of a activation for a "user", "username", "privateLicenseKey" and "licenseCertificate"
+	fn adept_information_subentries(&self, activation: &RegKey) -> Result<AdeptInformationSubEntry, crate::Error> {
+		// the sub-entries look like (as "reg query" would show them):
+		// HKEY_CURRENT_USER\Software\Adobe\Adept\Activation\0000\0001
+		//     (Default)    REG_SZ    user
+		//     value    REG_SZ    urn:uuid:...
+		let mut user: Option<String> = None;
+		let mut username: Option<(String, String)> = None;
+		let mut key: Option<SecretString> = None;
+		let mut license_certificate: Option<String> = None;
+
+		// safe to unwrap, because "activations" only returns sub-keys of a existing export
+		for entry in self.adept.as_ref().unwrap().subkeys(&activation.path) {
+			let value = entry.string("value").map(ToOwned::to_owned);
+
+			match entry.string("") {
+				Some("user") => user = value,
+				Some("username") => username = entry.string("method").map(|v| return v.to_owned()).zip(value),
+				Some("privateLicenseKey") => key = value.map(SecretString::from),
+				Some("licenseCertificate") => license_certificate = value,
+				_ => (),
+			}
+		}
 
-#[derive(Debug)]
-struct AdeptInformationSubEntry {
-	/// The "urn:uuid" of the used account
-	user:     String,
-	/// The method & AdobeID that is used (method, id)
-	username: (String, String),
-	/// The raw key
-	key:      String,
-}
+		let Some(user) = user else {
+			return Err(crate::Error::no_adept_reg_key("user"));
+		};
 
-/// Helper function to parse through all the sub-entries for a "user", "username" and "privateLicenseKey"
-fn get_adept_information_subentries(path: &str) -> anyhow::Result<AdeptInformationSubEntry> {
-	// find id with "user", "username" & "privateLicenseKey"
+		let Some(username) = username else {
+			return Err(crate::Error::no_adept_reg_key("username"));
+		};
 
-	// ine reg query HKEY_CURRENT_USER\\Software\\Adobe\\Adept\\Activation\\0000 /s
-	// expected output:
-	// (many)
-	// find output:
-	// REG_SZ certificate
-
-	// let mut adept_sub_reg_cmd = new_wine_cmd();
-	// adept_sub_reg_cmd.args([
-	// 	"reg", "query", path, // "HKEY_CURRENT_USER\\Software\\Adobe\\Adept\\Activation\\0000",
-	// 	"/s",
-	// ]);
-	let mut adept_sub_reg_cmd = do_wine_like_cmd("reg");
-	adept_sub_reg_cmd.args(["query", path, "/s"]);
-
-	let adept_sub_reg_out = exec_cmd_and_wait(adept_sub_reg_cmd).context("reg query adept subentries")?;
-
-	let mut user: Option<String> = None;
-	let mut username: Option<(String, String)> = None;
-	let mut key: Option<String> = None;
-
-	for cap in ADEPT_SUBENTRY_FILTER_REGEX.captures_iter(&adept_sub_reg_out) {
-		let val_type = &cap[1];
-		let val = &cap[2];
-
-		trace!("DEBUG type {val_type}");
-
-		match val_type {
-			"user" => user = adept_information_parse_user(val),
-			"username" => username = adept_information_parse_username(val),
-			"privateLicenseKey" => key = adept_information_parse_key(val),
-			_ => (),
-		}
+		let Some(key) = key else {
+			return Err(crate::Error::no_adept_reg_key("privateLicenseKey"));
+		};
+
+		return Ok(AdeptInformationSubEntry {
+			key,
+			user,
+			username,
+			license_certificate,
+		});
 	}
 
-	let Some(user) = user else {
-		return Err(crate::Error::no_adept_reg_key("user").into());
-	};
+	/// Get the information of all activations (accounts)
+	pub fn all_adept_information(&self) -> Result<Vec<AdeptInformation>, crate::Error> {
+		// the device key is a "REG_BINARY", which is used as hex (like "reg query" shows it)
+		let device_key: SecretString = {
+			let key = self
+				.adept_key(DEVICE_KEY_PATH)
+				.and_then(|v| return v.binary("key"))
+				.ok_or_else(|| return crate::Error::no_adept_reg_key("key"))?;
 
-	let Some(username) = username else {
-		return Err(crate::Error::no_adept_reg_key("username").into());
-	};
+			let mut hex = encode_hex(key);
+			hex.make_ascii_uppercase();
 
-	let Some(key) = key else {
-		return Err(crate::Error::no_adept_reg_key("privateLicenseKey").into());
-	};
+			SecretString::from(hex)
+		};
 
-	return Ok(AdeptInformationSubEntry { key, user, username });
-}
+		if self.adept_key(ACTIVATION_KEY_PATH).is_none() {
+			return Err(crate::Error::no_adept_reg_key("Activation"));
+		}
 
-/// Regex for parsing output from "cpuid"
-static ADEPT_DEVICE_KEY_REGEX: Lazy<Regex> = Lazy::new(|| {
-	return Regex::new(r"(?mis)key\s+REG_BINARY\s+([^\r\n]+)").unwrap();
-});
-/// Regex for parsing output from "cpuid"
-static ADEPT_ACTIVATION_SUBENTRY_REGEX: Lazy<Regex> = Lazy::new(|| {
-	return Regex::new(r"(?mi)\\(\d+)\s+\(Default\)\s+REG_SZ\s+credentials").unwrap();
-});
+		let mut infos = Vec::new();
+		let mut last_err = None;
+
+		for activation in self.activations() {
+			// a single broken activation should not prevent the other activations from being used
+			let sub_info = match self.adept_information_subentries(activation) {
+				Ok(v) => v,
+				Err(err) => {
+					warn!("Skipping activation \"{}\": {err}", activation.path);
+					last_err = Some(err);
+					continue;
+				},
+			};
+
+			infos.push(AdeptInformation {
+				device_key:          device_key.clone(),
+				key:                 sub_info.key,
+				user:                sub_info.user,
+				username:            sub_info.username,
+				license_certificate: sub_info.license_certificate,
+			});
+		}
 
-#[derive(Debug)]
-pub struct AdeptInformation {
-	/// The "urn:uuid" of the used account
-	pub user:       String,
-	/// The method & AdobeID that is used (method, id)
-	pub username:   (String, String),
-	/// The raw key
-	pub key:        String,
-	/// The key of the device
-	pub device_key: String,
-}
+		if infos.is_empty() {
+			return Err(last_err.unwrap_or_else(|| return crate::Error::no_adept_reg_key("Activation")));
+		}
 
-/// Search Adept for information
-pub fn get_adept_information() -> anyhow::Result<AdeptInformation> {
-	// exec and parse output
-	// wine reg query HKEY_CURRENT_USER\\Software\\Adobe\\Adept\\Device /v key
-	// expected output:
-	// HKEY_CURRENT_USER\Software\Adobe\Adept\Device
-	// key    REG_BINARY    really_long_hex_string
-	// required output:
-	// really_long_hex_string (as binary)
-	let device_key: String;
-	{
-		let mut adept_device_key_cmd = do_wine_like_cmd("reg");
-		adept_device_key_cmd.args(["query", DEVICE_KEY_PATH, "/v", "key"]);
+		info!("Found {} activation(s)", infos.len());
 
-		let adept_device_key_out = exec_cmd_and_wait(adept_device_key_cmd).context("reg query DEVICE_KEY_PATH key")?;
-		let caps = ADEPT_DEVICE_KEY_REGEX.captures(&adept_device_key_out).ok_or_else(|| {
-			return crate::Error::no_captures("adept device key");
-		})?;
-		device_key = caps[1].to_owned();
+		return Ok(infos);
 	}
+}
 
-	// find id with "credentials"
-	// exec and parse output
-	// ine reg query HKEY_CURRENT_USER\\Software\\Adobe\\Adept\\Activation /s
-	// expected output:
-	// (many)
-	// find output:
-	// REG_SZ certificate
-
-	let mut adept_sub_reg_cmd = do_wine_like_cmd("reg");
-	adept_sub_reg_cmd.args(["query", ACTIVATION_KEY_PATH, "/s"]);
-
-	let adept_sub_reg_out = exec_cmd_and_wait(adept_sub_reg_cmd)?;
-	let caps = ADEPT_ACTIVATION_SUBENTRY_REGEX
-		.captures(&adept_sub_reg_out)
-		.ok_or_else(|| {
-			return crate::Error::no_captures("adept sub-entry list");
-		})?;
+/// Get the username from Adobe, and fallback to environment variable if not found, see [WineSnapshot::win_username] to not take a new snapshot
+pub fn get_win_username() -> Result<String, crate::Error> {
+	return WineSnapshot::take()?.win_username();
+}
 
-	let credentails_path = format!("{ACTIVATION_KEY_PATH}\\{}", &caps[1]);
+/// Search Adept for information, only returns the first activation, see [get_all_adept_information] for all
+pub fn get_adept_information() -> Result<AdeptInformation, crate::Error> {
+	let first = get_all_adept_information()?
+		.into_iter()
+		.next()
+		.expect("Expected get_all_adept_information to return at least one entry");
 
-	let sub_info = get_adept_information_subentries(&credentails_path)?;
+	return Ok(first);
+}
 
-	return Ok(AdeptInformation {
-		device_key,
-		key: sub_info.key,
-		user: sub_info.user,
-		username: sub_info.username,
-	});
+/// Search Adept for information of all activations (accounts), see [WineSnapshot::all_adept_information] to not take a new snapshot
+pub fn get_all_adept_information() -> Result<Vec<AdeptInformation>, crate::Error> {
+	return WineSnapshot::take()?.all_adept_information();
 }
 
-/// Setup the entropy bytes
-fn setup_entropy(drive_info: &DriveInfo, cpu_info: &CpuInfo, user: &str) -> anyhow::Result<Vec<u8>> {
+/// Setup the entropy bytes, which is what the device key is protected with (next to the windows user)
+pub fn setup_entropy(drive_info: &DriveInfo, cpu_info: &CpuInfo, user: &str) -> Result<Vec<u8>, crate::Error> {
+	return write_entropy(drive_info, cpu_info, user).map_err(|source| return crate::Error::Entropy { source });
+}
+
+/// Write the entropy bytes, see [setup_entropy]
+fn write_entropy(drive_info: &DriveInfo, cpu_info: &CpuInfo, user: &str) -> std::io::Result<Vec<u8>> {
 	let mut entropy = vec![];
 	entropy.write_u32::<BigEndian>(drive_info.win_system_volume_serial)?;
 	entropy.write_all(cpu_info.cpu_vendor.as_bytes())?;
@@ -487,32 +538,49 @@ fn setup_entropy(drive_info: &DriveInfo, cpu_info: &CpuInfo, user: &str) -> anyh
 		entropy.write_all(&v)?;
 	}
 
-	trace!("Entropy: {:#?}", encode_hex(&entropy));
-
 	return Ok(entropy);
 }
 
-/// Probe if the "ade-extract-winapi-bin.exe" binary exists in the current cwd
-fn probe_winapi_binary() -> anyhow::Result<()> {
-	let bin_path = std::path::Path::new("./ade-extract-winapi-bin.exe");
+/// Probe if the winapi-bin exists, see [winapi_bin_path]
+/// Always succeeds if the current runner does not actually run commands (like when replaying)
+pub(crate) fn probe_winapi_binary() -> Result<(), crate::Error> {
+	if !crate::runner::get().runs_processes() {
+		return Ok(());
+	}
+
+	let bin_path = winapi_bin_path();
 
 	if !bin_path.exists() {
-		return Err(crate::Error::NoBinary("ade-extract-winapi-bin.exe", std::env::current_dir()?).into());
+		let current_dir = std::env::current_dir()
+			.map_err(|err| return crate::Error::io("Failed to get the current directory", err))?;
+
+		return Err(crate::Error::NoBinary(current_dir.join(bin_path)));
 	}
 
 	return Ok(());
 }
 
 /// Decode the given "input" from hex into a [Vec<u8>] Array
-fn decode_hex(input: &str) -> Result<Vec<u8>, std::num::ParseIntError> {
-	return (0..input.len())
+pub(crate) fn decode_hex(input: &str) -> Result<Vec<u8>, HexError> {
+	let bytes = input.as_bytes();
+
+	if bytes.len() % 2 != 0 {
+		return Err(HexError::OddLength(bytes.len()));
+	}
+
+	// checked per byte, so that multibyte characters are a error instead of a panic
+	let digit = |i: usize| {
+		return char::from(bytes[i]).to_digit(16).ok_or(HexError::InvalidCharacter(i));
+	};
+
+	return (0..bytes.len())
 		.step_by(2)
-		.map(|i| return u8::from_str_radix(&input[i..i + 2], 16))
+		.map(|i| return Ok((digit(i)? * 16 + digit(i + 1)?) as u8))
 		.collect();
 }
 
 /// Encode the given "bytes" to a hex [String]
-fn encode_hex(bytes: &[u8]) -> String {
+pub(crate) fn encode_hex(bytes: &[u8]) -> String {
 	use std::fmt::Write;
 
 	let mut s = String::with_capacity(bytes.len() * 2);
@@ -522,11 +590,6 @@ fn encode_hex(bytes: &[u8]) -> String {
 	return s;
 }
 
-/// Regex for parsing output from "cpuid"
-static WINAPI_DECRYPTED_REGEX: Lazy<Regex> = Lazy::new(|| {
-	return Regex::new(r#"(?m)^decrypted "([^"]+)"$"#).unwrap();
-});
-
 /// Decrypt the key with the given information
 pub fn decrypt(
 	drive_info: &DriveInfo,
@@ -534,77 +597,277 @@ pub fn decrypt(
 	user: &str,
 	adept_info: &AdeptInformation,
 	print_info: bool,
-) -> anyhow::Result<Vec<u8>> {
+) -> Result<SecretBytes, crate::Error> {
+	let final_key = decrypt_all(drive_info, cpu_info, user, std::slice::from_ref(adept_info), print_info)?
+		.into_iter()
+		.next()
+		.expect("Expected decrypt_all to return one key for one adept_info");
+
+	return Ok(final_key);
+}
+
+/// Decrypt the keys of all given activations, the winapi-bin only needs to run once, because all activations share the device key
+/// The returned keys are in the same order as "adept_infos"
+pub fn decrypt_all(
+	drive_info: &DriveInfo,
+	cpu_info: &CpuInfo,
+	user: &str,
+	adept_infos: &[AdeptInformation],
+	print_info: bool,
+) -> Result<Vec<SecretBytes>, crate::Error> {
 	// decrypt "privateLicenseKey" with "keykey"
 
-	trace!(
-		"DEBUG {:#?}, {:#?}, {:#?}, {:#?}",
-		drive_info,
-		cpu_info,
-		user,
-		adept_info
-	);
+	trace!("Decrypting the keys of {} activation(s)", adept_infos.len());
+
+	let Some(first_info) = adept_infos.first() else {
+		return Ok(Vec::new());
+	};
 
-	let entropy_hex = encode_hex(&setup_entropy(drive_info, cpu_info, user)?);
-	let device_key_hex = adept_info.device_key.clone(); // the devicekey is already a hex
+	let entropy_hex = SecretString::from(encode_hex(&setup_entropy(drive_info, cpu_info, user)?));
+	let device_key_hex = &first_info.device_key; // the devicekey is already a hex
 
 	// Print info, so that the "winapi-bin" can be run separately
 	if print_info {
-		println!("Entropy (hex): \"{}\"", &entropy_hex);
-		println!("Device-Key (hex): \"{}\"", device_key_hex);
-		println!("Adept-Key (base64): \"{}\"", &adept_info.key);
+		println!("Entropy (hex): \"{}\"", entropy_hex.expose());
+		println!("Device-Key (hex): \"{}\"", device_key_hex.expose());
+		for adept_info in adept_infos {
+			println!("Adept-Key (base64): \"{}\"", adept_info.key.expose());
+		}
 	}
 
+	let decrypted_key_hex = dpapi_decrypt(entropy_hex.expose(), device_key_hex.expose())?;
+
+	let final_keys = adept_infos
+		.iter()
+		.map(|adept_info| return aes_decrypt(decrypted_key_hex.expose(), adept_info.key.expose()))
+		.collect::<Result<Vec<SecretBytes>, crate::Error>>()?;
+
+	return Ok(final_keys);
+}
+
+/// Decrypt the device key ("data_hex") with "entropy_hex" via DPAPI, by running the "ade-extract-winapi-bin.exe"
+/// Returns the decrypted data as hex
+pub fn dpapi_decrypt(entropy_hex: &str, data_hex: &str) -> Result<SecretString, crate::Error> {
 	trace!("Trying to run winapi-binary");
 
 	probe_winapi_binary()?;
 
-	let mut winapi_cmd = do_wine_like_cmd("ade-extract-winapi-bin.exe");
-	winapi_cmd.args([entropy_hex, device_key_hex]);
+	let winapi_cmd = do_wine_like_cmd(winapi_bin_path());
 
-	let winapi_out = exec_cmd_and_wait(winapi_cmd).context("ade-extract-winapi-bin.exe")?;
-	let caps = WINAPI_DECRYPTED_REGEX.captures(&winapi_out).ok_or_else(|| {
-		return crate::Error::no_captures("winapi \"decrypted\" output");
-	})?;
-	let decrypted_key_hex = &caps[1].to_owned();
+	return winapi_decrypt(winapi_cmd, entropy_hex, data_hex);
+}
 
-	let final_key = aes_decrypt(decrypted_key_hex, &adept_info.key)?;
+/// Run the given winapi-bin command (or a stand-in that speaks the same protocol) to decrypt "data_hex" with "entropy_hex"
+/// The secrets are passed via stdin, see [crate::winapi_protocol]
+/// Returns the decrypted data as hex
+pub fn winapi_decrypt(winapi_cmd: Command, entropy_hex: &str, data_hex: &str) -> Result<SecretString, crate::Error> {
+	let request = Zeroizing::new(crate::winapi_protocol::encode_request(entropy_hex, data_hex));
+
+	let cmd_line = crate::runner::command_line(&winapi_cmd);
+	let output = crate::runner::get()
+		.run(winapi_cmd, Some(&request))
+		.map_err(|err| return crate::Error::dpapi(WINAPI_BIN_NAME, err))?;
+	let winapi_out = Zeroizing::new(output.stdout);
+
+	let decrypted_hex = match crate::winapi_protocol::parse_response(&winapi_out) {
+		Ok(v) => v,
+		// the error the winapi-bin reported itself is more useful than its exit code
+		Err(err @ WinapiProtocolError::HelperError { .. }) => {
+			return Err(crate::Error::dpapi(WINAPI_BIN_NAME, err.into()));
+		},
+		Err(_) if !output.code.is_some_and(|v| return v == 0) => {
+			return Err(crate::Error::dpapi(
+				WINAPI_BIN_NAME,
+				crate::Error::CommandStatus {
+					cmd:    cmd_line,
+					code:   output.code,
+					stderr: output.stderr.trim().to_owned(),
+				},
+			));
+		},
+		Err(err) => return Err(crate::Error::dpapi(WINAPI_BIN_NAME, err.into())),
+	};
 
-	return Ok(final_key);
+	return Ok(SecretString::from(decrypted_hex));
 }
 
 /// AES decrypt the given "adept_key" with "key_hex"
-pub fn aes_decrypt(key_hex: &str, adept_key: &str) -> anyhow::Result<Vec<u8>> {
-	let decrypted_key = decode_hex(key_hex).context("Failed to decode key_hex")?;
+pub fn aes_decrypt(key_hex: &str, adept_key: &str) -> Result<SecretBytes, crate::Error> {
+	let decrypted_key =
+		Zeroizing::new(decode_hex(key_hex).map_err(|err| return crate::Error::aes("decoding the device key", err))?);
 
 	if decrypted_key.len() != 16 {
-		return Err(crate::Error::assertion_failed(format!(
-			"decrypted key is not the proper size, expected 16, got {}",
-			decrypted_key.len()
-		))
-		.into());
+		return Err(crate::Error::aes(
+			"checking the device key",
+			KeyFormatError::InvalidDeviceKeyLength(decrypted_key.len()),
+		));
 	}
 
 	trace!("Trying to decrypt AES-CBC key");
 
-	let adept_key_bytes = base64::engine::general_purpose::STANDARD
-		.decode(adept_key)
-		.context("Failed to decode base64 adept privateLicenseKey")?;
+	let adept_key_bytes = Zeroizing::new(
+		base64::engine::general_purpose::STANDARD
+			.decode(adept_key)
+			.map_err(|err| return crate::Error::aes("decoding the privateLicenseKey", err))?,
+	);
+
+	if adept_key_bytes.len() % crate::key::AES_BLOCK_SIZE != 0 {
+		return Err(crate::Error::aes(
+			"checking the privateLicenseKey",
+			KeyFormatError::InvalidCiphertextLength(adept_key_bytes.len()),
+		));
+	}
 
 	use libaes::Cipher;
 
 	let decrypted_key_slice: &[u8; 16] = &decrypted_key[0..16]
 		.try_into()
-		.context("Failed to convert Vec<u8> to sized slice")?;
+		.expect("Expected the device key length to be checked above");
 
-	let cipher = Cipher::new_128(decrypted_key_slice);
+	let mut cipher = Cipher::new_128(decrypted_key_slice);
+	// padding is removed (and validated) manually below
+	cipher.set_auto_padding(false);
 
 	let iv = vec![0; decrypted_key_slice.len()];
 
-	let mut final_key = cipher.cbc_decrypt(&iv, &adept_key_bytes);
+	let mut decrypted = Zeroizing::new(cipher.cbc_decrypt(&iv, &adept_key_bytes));
+
+	crate::key::unpad_pkcs7(&mut decrypted, crate::key::AES_BLOCK_SIZE)
+		.map_err(|err| return crate::Error::aes("removing the padding", err))?;
 
-	// remove the first 25 bytes, because it seems like those are not wanted
-	final_key.drain(0..=25);
+	let final_key = crate::key::extract_rsa_private_key(&decrypted).map_err(crate::Error::from)?;
 
 	return Ok(final_key);
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn decode_hex_valid() {
+		assert_eq!(decode_hex("00ffAb").unwrap(), [0x00, 0xff, 0xab]);
+		assert_eq!(decode_hex("").unwrap(), Vec::<u8>::new());
+	}
+
+	#[test]
+	fn decode_hex_invalid() {
+		assert_eq!(decode_hex("abc"), Err(HexError::OddLength(3)));
+		assert_eq!(decode_hex("0g"), Err(HexError::InvalidCharacter(1)));
+		// a two byte character, which would be split by slicing
+		assert_eq!(decode_hex("0\u{e9}0"), Err(HexError::InvalidCharacter(1)));
+	}
+
+	/// Get a stand-in for the winapi-bin, a "sh" script that speaks the protocol
+	#[cfg(unix)]
+	fn winapi_stand_in(script: &str) -> Command {
+		let mut cmd = Command::new("sh");
+		cmd.args(["-c", script]);
+
+		return cmd;
+	}
+
+	#[test]
+	#[cfg(unix)]
+	fn winapi_decrypt_stand_in() {
+		let _lock = crate::runner::lock_for_test();
+		crate::runner::set(std::sync::Arc::new(crate::runner::ProcessRunner));
+
+		// answers with the data as the decrypted data, after checking the request
+		let cmd = winapi_stand_in(
+			r#"read header && read entropy && read data
+			[ "$header" = "ade-extract-winapi 1" ] && [ "$entropy" = "entropy 0a0b" ] || exit 2
+			echo "ade-extract-winapi 1"
+			echo "decrypted ${data#data }""#,
+		);
+
+		assert_eq!(winapi_decrypt(cmd, "0a0b", "00112233").unwrap().expose(), "00112233");
+	}
+
+	#[test]
+	#[cfg(unix)]
+	fn winapi_decrypt_stand_in_errors() {
+		let _lock = crate::runner::lock_for_test();
+		crate::runner::set(std::sync::Arc::new(crate::runner::ProcessRunner));
+
+		// the error the helper reports is used, even though it also exits with a error
+		let err = winapi_decrypt(
+			winapi_stand_in(
+				"cat >/dev/null; echo 'ade-extract-winapi 1'; echo 'error decrypt CryptUnprotectData failed'; exit 5",
+			),
+			"0a0b",
+			"0011",
+		)
+		.unwrap_err();
+		assert!(matches!(
+			&err,
+			crate::Error::Dpapi { source, .. } if matches!(**source, crate::Error::WinapiProtocol(WinapiProtocolError::HelperError { .. }))
+		));
+		assert_eq!(err.category(), crate::error::ErrorCategory::Dpapi);
+
+		// no response, the exit status and stderr are more useful
+		let err = winapi_decrypt(
+			winapi_stand_in("cat >/dev/null; echo 'not found' >&2; exit 1"),
+			"0a0b",
+			"0011",
+		)
+		.unwrap_err();
+		assert!(matches!(
+			&err,
+			crate::Error::Dpapi { source, .. } if matches!(&**source, crate::Error::CommandStatus { code: Some(1), stderr, .. } if stderr == "not found")
+		));
+		assert_eq!(err.category(), crate::error::ErrorCategory::Environment);
+
+		// a successful exit without a result
+		let err = winapi_decrypt(
+			winapi_stand_in("cat >/dev/null; echo 'ade-extract-winapi 1'"),
+			"0a0b",
+			"0011",
+		)
+		.unwrap_err();
+		assert_eq!(err.category(), crate::error::ErrorCategory::Dpapi);
+	}
+
+	#[test]
+	fn aes_decrypt_invalid_device_key() {
+		for key_hex in ["0011", "001", "00112233445566778899aabbccddeexx", "\u{e9}"] {
+			let err = aes_decrypt(key_hex, "QUJD").unwrap_err();
+
+			assert!(matches!(err, crate::Error::Aes { .. }), "{key_hex}: {err}");
+			assert_eq!(err.category(), crate::error::ErrorCategory::Format);
+		}
+	}
+
+	#[cfg(not(windows))]
+	#[test]
+	fn wine_prefix_order() {
+		let _lock = crate::runner::lock_for_test();
+		let old_prefix = std::env::var_os("WINEPREFIX");
+
+		std::env::set_var("WINEPREFIX", "/env/prefix");
+		crate::settings::set(crate::settings::Settings {
+			prefix: Some(PathBuf::from("/configured/prefix")),
+			..Default::default()
+		});
+		let configured = wine_prefix();
+
+		crate::settings::set(crate::settings::Settings::default());
+		let from_env = wine_prefix();
+
+		// a empty "WINEPREFIX" is the same as none
+		std::env::set_var("WINEPREFIX", "");
+		let default = wine_prefix();
+
+		match old_prefix {
+			Some(v) => std::env::set_var("WINEPREFIX", v),
+			None => std::env::remove_var("WINEPREFIX"),
+		}
+
+		assert_eq!(configured, Some(PathBuf::from("/configured/prefix")));
+		assert_eq!(from_env, Some(PathBuf::from("/env/prefix")));
+		assert_eq!(
+			default,
+			std::env::var_os("HOME").map(|v| return PathBuf::from(v).join(".wine"))
+		);
+	}
+}
@@ -0,0 +1,232 @@
+//! Module for adding keys to the configuration of the calibre DeDRM plugin ("dedrm.json")
+
+use std::path::{
+	Path,
+	PathBuf,
+};
+
+use serde_json::{
+	Map,
+	Value,
+};
+use zeroize::Zeroizing;
+
+/// Key in "dedrm.json" under which the adobe keys are stored
+const ADEPT_KEYS_KEY: &str = "adeptkeys";
+
+/// Find the calibre configuration directory, like calibre itself does
+/// Order: "CALIBRE_CONFIG_DIRECTORY", then the platform default
+pub fn find_calibre_config_dir() -> Option<PathBuf> {
+	if let Some(dir) = std::env::var_os("CALIBRE_CONFIG_DIRECTORY") {
+		return Some(PathBuf::from(dir));
+	}
+
+	#[cfg(windows)]
+	{
+		return std::env::var_os("APPDATA").map(|v| return PathBuf::from(v).join("calibre"));
+	}
+
+	#[cfg(target_os = "macos")]
+	{
+		return std::env::var_os("HOME").map(|v| return PathBuf::from(v).join("Library/Preferences/calibre"));
+	}
+
+	#[cfg(not(any(windows, target_os = "macos")))]
+	{
+		if let Some(dir) = std::env::var_os("XDG_CONFIG_HOME").filter(|v| return !v.is_empty()) {
+			return Some(PathBuf::from(dir).join("calibre"));
+		}
+
+		return std::env::var_os("HOME").map(|v| return PathBuf::from(v).join(".config/calibre"));
+	}
+}
+
+/// Get the path of "dedrm.json" in the given calibre configuration directory
+pub fn dedrm_json_path(calibre_config_dir: &Path) -> PathBuf {
+	return calibre_config_dir.join("plugins").join("dedrm.json");
+}
+
+/// The outcome of adding a key to "dedrm.json"
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeDrmExport {
+	/// The key was added with the contained name
+	Added(String),
+	/// The key was already present with the contained name, nothing was changed
+	AlreadyPresent(String),
+}
+
+/// Add the given PKCS#1 DER "key" to the "adeptkeys" in the "dedrm.json" at "path" with "name"
+/// If "name" is already used for a different key, a numeric suffix is added
+/// The old file is kept as "dedrm.json.<timestamp>.bak" (see [crate::file::backup_file]) and the new file is written atomically
+pub fn add_adept_key(path: &Path, name: &str, key: &[u8]) -> Result<DeDrmExport, crate::Error> {
+	let mut config: Map<String, Value> = if path.exists() {
+		// the file contains the other keys
+		let content = Zeroizing::new(
+			std::fs::read(path)
+				.map_err(|err| return crate::Error::io(format!("Failed to read \"{}\"", path.display()), err))?,
+		);
+
+		serde_json::from_slice(&content)
+			.map_err(|err| return crate::Error::parse(format!("\"{}\"", path.display()), err))?
+	} else {
+		Map::new()
+	};
+
+	let adept_keys = config
+		.entry(ADEPT_KEYS_KEY)
+		.or_insert_with(|| return Value::Object(Map::new()))
+		.as_object_mut()
+		.ok_or_else(|| {
+			return crate::Error::parse(
+				format!("\"{}\"", path.display()),
+				format!("Expected \"{ADEPT_KEYS_KEY}\" to be a object"),
+			);
+		})?;
+
+	// the plugin stores the keys as hex encoded DER
+	let key_hex = Zeroizing::new(crate::decrypt::encode_hex(key));
+
+	if let Some((existing_name, _)) = adept_keys
+		.iter()
+		.find(|(_, v)| return v.as_str().is_some_and(|v| return v.eq_ignore_ascii_case(&key_hex)))
+	{
+		return Ok(DeDrmExport::AlreadyPresent(existing_name.clone()));
+	}
+
+	let mut final_name = name.to_owned();
+	let mut suffix = 1;
+	while adept_keys.contains_key(&final_name) {
+		final_name = format!("{name}_{suffix}");
+		suffix += 1;
+	}
+
+	adept_keys.insert(final_name.clone(), Value::String(key_hex.to_string()));
+
+	let mut content = Zeroizing::new(serde_json::to_vec_pretty(&config).map_err(|source| {
+		return crate::Error::Serialize {
+			what: "dedrm.json",
+			source,
+		};
+	})?);
+	content.push(b'\n');
+
+	if path.exists() {
+		crate::file::backup_file(path)?;
+	} else if let Some(parent) = path.parent() {
+		std::fs::create_dir_all(parent).map_err(|err| {
+			return crate::Error::io(format!("Failed to create directory \"{}\"", parent.display()), err);
+		})?;
+	}
+
+	crate::file::write_atomic(path, &content)?;
+
+	return Ok(DeDrmExport::Added(final_name));
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::file::TempDir;
+
+	/// Get the "adeptkeys" of the "dedrm.json" at "path"
+	fn adept_keys(path: &Path) -> Map<String, Value> {
+		let config: Map<String, Value> = serde_json::from_slice(&std::fs::read(path).unwrap()).unwrap();
+
+		return config[ADEPT_KEYS_KEY].as_object().unwrap().clone();
+	}
+
+	/// Get the backups of "dedrm.json" in "dir"
+	fn backups(dir: &Path) -> Vec<PathBuf> {
+		return std::fs::read_dir(dir)
+			.unwrap()
+			.map(|v| return v.unwrap().path())
+			.filter(|v| return v.to_string_lossy().ends_with(".bak"))
+			.collect();
+	}
+
+	#[test]
+	fn add_and_dedup() {
+		let dir = TempDir::new("ade-extract-key-test").unwrap();
+		let path = dedrm_json_path(dir.path());
+
+		assert_eq!(
+			add_adept_key(&path, "name", &[1, 2]).unwrap(),
+			DeDrmExport::Added(String::from("name"))
+		);
+		// a new file has no backup
+		assert!(backups(path.parent().unwrap()).is_empty());
+
+		// the same key is not added again, also under a other name
+		assert_eq!(
+			add_adept_key(&path, "other", &[1, 2]).unwrap(),
+			DeDrmExport::AlreadyPresent(String::from("name"))
+		);
+
+		// different keys with the same name get a suffix
+		assert_eq!(
+			add_adept_key(&path, "name", &[3]).unwrap(),
+			DeDrmExport::Added(String::from("name_1"))
+		);
+		assert_eq!(
+			add_adept_key(&path, "name", &[4]).unwrap(),
+			DeDrmExport::Added(String::from("name_2"))
+		);
+
+		let keys = adept_keys(&path);
+		assert_eq!(keys.len(), 3);
+		assert_eq!(keys["name"], "0102");
+		assert_eq!(keys["name_1"], "03");
+		assert_eq!(keys["name_2"], "04");
+	}
+
+	#[test]
+	fn keeps_other_keys() {
+		let dir = TempDir::new("ade-extract-key-test").unwrap();
+		let path = dir.path().join("dedrm.json");
+		let existing = serde_json::json!({
+			"adeptkeys": { "existing": "AABB" },
+			"kindlekeys": { "kindle": "something" },
+			"configured": true,
+		});
+		std::fs::write(&path, existing.to_string()).unwrap();
+
+		// the case of the existing hex does not matter
+		assert_eq!(
+			add_adept_key(&path, "new", &[0xaa, 0xbb]).unwrap(),
+			DeDrmExport::AlreadyPresent(String::from("existing"))
+		);
+		assert_eq!(
+			add_adept_key(&path, "new", &[1]).unwrap(),
+			DeDrmExport::Added(String::from("new"))
+		);
+
+		let config: Value = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+		assert_eq!(config["kindlekeys"], existing["kindlekeys"]);
+		assert_eq!(config["configured"], true);
+		assert_eq!(config["adeptkeys"]["existing"], "AABB");
+		assert_eq!(config["adeptkeys"]["new"], "01");
+
+		// the previous file was kept
+		let backups = backups(dir.path());
+		assert_eq!(backups.len(), 1);
+		assert_eq!(std::fs::read_to_string(&backups[0]).unwrap(), existing.to_string());
+	}
+
+	#[test]
+	fn malformed() {
+		let dir = TempDir::new("ade-extract-key-test").unwrap();
+		let path = dir.path().join("dedrm.json");
+
+		std::fs::write(&path, r#"{"adeptkeys": []}"#).unwrap();
+		let err = add_adept_key(&path, "name", &[1]).unwrap_err();
+		assert_eq!(err.category(), crate::error::ErrorCategory::Format);
+
+		std::fs::write(&path, "not json").unwrap();
+		let err = add_adept_key(&path, "name", &[1]).unwrap_err();
+		assert_eq!(err.category(), crate::error::ErrorCategory::Format);
+
+		// nothing was changed
+		assert_eq!(std::fs::read_to_string(&path).unwrap(), "not json");
+		assert!(backups(dir.path()).is_empty());
+	}
+}
@@ -0,0 +1,505 @@
+//! Module for diagnosing the environment the extraction depends on, step by step
+//! None of the checks decrypt anything or produce secrets
+
+use std::path::Path;
+
+use crate::decrypt;
+
+/// The outcome of a single check
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckStatus {
+	Pass,
+	Warn,
+	Fail,
+}
+
+impl std::fmt::Display for CheckStatus {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		return match self {
+			Self::Pass => write!(f, "PASS"),
+			Self::Warn => write!(f, "WARN"),
+			Self::Fail => write!(f, "FAIL"),
+		};
+	}
+}
+
+/// The result of a single check
+#[derive(Debug, Clone)]
+pub struct CheckResult {
+	/// Short name of what was checked
+	pub name:    &'static str,
+	pub status:  CheckStatus,
+	/// What was found
+	pub message: String,
+	/// How to fix it, only set for "Warn" and "Fail"
+	pub hint:    Option<&'static str>,
+}
+
+impl CheckResult {
+	fn pass(name: &'static str, message: impl Into<String>) -> Self {
+		return Self {
+			name,
+			status: CheckStatus::Pass,
+			message: message.into(),
+			hint: None,
+		};
+	}
+
+	fn warn(name: &'static str, message: impl Into<String>, hint: &'static str) -> Self {
+		return Self {
+			name,
+			status: CheckStatus::Warn,
+			message: message.into(),
+			hint: Some(hint),
+		};
+	}
+
+	fn fail(name: &'static str, message: impl Into<String>, hint: &'static str) -> Self {
+		return Self {
+			name,
+			status: CheckStatus::Fail,
+			message: message.into(),
+			hint: Some(hint),
+		};
+	}
+}
+
+/// Machine types of a PE executable, see "IMAGE_FILE_MACHINE_*"
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeMachine {
+	X86,
+	X86_64,
+	Arm64,
+	Other(u16),
+}
+
+/// Read the machine type from the header of the PE executable at "path"
+pub fn read_pe_machine(path: &Path) -> Result<PeMachine, crate::Error> {
+	let content = std::fs::read(path)
+		.map_err(|err| return crate::Error::io(format!("Failed to read \"{}\"", path.display()), err))?;
+
+	if !content.starts_with(b"MZ") || content.len() < 0x40 {
+		return Err(crate::Error::other("Not a windows executable (missing MZ header)"));
+	}
+
+	let pe_offset = u32::from_le_bytes([content[0x3c], content[0x3d], content[0x3e], content[0x3f]]) as usize;
+
+	let Some(pe_header) = content.get(pe_offset..pe_offset + 6) else {
+		return Err(crate::Error::other("Not a windows executable (PE header out of range)"));
+	};
+
+	if &pe_header[..4] != b"PE\0\0" {
+		return Err(crate::Error::other("Not a windows executable (missing PE signature)"));
+	}
+
+	return Ok(match u16::from_le_bytes([pe_header[4], pe_header[5]]) {
+		0x014c => PeMachine::X86,
+		0x8664 => PeMachine::X86_64,
+		0xaa64 => PeMachine::Arm64,
+		other => PeMachine::Other(other),
+	});
+}
+
+/// Read the architecture ("win32" or "win64") of the wine prefix at "prefix" from its "system.reg"
+#[cfg(not(windows))]
+fn read_prefix_arch(prefix: &Path) -> Result<Option<String>, crate::Error> {
+	let system_reg_path = prefix.join("system.reg");
+	let system_reg = std::fs::read_to_string(&system_reg_path)
+		.map_err(|err| return crate::Error::io(format!("Failed to read \"{}\"", system_reg_path.display()), err))?;
+
+	return Ok(system_reg
+		.lines()
+		.find_map(|v| return v.strip_prefix("#arch="))
+		.map(|v| return v.trim().to_owned()));
+}
+
+/// Run all checks in the order the extraction depends on them
+/// Stops after the first failed check, because the following checks depend on it
+pub fn run_checks() -> Vec<CheckResult> {
+	return run_checks_with_snapshot().0;
+}
+
+/// Like [run_checks], but also returns the snapshot the checks used, so that it can be reused (like with [crate::pipeline::gather_from_snapshot])
+/// The snapshot is "None" if a check before it failed or it could not be taken
+pub fn run_checks_with_snapshot() -> (Vec<CheckResult>, Option<decrypt::WineSnapshot>) {
+	let mut results = Vec::new();
+	let mut snapshot: Option<decrypt::WineSnapshot> = None;
+
+	// only needed for the winapi-bin architecture check, "None" if unknown
+	#[allow(unused_mut)]
+	let mut prefix_arch: Option<String> = None;
+
+	/// Add the result and return from "run_checks" if it failed
+	macro_rules! check {
+		($result:expr) => {
+			let result = $result;
+			let failed = result.status == CheckStatus::Fail;
+			results.push(result);
+
+			if failed {
+				return (results, snapshot);
+			}
+		};
+	}
+
+	#[cfg(not(windows))]
+	{
+		check!(match crate::wine::version() {
+			Ok(version) => match crate::wine::incompatibility(&version) {
+				Some(reason) => CheckResult::warn(
+					"wine",
+					reason,
+					"Update wine, or use a newer one with \"--wine\" (like a Proton or CrossOver installation)",
+				),
+				None => CheckResult::pass("wine", version),
+			},
+			Err(err) => CheckResult::fail(
+				"wine",
+				format!("Could not run wine: {}", crate::error::display_chain(&err)),
+				"Install wine (like \"apt install wine\") and make sure \"wine\" is in PATH, or set it with \"--wine\"",
+			),
+		});
+
+		let prefix = decrypt::wine_prefix();
+		check!(match &prefix {
+			Some(prefix) if prefix.join("system.reg").is_file() => {
+				CheckResult::pass("wine prefix", prefix.display().to_string())
+			},
+			Some(prefix) => CheckResult::fail(
+				"wine prefix",
+				format!("\"{}\" is not a initialized wine prefix", prefix.display()),
+				"Set WINEPREFIX (or \"--prefix\", or \"--bottle\" for CrossOver) to the prefix ADE is installed in",
+			),
+			None => CheckResult::fail(
+				"wine prefix",
+				"Could not determine the wine prefix",
+				"Set WINEPREFIX (or \"--prefix\", or \"--bottle\" for CrossOver) to the prefix ADE is installed in",
+			),
+		});
+
+		// safe to unwrap, because the check above would have failed otherwise
+		let prefix = prefix.unwrap();
+		check!(match read_prefix_arch(&prefix) {
+			Ok(Some(arch)) => {
+				prefix_arch = Some(arch.clone());
+				CheckResult::pass("prefix architecture", arch)
+			},
+			Ok(None) => CheckResult::warn(
+				"prefix architecture",
+				"Could not find the architecture in \"system.reg\"",
+				"Make sure the prefix was created by a recent wine version",
+			),
+			Err(err) => CheckResult::warn(
+				"prefix architecture",
+				format!("Could not read \"system.reg\": {}", crate::error::display_chain(&err)),
+				"Make sure the prefix is readable by the current user",
+			),
+		});
+	}
+
+	check!(match decrypt::probe_winapi_binary() {
+		Ok(()) => CheckResult::pass(
+			"winapi-bin",
+			format!("found \"{}\"", decrypt::winapi_bin_path().display()),
+		),
+		Err(err) => CheckResult::fail(
+			"winapi-bin",
+			crate::error::display_chain(&err),
+			"Place \"ade-extract-winapi-bin.exe\" (from the release binaries) in the current directory or use \"--winapi-bin\"",
+		),
+	});
+
+	check!(match read_pe_machine(&decrypt::winapi_bin_path()) {
+		Ok(PeMachine::X86_64) if prefix_arch.as_deref() == Some("win32") => CheckResult::fail(
+			"winapi-bin architecture",
+			"winapi-bin is 64-bit, but the wine prefix is 32-bit",
+			"Use a 64-bit prefix (WINEARCH=win64) or a 32-bit (i686) build of the winapi-bin",
+		),
+		Ok(machine @ (PeMachine::X86 | PeMachine::X86_64)) => {
+			CheckResult::pass("winapi-bin architecture", format!("{machine:?}"))
+		},
+		Ok(machine) => CheckResult::warn(
+			"winapi-bin architecture",
+			format!("Unexpected architecture {machine:?}"),
+			"Use the x86_64 build of the winapi-bin from the release binaries",
+		),
+		Err(err) => CheckResult::fail(
+			"winapi-bin architecture",
+			format!("Could not read the winapi-bin: {}", crate::error::display_chain(&err)),
+			"Replace \"ade-extract-winapi-bin.exe\" with a fresh copy from the release binaries",
+		),
+	});
+
+	// all following checks use a single snapshot, like the extraction
+	let taken = match decrypt::WineSnapshot::take() {
+		Ok(v) => v,
+		Err(err) => {
+			results.push(CheckResult::fail(
+				"windows commands",
+				format!(
+					"Could not run the windows commands: {}",
+					crate::error::display_chain(&err)
+				),
+				"Make sure the prefix is fully initialized (like by running \"wineboot\")",
+			));
+
+			return (results, None);
+		},
+	};
+	let taken = snapshot.insert(taken);
+
+	check!(match taken.drive_info() {
+		Ok(drive_info) => CheckResult::pass(
+			"windows commands",
+			format!("system drive is \"{}\"", drive_info.win_system_drive),
+		),
+		Err(err) => CheckResult::fail(
+			"windows commands",
+			format!(
+				"Could not get the system drive information: {}",
+				crate::error::display_chain(&err)
+			),
+			"Make sure the prefix is fully initialized (like by running \"wineboot\")",
+		),
+	});
+
+	check!(if taken.has_device_key() {
+		CheckResult::pass("ADE installed", "found the device key")
+	} else {
+		CheckResult::fail(
+			"ADE installed",
+			"Could not find the ADE device key in the registry",
+			"Install Adobe Digital Editions (2.5) in this prefix and start it at least once",
+		)
+	});
+
+	check!(match taken.count_activations() {
+		0 => CheckResult::fail(
+			"ADE authorized",
+			"ADE is not authorized with any account",
+			"Authorize ADE with a Adobe ID (\"Help\" -> \"Authorize Computer\")",
+		),
+		count => CheckResult::pass("ADE authorized", format!("{count} account(s)")),
+	});
+
+	check!(match taken.win_username() {
+		Ok(username) => CheckResult::pass("windows username", username),
+		Err(err) => CheckResult::fail(
+			"windows username",
+			format!(
+				"Could not get the windows username: {}",
+				crate::error::display_chain(&err)
+			),
+			"Make sure the prefix is fully initialized (like by running \"wineboot\")",
+		),
+	});
+
+	return (results, snapshot);
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::Arc;
+
+	use super::*;
+	use crate::{
+		file::TempDir,
+		runner::FakeRunner,
+		settings::Settings,
+		test_util::{
+			success,
+			use_runner,
+			ADEPT_EXPORT,
+			SNAPSHOT_OUTPUT,
+		},
+	};
+
+	/// Get the bytes of a minimal PE executable for "machine"
+	fn pe_file(machine: u16) -> Vec<u8> {
+		let mut content = vec![0; 0x40];
+		content[..2].copy_from_slice(b"MZ");
+		content[0x3c] = 0x40;
+		content.extend_from_slice(b"PE\0\0");
+		content.extend_from_slice(&machine.to_le_bytes());
+
+		return content;
+	}
+
+	/// Write "content" to "name" in "dir" and return the path
+	fn write(dir: &TempDir, name: &str, content: &[u8]) -> std::path::PathBuf {
+		let path = dir.path().join(name);
+		std::fs::write(&path, content).unwrap();
+
+		return path;
+	}
+
+	#[test]
+	fn pe_machine() {
+		let dir = TempDir::new("ade-extract-key-test").unwrap();
+
+		for (machine, expected) in [
+			(0x014c, PeMachine::X86),
+			(0x8664, PeMachine::X86_64),
+			(0xaa64, PeMachine::Arm64),
+			(0x01c4, PeMachine::Other(0x01c4)),
+		] {
+			let path = write(&dir, "bin.exe", &pe_file(machine));
+			assert_eq!(read_pe_machine(&path).unwrap(), expected);
+		}
+	}
+
+	#[test]
+	fn pe_machine_invalid() {
+		let dir = TempDir::new("ade-extract-key-test").unwrap();
+
+		// no MZ header
+		let mut content = pe_file(0x8664);
+		content[..2].copy_from_slice(b"ZM");
+		assert!(read_pe_machine(&write(&dir, "no_mz.exe", &content)).is_err());
+
+		// too short for the MZ header
+		assert!(read_pe_machine(&write(&dir, "short.exe", b"MZ")).is_err());
+
+		// PE header offset after the end of the file
+		let mut content = pe_file(0x8664);
+		content[0x3c] = 0xf0;
+		assert!(read_pe_machine(&write(&dir, "offset.exe", &content)).is_err());
+
+		// no PE signature
+		let mut content = pe_file(0x8664);
+		content[0x40..0x44].copy_from_slice(b"NE\0\0");
+		assert!(read_pe_machine(&write(&dir, "no_pe.exe", &content)).is_err());
+
+		// does not exist
+		assert!(read_pe_machine(&dir.path().join("missing.exe")).is_err());
+	}
+
+	#[cfg(not(windows))]
+	#[test]
+	fn prefix_arch() {
+		let dir = TempDir::new("ade-extract-key-test").unwrap();
+
+		write(
+			&dir,
+			"system.reg",
+			b"WINE REGISTRY Version 2\n;; All keys relative to \\\\Machine\n\n#arch=win64\n\n[Software]\n",
+		);
+		assert_eq!(read_prefix_arch(dir.path()).unwrap().as_deref(), Some("win64"));
+
+		write(&dir, "system.reg", b"WINE REGISTRY Version 2\n\n[Software]\n");
+		assert_eq!(read_prefix_arch(dir.path()).unwrap(), None);
+
+		assert!(read_prefix_arch(&dir.path().join("missing")).is_err());
+	}
+
+	/// Set the settings to a prefix of "arch" and a x86_64 winapi-bin in "dir"
+	fn use_prefix(dir: &TempDir, arch: &str) {
+		write(
+			dir,
+			"system.reg",
+			format!("WINE REGISTRY Version 2\n#arch={arch}\n").as_bytes(),
+		);
+
+		crate::settings::set(Settings {
+			prefix: Some(dir.path().to_path_buf()),
+			winapi_bin: Some(write(dir, "ade-extract-winapi-bin.exe", &pe_file(0x8664))),
+			..Default::default()
+		});
+	}
+
+	/// Reset the runner and the settings changed by a test
+	fn reset() {
+		crate::runner::set(Arc::new(crate::runner::ProcessRunner));
+		crate::settings::set(Settings::default());
+	}
+
+	/// Get the names and statuses of "results"
+	fn statuses(results: &[CheckResult]) -> Vec<(&'static str, CheckStatus)> {
+		return results.iter().map(|v| return (v.name, v.status)).collect();
+	}
+
+	#[cfg(not(windows))]
+	#[test]
+	fn checks_pass() {
+		let _lock = crate::runner::lock_for_test();
+		let dir = TempDir::new("ade-extract-key-test").unwrap();
+		use_prefix(&dir, "win64");
+		use_runner(
+			FakeRunner::new()
+				.respond("--version", success("wine-9.0\n"))
+				.respond_with_file("reg export", success(SNAPSHOT_OUTPUT), ADEPT_EXPORT),
+		);
+
+		let (results, snapshot) = run_checks_with_snapshot();
+		reset();
+
+		assert_eq!(
+			statuses(&results),
+			[
+				("wine", CheckStatus::Pass),
+				("wine prefix", CheckStatus::Pass),
+				("prefix architecture", CheckStatus::Pass),
+				("winapi-bin", CheckStatus::Pass),
+				("winapi-bin architecture", CheckStatus::Pass),
+				("windows commands", CheckStatus::Pass),
+				("ADE installed", CheckStatus::Pass),
+				("ADE authorized", CheckStatus::Pass),
+				("windows username", CheckStatus::Pass),
+			]
+		);
+		assert_eq!(results[0].message, "wine-9.0");
+		assert_eq!(results[7].message, "1 account(s)");
+		assert!(snapshot.is_some());
+	}
+
+	#[cfg(not(windows))]
+	#[test]
+	fn checks_fail() {
+		let _lock = crate::runner::lock_for_test();
+		let dir = TempDir::new("ade-extract-key-test").unwrap();
+
+		// a 64-bit winapi-bin cannot run in a 32-bit prefix, nothing after it is checked
+		use_prefix(&dir, "win32");
+		use_runner(FakeRunner::new().respond("--version", success("wine-9.0\n")));
+		let (results, snapshot) = run_checks_with_snapshot();
+		reset();
+
+		assert_eq!(results.len(), 5);
+		assert_eq!(results[4].name, "winapi-bin architecture");
+		assert_eq!(results[4].status, CheckStatus::Fail);
+		assert!(results[4].hint.is_some());
+		assert!(snapshot.is_none());
+
+		// wine is too old and the snapshot command fails
+		use_prefix(&dir, "win64");
+		use_runner(FakeRunner::new().respond("--version", success("wine-4.0\n")));
+		let (results, snapshot) = run_checks_with_snapshot();
+		reset();
+
+		assert_eq!(results[0].status, CheckStatus::Warn);
+		assert_eq!(
+			results.last().map(|v| return (v.name, v.status)),
+			Some(("windows commands", CheckStatus::Fail))
+		);
+		assert!(snapshot.is_none());
+
+		// ADE was never started, so the snapshot is still returned for inspection
+		use_prefix(&dir, "win64");
+		use_runner(FakeRunner::new().respond("--version", success("wine-9.0\n")).respond(
+			"reg export",
+			crate::runner::CommandOutput {
+				code:   Some(1),
+				stdout: SNAPSHOT_OUTPUT.to_owned(),
+				stderr: String::new(),
+			},
+		));
+		let (results, snapshot) = run_checks_with_snapshot();
+		reset();
+
+		assert_eq!(
+			results.last().map(|v| return (v.name, v.status)),
+			Some(("ADE installed", CheckStatus::Fail))
+		);
+		assert!(snapshot.is_some());
+	}
+}
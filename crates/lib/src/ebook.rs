@@ -0,0 +1,303 @@
+//! Module for reading the ADEPT license of ebooks (EPUB & PDF), to find out which account they belong to
+//! Only the license metadata is read, the content is never decrypted
+
+use std::{
+	io::Read,
+	path::{
+		Path,
+		PathBuf,
+	},
+};
+
+use base64::Engine;
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+use crate::error::EbookError;
+
+/// Path of the ADEPT license in a EPUB
+const EPUB_RIGHTS_PATH: &str = "META-INF/rights.xml";
+
+/// Name of the entry in the PDF encryption dictionary (EBX handler) that contains the ADEPT license
+const PDF_LICENSE_NAME: &[u8] = b"/ADEPT_LICENSE";
+
+/// The types of ebooks a ADEPT license can be read from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EbookType {
+	Epub,
+	Pdf,
+}
+
+impl EbookType {
+	/// Get the type from the file extension of "path"
+	#[must_use]
+	pub fn from_path(path: &Path) -> Option<Self> {
+		let ext = path.extension()?.to_string_lossy().to_ascii_lowercase();
+
+		return match ext.as_str() {
+			"epub" => Some(Self::Epub),
+			"pdf" => Some(Self::Pdf),
+			_ => None,
+		};
+	}
+}
+
+impl std::fmt::Display for EbookType {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		return match self {
+			Self::Epub => write!(f, "EPUB"),
+			Self::Pdf => write!(f, "PDF"),
+		};
+	}
+}
+
+/// The information from a ADEPT license
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AdeptLicense {
+	/// The "urn:uuid" of the account the ebook is licensed to, same as [crate::decrypt::AdeptInformation::user]
+	pub user:     String,
+	/// The "urn:uuid" of the ebook itself, if present
+	pub resource: Option<String>,
+}
+
+/// Read the ADEPT license of the ebook at "path"
+/// Returns "None" if the ebook is not ADEPT protected
+pub fn read_license(path: &Path) -> Result<Option<AdeptLicense>, EbookError> {
+	return match EbookType::from_path(path) {
+		Some(EbookType::Epub) => read_epub_license(path),
+		Some(EbookType::Pdf) => read_pdf_license(path),
+		None => Err(EbookError::UnknownType(path.to_path_buf())),
+	};
+}
+
+/// Read the ADEPT license from "META-INF/rights.xml" of the EPUB at "path"
+fn read_epub_license(path: &Path) -> Result<Option<AdeptLicense>, EbookError> {
+	let zip_err = |source| {
+		return EbookError::Zip {
+			path: path.to_path_buf(),
+			source,
+		};
+	};
+
+	let file = std::fs::File::open(path).map_err(|err| return EbookError::io(path, err))?;
+	let mut archive = zip::ZipArchive::new(file).map_err(zip_err)?;
+
+	let mut rights_file = match archive.by_name(EPUB_RIGHTS_PATH) {
+		Ok(v) => v,
+		Err(zip::result::ZipError::FileNotFound) => return Ok(None),
+		Err(err) => return Err(zip_err(err)),
+	};
+
+	let mut rights = String::new();
+	rights_file
+		.read_to_string(&mut rights)
+		.map_err(|err| return EbookError::io(path, err))?;
+
+	return parse_rights_xml(&rights).map(Some);
+}
+
+/// Read the "ADEPT_LICENSE" from the EBX handler encryption dictionary of the PDF at "path"
+fn read_pdf_license(path: &Path) -> Result<Option<AdeptLicense>, EbookError> {
+	let content = std::fs::read(path).map_err(|err| return EbookError::io(path, err))?;
+
+	// the encryption dictionary is never compressed (it cannot be in a object stream),
+	// so it can be searched for directly, the last one is the one of the latest revision
+	let Some(pos) = content
+		.windows(PDF_LICENSE_NAME.len())
+		.rposition(|v| return v == PDF_LICENSE_NAME)
+	else {
+		return Ok(None);
+	};
+
+	let license_b64 = parse_pdf_string(&content[pos + PDF_LICENSE_NAME.len()..])?;
+
+	// the license is base64 of raw deflate compressed XML
+	let license_b64: Vec<u8> = license_b64
+		.into_iter()
+		.filter(|v| return !v.is_ascii_whitespace())
+		.collect();
+	let compressed = base64::engine::general_purpose::STANDARD
+		.decode(license_b64)
+		.map_err(EbookError::InvalidLicenseEncoding)?;
+
+	let mut rights = String::new();
+	flate2::read::DeflateDecoder::new(compressed.as_slice())
+		.read_to_string(&mut rights)
+		.map_err(EbookError::InvalidLicenseCompression)?;
+
+	return parse_rights_xml(&rights).map(Some);
+}
+
+/// Parse a PDF string object (literal "(...)" or hex "<...>") at the start of "input" (after whitespace)
+fn parse_pdf_string(input: &[u8]) -> Result<Vec<u8>, EbookError> {
+	let start = input
+		.iter()
+		.position(|v| return !v.is_ascii_whitespace())
+		.ok_or(EbookError::MalformedPdfLicense("missing value"))?;
+	let input = &input[start..];
+
+	match input.first() {
+		Some(b'(') => (),
+		Some(b'<') => {
+			let end = input
+				.iter()
+				.position(|v| return *v == b'>')
+				.ok_or(EbookError::MalformedPdfLicense("unterminated hex string"))?;
+			let mut hex: String = input[1..end]
+				.iter()
+				.filter(|v| return !v.is_ascii_whitespace())
+				.map(|v| return char::from(*v))
+				.collect();
+
+			// a missing last digit is "0", as the PDF specification says
+			if hex.len() % 2 != 0 {
+				hex.push('0');
+			}
+
+			return crate::decrypt::decode_hex(&hex)
+				.map_err(|_| return EbookError::MalformedPdfLicense("invalid hex string"));
+		},
+		_ => return Err(EbookError::MalformedPdfLicense("value is not a string")),
+	}
+
+	let mut out = Vec::new();
+	let mut depth = 0usize;
+	let mut iter = input.iter().copied().peekable();
+
+	while let Some(byte) = iter.next() {
+		match byte {
+			b'(' => {
+				depth += 1;
+				if depth == 1 {
+					continue;
+				}
+			},
+			b')' => {
+				depth -= 1;
+				if depth == 0 {
+					return Ok(out);
+				}
+			},
+			b'\\' => {
+				let escaped = iter
+					.next()
+					.ok_or(EbookError::MalformedPdfLicense("unterminated literal string"))?;
+
+				match escaped {
+					b'n' => out.push(b'\n'),
+					b'r' => out.push(b'\r'),
+					b't' => out.push(b'\t'),
+					b'b' => out.push(0x08),
+					b'f' => out.push(0x0c),
+					// line continuation
+					b'\r' | b'\n' => (),
+					b'0'..=b'7' => {
+						let mut value = u32::from(escaped - b'0');
+						for _ in 0..2 {
+							match iter.peek() {
+								Some(v @ b'0'..=b'7') => {
+									value = value * 8 + u32::from(*v - b'0');
+									iter.next();
+								},
+								_ => break,
+							}
+						}
+						// high-order overflow is ignored, as the PDF specification says
+						out.push((value & 0xff) as u8);
+					},
+					other => out.push(other),
+				}
+
+				continue;
+			},
+			_ => (),
+		}
+
+		out.push(byte);
+	}
+
+	return Err(EbookError::MalformedPdfLicense("unterminated literal string"));
+}
+
+/// Regex to get the user from the license, the namespace prefix is usually "adept:"
+static ADEPT_USER_REGEX: Lazy<Regex> = Lazy::new(|| {
+	return Regex::new(r"<(?:[\w.-]+:)?user>\s*([^<\s]+)\s*</(?:[\w.-]+:)?user>").unwrap();
+});
+
+/// Regex to get the resource (the ebook's id) from the license
+static ADEPT_RESOURCE_REGEX: Lazy<Regex> = Lazy::new(|| {
+	return Regex::new(r"<(?:[\w.-]+:)?resource>\s*([^<\s]+)\s*</(?:[\w.-]+:)?resource>").unwrap();
+});
+
+/// Parse the ADEPT license XML ("rights.xml")
+pub fn parse_rights_xml(xml: &str) -> Result<AdeptLicense, EbookError> {
+	let user = ADEPT_USER_REGEX
+		.captures(xml)
+		.map(|v| return v[1].to_owned())
+		.ok_or(EbookError::MissingUser)?;
+	let resource = ADEPT_RESOURCE_REGEX.captures(xml).map(|v| return v[1].to_owned());
+
+	return Ok(AdeptLicense { user, resource });
+}
+
+/// Find all ebooks (by extension) in "dir" recursively, sorted by path
+pub fn find_ebooks(dir: &Path) -> Result<Vec<PathBuf>, EbookError> {
+	let mut found = Vec::new();
+	let mut dirs = vec![dir.to_path_buf()];
+
+	while let Some(current) = dirs.pop() {
+		for entry in std::fs::read_dir(&current).map_err(|err| return EbookError::io(&current, err))? {
+			let entry = entry.map_err(|err| return EbookError::io(&current, err))?;
+			let file_type = entry
+				.file_type()
+				.map_err(|err| return EbookError::io(&entry.path(), err))?;
+			let path = entry.path();
+
+			if file_type.is_dir() {
+				dirs.push(path);
+			} else if EbookType::from_path(&path).is_some() {
+				found.push(path);
+			}
+		}
+	}
+
+	found.sort();
+
+	return Ok(found);
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn pdf_hex_string() {
+		assert_eq!(parse_pdf_string(b" <48 65\n6c6C6f>").unwrap(), b"Hello");
+	}
+
+	#[test]
+	fn pdf_hex_string_odd_length() {
+		assert_eq!(parse_pdf_string(b"<901FA>").unwrap(), [0x90, 0x1f, 0xa0]);
+	}
+
+	#[test]
+	fn pdf_hex_string_invalid() {
+		assert!(matches!(
+			parse_pdf_string("<4\u{e9}>".as_bytes()),
+			Err(EbookError::MalformedPdfLicense(_))
+		));
+		assert!(matches!(
+			parse_pdf_string(b"<4g>"),
+			Err(EbookError::MalformedPdfLicense(_))
+		));
+		assert!(matches!(
+			parse_pdf_string(b"<48"),
+			Err(EbookError::MalformedPdfLicense(_))
+		));
+	}
+
+	#[test]
+	fn pdf_literal_string() {
+		assert_eq!(parse_pdf_string(br"(a\(b\) (c) \101)").unwrap(), b"a(b) (c) A");
+	}
+}
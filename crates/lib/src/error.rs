@@ -1,14 +1,20 @@
-use std::path::PathBuf;
+use std::{
+	path::PathBuf,
+	time::Duration,
+};
 
 use thiserror::Error;
 
+/// A boxed error of any type, for sources that can come from different libraries
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
 #[derive(Error, Debug)]
 pub enum ExtractorError {
 	#[error("Failed to get regex captures for {0}")]
 	NoCaptures(&'static str),
 
-	#[error("Could not find binary \"{0}\" in \"{1}\"")]
-	NoBinary(&'static str, PathBuf),
+	#[error("Could not find binary \"{}\"", .0.display())]
+	NoBinary(PathBuf),
 
 	#[error("Could not find {0} Registry key \"{1}\"")]
 	RegistryKeyNotFound(&'static str, &'static str),
@@ -16,10 +22,264 @@ pub enum ExtractorError {
 	#[error("{0}")]
 	AssertionFailed(String),
 
+	#[error("{0}")]
+	Usage(String),
+
+	#[error("{0}")]
+	Environment(String),
+
+	#[error("Failed to run \"{cmd}\"")]
+	CommandFailed {
+		cmd:    String,
+		#[source]
+		source: std::io::Error,
+	},
+
+	#[error("\"{cmd}\" did not finish within {} seconds (it may wait for a dialog)", .timeout.as_secs())]
+	CommandTimeout { cmd: String, timeout: Duration },
+
+	#[error("\"{cmd}\" exited with {}{}", describe_code(*.code), describe_stderr(.stderr))]
+	CommandStatus {
+		cmd:    String,
+		code:   Option<i32>,
+		/// The last lines of stderr
+		stderr: String,
+	},
+
+	#[error("Failed to read \"{}\"", .path.display())]
+	OutputFile {
+		path:   PathBuf,
+		#[source]
+		source: std::io::Error,
+	},
+
+	#[error("Failed to parse the registry export: {0}")]
+	RegistryExport(String),
+
+	#[error("{context}")]
+	Io {
+		context: String,
+		#[source]
+		source:  std::io::Error,
+	},
+
+	#[error("Failed to parse {what}")]
+	Parse {
+		what:   String,
+		#[source]
+		source: BoxError,
+	},
+
+	#[error("Failed to serialize {what}")]
+	Serialize {
+		what:   &'static str,
+		#[source]
+		source: serde_json::Error,
+	},
+
+	#[error("Failed to compute the entropy")]
+	Entropy {
+		#[source]
+		source: std::io::Error,
+	},
+
+	#[error("DPAPI decryption with {context} failed")]
+	Dpapi {
+		context: String,
+		#[source]
+		source:  Box<ExtractorError>,
+	},
+
+	#[error("AES decryption failed while {context}")]
+	Aes {
+		context: &'static str,
+		#[source]
+		source:  BoxError,
+	},
+
+	#[error("Failed to gather {} inputs:{}", .0.len(), describe_failures(.0))]
+	Gather(Vec<(&'static str, ExtractorError)>),
+
+	#[error(transparent)]
+	WinapiProtocol(#[from] WinapiProtocolError),
+
+	#[error(transparent)]
+	KeyFormat(#[from] KeyFormatError),
+
+	#[error(transparent)]
+	Ebook(#[from] EbookError),
+
 	#[error("{0}")]
 	Other(String),
 }
 
+/// Describe a exit code for [ExtractorError::CommandStatus]
+fn describe_code(code: Option<i32>) -> String {
+	return code.map_or_else(|| return String::from("a signal"), |v| return format!("code {v}"));
+}
+
+/// Describe the stderr for [ExtractorError::CommandStatus], if there is any
+fn describe_stderr(stderr: &str) -> String {
+	if stderr.is_empty() {
+		return String::new();
+	}
+
+	return format!(", stderr:\n{stderr}");
+}
+
+/// Get the message of "err" followed by the messages of all its sources, like "Failed to read \"file\": No such file or directory"
+/// For where a error is shown on a single line (like logs), instead of with its causes
+#[must_use]
+pub fn display_chain(err: &(dyn std::error::Error + 'static)) -> String {
+	return std::iter::successors(Some(err), |v| return v.source())
+		.map(ToString::to_string)
+		.collect::<Vec<_>>()
+		.join(": ");
+}
+
+/// Describe the failed inputs for [ExtractorError::Gather], one per line
+fn describe_failures(failures: &[(&'static str, ExtractorError)]) -> String {
+	return failures
+		.iter()
+		.map(|(input, err)| return format!("\n  {input}: {}", display_chain(err)))
+		.collect();
+}
+
+/// Errors for when a hex string could not be decoded
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum HexError {
+	#[error("Hex string has odd length {0}")]
+	OddLength(usize),
+
+	#[error("Hex string has a invalid character at byte {0}")]
+	InvalidCharacter(usize),
+}
+
+/// Errors for when the response of "ade-extract-winapi-bin.exe" could not be used
+#[derive(Error, Debug)]
+pub enum WinapiProtocolError {
+	#[error("winapi-bin did not output anything")]
+	EmptyResponse,
+
+	#[error("winapi-bin output did not contain the protocol header")]
+	MissingHeader,
+
+	#[error("winapi-bin speaks unsupported protocol version \"{0}\"")]
+	UnsupportedVersion(String),
+
+	#[error("winapi-bin reported error \"{kind}\": {msg}")]
+	HelperError { kind: String, msg: String },
+
+	#[error("winapi-bin output contained unexpected line \"{0}\"")]
+	UnexpectedLine(String),
+
+	#[error("winapi-bin output did not contain a result")]
+	MissingResult,
+
+	#[error("winapi-bin output contained a result that is not valid hex")]
+	InvalidHex,
+}
+
+impl WinapiProtocolError {
+	/// Get the category of this error, which decides the exit code
+	#[must_use]
+	pub fn category(&self) -> ErrorCategory {
+		return match self {
+			// the winapi-bin did not run properly or is a incompatible version
+			Self::EmptyResponse | Self::MissingHeader | Self::UnsupportedVersion(_) => ErrorCategory::Environment,
+			Self::HelperError { kind, .. } if kind == "unsupported" => ErrorCategory::Environment,
+			Self::HelperError { .. } | Self::UnexpectedLine(_) | Self::MissingResult | Self::InvalidHex => {
+				ErrorCategory::Dpapi
+			},
+		};
+	}
+}
+
+/// Categories of errors, each with a stable exit code that wrappers can branch on
+/// Both binaries use the same exit codes, keep this in sync with the winapi-bin and the README
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCategory {
+	/// Anything not covered by the other categories
+	Other,
+	/// Invalid arguments, configuration or input
+	Usage,
+	/// wine, the prefix or the winapi-bin are missing or not working
+	Environment,
+	/// ADE is not installed / authorized, or its registry keys are missing
+	Registry,
+	/// Computing the entropy or decrypting the device key with DPAPI failed
+	Dpapi,
+	/// Decrypting (AES), parsing or encoding the key failed, or it does not match, or a file or output is malformed
+	Format,
+	/// Reading or writing files failed
+	Io,
+}
+
+impl ErrorCategory {
+	/// Get the exit code of this category, "0" is never used because it means success
+	#[must_use]
+	pub fn exit_code(self) -> u8 {
+		return match self {
+			Self::Other => 1,
+			// same as clap uses for invalid arguments
+			Self::Usage => 2,
+			Self::Environment => 3,
+			Self::Registry => 4,
+			Self::Dpapi => 5,
+			Self::Format => 6,
+			Self::Io => 7,
+		};
+	}
+
+	/// Get the name of this category, as used in JSON output
+	#[must_use]
+	pub fn name(self) -> &'static str {
+		return match self {
+			Self::Other => "other",
+			Self::Usage => "usage",
+			Self::Environment => "environment",
+			Self::Registry => "registry",
+			Self::Dpapi => "dpapi",
+			Self::Format => "format",
+			Self::Io => "io",
+		};
+	}
+
+	/// Get the category of "err", from the first error in its chain (itself and its sources) that has a category
+	#[must_use]
+	pub fn of(err: &(dyn std::error::Error + 'static)) -> Self {
+		for cause in std::iter::successors(Some(err), |v| return v.source()) {
+			if let Some(v) = cause.downcast_ref::<ExtractorError>() {
+				return v.category();
+			}
+
+			if let Some(v) = cause.downcast_ref::<WinapiProtocolError>() {
+				return v.category();
+			}
+
+			if cause.is::<KeyFormatError>() {
+				return Self::Format;
+			}
+
+			if let Some(v) = cause.downcast_ref::<EbookError>() {
+				return v.category();
+			}
+
+			if cause.is::<std::io::Error>() {
+				return Self::Io;
+			}
+		}
+
+		return Self::Other;
+	}
+}
+
+impl std::fmt::Display for ErrorCategory {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		return write!(f, "{}", self.name());
+	}
+}
+
 impl ExtractorError {
 	/// Error for when regex captures fails
 	pub fn no_captures(msg: &'static str) -> Self {
@@ -36,6 +296,103 @@ impl ExtractorError {
 		return Self::AssertionFailed(msg);
 	}
 
+	/// Error for when the arguments, configuration or other input given by the user cannot be used
+	pub fn usage<M>(msg: M) -> Self
+	where
+		M: Into<String>,
+	{
+		return Self::Usage(msg.into());
+	}
+
+	/// Error for when the environment (wine, prefix, winapi-bin) is not usable
+	pub fn environment<M>(msg: M) -> Self
+	where
+		M: Into<String>,
+	{
+		return Self::Environment(msg.into());
+	}
+
+	/// Error for when a external command could not be spawned or communicated with
+	pub fn command_failed(cmd: &std::process::Command, source: std::io::Error) -> Self {
+		return Self::CommandFailed {
+			cmd: crate::runner::command_line(cmd),
+			source,
+		};
+	}
+
+	/// Error for when a file or directory operation failed, "context" says which one (like "Failed to read \"file\"")
+	pub fn io<M>(context: M, source: std::io::Error) -> Self
+	where
+		M: Into<String>,
+	{
+		return Self::Io {
+			context: context.into(),
+			source,
+		};
+	}
+
+	/// Error for when "what" (like a state file) could not be parsed
+	pub fn parse<M, E>(what: M, source: E) -> Self
+	where
+		M: Into<String>,
+		E: Into<BoxError>,
+	{
+		return Self::Parse {
+			what:   what.into(),
+			source: source.into(),
+		};
+	}
+
+	/// Error for when decrypting the device key with DPAPI via "context" (like the winapi-bin) failed
+	pub fn dpapi<M>(context: M, source: Self) -> Self
+	where
+		M: Into<String>,
+	{
+		return Self::Dpapi {
+			context: context.into(),
+			source:  Box::new(source),
+		};
+	}
+
+	/// Error for when decrypting the "privateLicenseKey" with AES failed while "context" (like "decoding the device key")
+	pub fn aes<E>(context: &'static str, source: E) -> Self
+	where
+		E: Into<BoxError>,
+	{
+		return Self::Aes {
+			context,
+			source: source.into(),
+		};
+	}
+
+	/// Get the category of this error, which decides the exit code
+	#[must_use]
+	pub fn category(&self) -> ErrorCategory {
+		return match self {
+			Self::NoBinary(_)
+			| Self::NoCaptures(_)
+			| Self::Environment(_)
+			| Self::CommandFailed { .. }
+			| Self::CommandTimeout { .. }
+			| Self::CommandStatus { .. } => ErrorCategory::Environment,
+			Self::RegistryKeyNotFound(..) | Self::RegistryExport(_) => ErrorCategory::Registry,
+			Self::OutputFile { .. } | Self::Io { .. } => ErrorCategory::Io,
+			Self::Entropy { .. } => ErrorCategory::Dpapi,
+			// the winapi-bin not running is a environment problem, not a failed decryption
+			Self::Dpapi { source, .. } => source.category(),
+			Self::Aes { .. } => ErrorCategory::Format,
+			// all failures are reported, but only the first can decide the exit code
+			Self::Gather(failures) => failures
+				.first()
+				.map_or(ErrorCategory::Other, |(_, err)| return err.category()),
+			Self::Usage(_) => ErrorCategory::Usage,
+			Self::WinapiProtocol(err) => err.category(),
+			Self::KeyFormat(_) | Self::Parse { .. } => ErrorCategory::Format,
+			Self::Ebook(err) => err.category(),
+			Self::Serialize { .. } | Self::AssertionFailed(_) | Self::Other(_) => ErrorCategory::Other,
+		};
+	}
+
 	/// Error with arbitrary, one-off meaning
 	pub fn other<M>(msg: M) -> Self
 	where
@@ -44,3 +401,122 @@ impl ExtractorError {
 		return Self::Other(msg.into());
 	}
 }
+
+/// Errors for when the decrypted key does not have the expected format
+#[derive(Error, Debug)]
+pub enum KeyFormatError {
+	#[error("Encrypted key length {0} is not a multiple of the AES block size")]
+	InvalidCiphertextLength(usize),
+
+	#[error("Decrypted device key has length {0}, expected 16")]
+	InvalidDeviceKeyLength(usize),
+
+	#[error("Invalid PKCS#7 padding: {0}")]
+	InvalidPadding(&'static str),
+
+	#[error("Failed to parse {what}")]
+	Asn1 {
+		what:   &'static str,
+		#[source]
+		source: pkcs8::der::Error,
+	},
+
+	#[error("Failed to encode {what}")]
+	Encode {
+		what:   &'static str,
+		#[source]
+		source: pkcs8::der::Error,
+	},
+
+	#[error("Extracted key does not match the public key of the licenseCertificate")]
+	KeyMismatch,
+
+	#[error("Failed to encrypt key")]
+	Encrypt(#[source] pkcs8::Error),
+
+	#[error("Failed to decrypt keyfile (wrong passphrase?)")]
+	Decrypt(#[source] pkcs8::Error),
+
+	#[error("Expected PEM label \"ENCRYPTED PRIVATE KEY\", got \"{0}\"")]
+	UnexpectedPemLabel(String),
+
+	#[error("Expected private key algorithm to be rsaEncryption, got OID {0}")]
+	UnexpectedAlgorithm(String),
+
+	#[error("RSA private key is not consistent")]
+	InconsistentKey(#[source] rsa::Error),
+
+	#[error("Failed to decode licenseCertificate from base64")]
+	InvalidCertificateEncoding(#[source] base64::DecodeError),
+
+	#[error("licenseCertificate does not contain a RSA public key")]
+	InvalidCertificateKey(#[source] pkcs8::spki::Error),
+}
+
+impl KeyFormatError {
+	/// Error for when a ASN.1 structure could not be parsed
+	pub fn asn1(what: &'static str, source: pkcs8::der::Error) -> Self {
+		return Self::Asn1 { what, source };
+	}
+
+	/// Error for when a ASN.1 structure could not be encoded
+	pub fn encode(what: &'static str, source: pkcs8::der::Error) -> Self {
+		return Self::Encode { what, source };
+	}
+}
+
+/// Errors for when the ADEPT license of a ebook could not be read
+#[derive(Error, Debug)]
+pub enum EbookError {
+	#[error("Failed to read \"{path}\"")]
+	Io {
+		path:   PathBuf,
+		#[source]
+		source: std::io::Error,
+	},
+
+	#[error("Failed to read EPUB \"{path}\"")]
+	Zip {
+		path:   PathBuf,
+		#[source]
+		source: zip::result::ZipError,
+	},
+
+	#[error("Unknown ebook type of \"{0}\", expected a EPUB or PDF")]
+	UnknownType(PathBuf),
+
+	#[error("ADEPT_LICENSE in PDF is malformed: {0}")]
+	MalformedPdfLicense(&'static str),
+
+	#[error("Failed to decode ADEPT_LICENSE from base64")]
+	InvalidLicenseEncoding(#[source] base64::DecodeError),
+
+	#[error("Failed to inflate ADEPT_LICENSE")]
+	InvalidLicenseCompression(#[source] std::io::Error),
+
+	#[error("ADEPT license does not contain a user")]
+	MissingUser,
+}
+
+impl EbookError {
+	/// Get the category of this error, which decides the exit code
+	#[must_use]
+	pub fn category(&self) -> ErrorCategory {
+		return match self {
+			Self::Io { .. } | Self::Zip { .. } => ErrorCategory::Io,
+			Self::UnknownType(_)
+			| Self::MalformedPdfLicense(_)
+			| Self::InvalidLicenseEncoding(_)
+			| Self::InvalidLicenseCompression(_)
+			| Self::MissingUser => ErrorCategory::Format,
+		};
+	}
+
+	/// Error for when a file could not be read
+	pub fn io(path: &std::path::Path, source: std::io::Error) -> Self {
+		return Self::Io {
+			path: path.to_path_buf(),
+			source,
+		};
+	}
+}
@@ -0,0 +1,125 @@
+//! Module for file related helpers
+
+use std::{
+	io::Write,
+	path::{
+		Path,
+		PathBuf,
+	},
+	time::SystemTime,
+};
+
+/// Write "content" to "path" by writing to a temporary file in the same directory first and then renaming it,
+/// so that "path" is never left partially written
+/// On unix the file is only readable and writable by the current user (mode 0600), because it is used for secrets
+pub fn write_atomic(path: &Path, content: &[u8]) -> Result<(), crate::Error> {
+	let mut tmp_path = path.as_os_str().to_owned();
+	tmp_path.push(format!(".tmp-{}", std::process::id()));
+	let tmp_path = PathBuf::from(tmp_path);
+
+	let res = (|| {
+		let mut options = std::fs::OpenOptions::new();
+		options.write(true).create(true).truncate(true);
+
+		#[cfg(unix)]
+		{
+			use std::os::unix::fs::OpenOptionsExt;
+			options.mode(0o600);
+		}
+
+		let mut file = options.open(&tmp_path)?;
+
+		// the mode only applies when the file is created, so also set it in case a old temporary file was left behind
+		#[cfg(unix)]
+		{
+			use std::os::unix::fs::PermissionsExt;
+			file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+		}
+
+		file.write_all(content)?;
+		file.sync_all()?;
+
+		return std::fs::rename(&tmp_path, path);
+	})();
+
+	if let Err(err) = res {
+		// try to not leave the temporary file behind
+		let _ = std::fs::remove_file(&tmp_path);
+
+		return Err(crate::Error::io(format!("Failed to write \"{}\"", path.display()), err));
+	}
+
+	return Ok(());
+}
+
+/// Copy the file at "path" to "<path>.<timestamp>.bak" and return the path of the backup
+pub fn backup_file(path: &Path) -> Result<PathBuf, crate::Error> {
+	// like "20261019T001645Z", because ":" is not allowed in file names on windows
+	let timestamp: String = humantime::format_rfc3339_seconds(SystemTime::now())
+		.to_string()
+		.chars()
+		.filter(|v| return !matches!(v, '-' | ':'))
+		.collect();
+
+	let mut backup_path = path.as_os_str().to_owned();
+	backup_path.push(format!(".{timestamp}.bak"));
+	let backup_path = PathBuf::from(backup_path);
+
+	std::fs::copy(path, &backup_path)
+		.map_err(|err| return crate::Error::io(format!("Failed to backup \"{}\"", path.display()), err))?;
+
+	return Ok(backup_path);
+}
+
+/// A temporary directory that is only accessible by the current user (mode 0700 on unix), removed with everything in it when dropped
+#[derive(Debug)]
+pub struct TempDir {
+	path: PathBuf,
+}
+
+impl TempDir {
+	/// Create a new temporary directory in the temporary directory of the system, its name starts with "prefix"
+	pub fn new(prefix: &str) -> Result<Self, crate::Error> {
+		let nanos = SystemTime::now()
+			.duration_since(SystemTime::UNIX_EPOCH)
+			.unwrap_or_default()
+			.subsec_nanos();
+		let path = std::env::temp_dir().join(format!("{prefix}-{}-{nanos}", std::process::id()));
+
+		// only changed on unix
+		#[allow(unused_mut)]
+		let mut builder = std::fs::DirBuilder::new();
+
+		#[cfg(unix)]
+		{
+			use std::os::unix::fs::DirBuilderExt;
+			builder.mode(0o700);
+		}
+
+		builder.create(&path).map_err(|err| {
+			return crate::Error::io(
+				format!("Failed to create temporary directory \"{}\"", path.display()),
+				err,
+			);
+		})?;
+
+		return Ok(Self { path });
+	}
+
+	/// Get the path of the directory
+	#[must_use]
+	pub fn path(&self) -> &Path {
+		return &self.path;
+	}
+}
+
+impl Drop for TempDir {
+	fn drop(&mut self) {
+		if let Err(err) = std::fs::remove_dir_all(&self.path) {
+			warn!(
+				"Failed to remove temporary directory \"{}\": {err}",
+				self.path.display()
+			);
+		}
+	}
+}
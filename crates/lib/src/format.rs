@@ -0,0 +1,372 @@
+//! Module for converting the extracted key (PKCS#1 DER) into different output formats
+
+use base64::Engine;
+use pkcs8::{
+	der::{
+		pem::{
+			self,
+			LineEnding,
+		},
+		Decode,
+		Encode,
+	},
+	pkcs5::{
+		pbes2,
+		scrypt,
+	},
+	rand_core::{
+		OsRng,
+		RngCore,
+	},
+};
+
+use zeroize::{
+	Zeroize,
+	Zeroizing,
+};
+
+use crate::{
+	error::KeyFormatError,
+	secret::SecretBytes,
+};
+
+/// All the formats the key can be output as
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum KeyFormat {
+	/// Raw PKCS#1 DER, which is what DeDRM expects
+	#[default]
+	Der,
+	/// PKCS#1 PEM ("BEGIN RSA PRIVATE KEY")
+	PemPkcs1,
+	/// PKCS#8 PEM ("BEGIN PRIVATE KEY")
+	PemPkcs8,
+	/// JSON Web Key (RFC 7517)
+	Jwk,
+	/// Hex encoded PKCS#1 DER
+	Hex,
+	/// Base64 encoded PKCS#1 DER
+	Base64,
+}
+
+impl KeyFormat {
+	/// All formats
+	pub const ALL: [Self; 6] = [
+		Self::Der,
+		Self::PemPkcs1,
+		Self::PemPkcs8,
+		Self::Jwk,
+		Self::Hex,
+		Self::Base64,
+	];
+
+	/// Get the file extension (without leading dot) that should be used for this format
+	#[must_use]
+	pub fn extension(&self) -> &'static str {
+		return match self {
+			Self::Der => "der",
+			Self::PemPkcs1 | Self::PemPkcs8 => "pem",
+			Self::Jwk => "jwk",
+			Self::Hex => "hex",
+			Self::Base64 => "b64",
+		};
+	}
+}
+
+impl std::fmt::Display for KeyFormat {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		return match self {
+			Self::Der => write!(f, "der"),
+			Self::PemPkcs1 => write!(f, "pem-pkcs1"),
+			Self::PemPkcs8 => write!(f, "pem-pkcs8"),
+			Self::Jwk => write!(f, "jwk"),
+			Self::Hex => write!(f, "hex"),
+			Self::Base64 => write!(f, "base64"),
+		};
+	}
+}
+
+/// Convert the given PKCS#1 DER "key" into the requested "format"
+pub fn encode_key(key: &[u8], format: KeyFormat) -> Result<SecretBytes, KeyFormatError> {
+	return match format {
+		KeyFormat::Der => Ok(SecretBytes::from(key.to_vec())),
+		KeyFormat::PemPkcs1 => encode_pem("RSA PRIVATE KEY", key),
+		KeyFormat::PemPkcs8 => {
+			let private_key_info = Zeroizing::new(
+				pkcs8::PrivateKeyInfo::new(pkcs1::ALGORITHM_ID, key)
+					.to_der()
+					.map_err(|err| return KeyFormatError::encode("PKCS#8 PrivateKeyInfo", err))?,
+			);
+
+			encode_pem("PRIVATE KEY", &private_key_info)
+		},
+		KeyFormat::Jwk => encode_jwk(key),
+		KeyFormat::Hex => Ok(with_newline(&Zeroizing::new(crate::decrypt::encode_hex(key)))),
+		KeyFormat::Base64 => Ok(with_newline(&Zeroizing::new(
+			base64::engine::general_purpose::STANDARD.encode(key),
+		))),
+	};
+}
+
+/// Get "encoded" with a trailing newline, allocated once so that no unwiped copy is left behind
+fn with_newline(encoded: &str) -> SecretBytes {
+	let mut out = Vec::with_capacity(encoded.len() + 1);
+	out.extend_from_slice(encoded.as_bytes());
+	out.push(b'\n');
+
+	return SecretBytes::from(out);
+}
+
+/// Encode the given DER "data" as PEM with "label"
+fn encode_pem(label: &'static str, data: &[u8]) -> Result<SecretBytes, KeyFormatError> {
+	let pem = pem::encode_string(label, LineEnding::LF, data)
+		.map_err(|err| return KeyFormatError::encode("PEM", err.into()))?;
+
+	return Ok(SecretBytes::from(pem.into_bytes()));
+}
+
+/// Encode the given PKCS#1 DER "key" as a JSON Web Key
+fn encode_jwk(key: &[u8]) -> Result<SecretBytes, KeyFormatError> {
+	let rsa_key =
+		pkcs1::RsaPrivateKey::from_der(key).map_err(|err| return KeyFormatError::asn1("PKCS#1 RSAPrivateKey", err))?;
+
+	/// Encode a big-endian unsigned integer as base64url without padding, as required by RFC 7518
+	fn b64(uint: pkcs1::UintRef<'_>) -> String {
+		return base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(uint.as_bytes());
+	}
+
+	let mut jwk = serde_json::json!({
+		"kty": "RSA",
+		"n": b64(rsa_key.modulus),
+		"e": b64(rsa_key.public_exponent),
+		"d": b64(rsa_key.private_exponent),
+		"p": b64(rsa_key.prime1),
+		"q": b64(rsa_key.prime2),
+		"dp": b64(rsa_key.exponent1),
+		"dq": b64(rsa_key.exponent2),
+		"qi": b64(rsa_key.coefficient),
+	});
+
+	// the base64 parts are shorter than twice the DER, so the buffer is not reallocated (which would leave unwiped copies)
+	let mut out = Vec::with_capacity(key.len() * 2 + 512);
+	serde_json::to_writer_pretty(&mut out, &jwk).expect("Expected serializing a json value to not fail");
+	out.push(b'\n');
+
+	// the private parts are in the json value as well
+	for value in jwk.as_object_mut().into_iter().flat_map(|v| return v.values_mut()) {
+		if let serde_json::Value::String(value) = value {
+			value.zeroize();
+		}
+	}
+
+	return Ok(SecretBytes::from(out));
+}
+
+/// Key derivation functions that can be used to encrypt a PKCS#8 key with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Kdf {
+	/// scrypt (N=2^14, r=8, p=1)
+	#[default]
+	Scrypt,
+	/// PBKDF2 with HMAC-SHA256
+	Pbkdf2,
+}
+
+impl std::fmt::Display for Kdf {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		return match self {
+			Self::Scrypt => write!(f, "scrypt"),
+			Self::Pbkdf2 => write!(f, "pbkdf2"),
+		};
+	}
+}
+
+/// Iterations used for PBKDF2, as recommended by OWASP for PBKDF2-HMAC-SHA256
+const PBKDF2_ITERATIONS: u32 = 600_000;
+
+/// scrypt parameters, same as openssl's defaults, because openssl refuses to use more memory than that by default
+const SCRYPT_LOG_N: u8 = 14;
+const SCRYPT_R: u32 = 8;
+const SCRYPT_P: u32 = 1;
+
+/// PEM label for a encrypted PKCS#8 key
+const ENCRYPTED_PEM_LABEL: &str = "ENCRYPTED PRIVATE KEY";
+
+/// Encrypt the given PKCS#1 DER "key" as a PKCS#8 "EncryptedPrivateKeyInfo" (PBES2 with "kdf" and AES-256-CBC)
+/// Output is PEM if "as_pem" is "true", otherwise DER
+pub fn encrypt_key(key: &[u8], passphrase: &[u8], kdf: Kdf, as_pem: bool) -> Result<SecretBytes, KeyFormatError> {
+	let mut salt = [0u8; 16];
+	let mut iv = [0u8; 16];
+	OsRng.fill_bytes(&mut salt);
+	OsRng.fill_bytes(&mut iv);
+
+	let params = match kdf {
+		Kdf::Scrypt => pbes2::Parameters::scrypt_aes256cbc(
+			scrypt::Params::new(SCRYPT_LOG_N, SCRYPT_R, SCRYPT_P, scrypt::Params::RECOMMENDED_LEN)
+				.expect("Expected static scrypt parameters to be valid"),
+			&salt,
+			&iv,
+		),
+		Kdf::Pbkdf2 => pbes2::Parameters::pbkdf2_sha256_aes256cbc(PBKDF2_ITERATIONS, &salt, &iv),
+	}
+	.map_err(|err| return KeyFormatError::Encrypt(err.into()))?;
+
+	let encrypted = pkcs8::PrivateKeyInfo::new(pkcs1::ALGORITHM_ID, key)
+		.encrypt_with_params(params, passphrase)
+		.map_err(KeyFormatError::Encrypt)?;
+
+	if as_pem {
+		return encode_pem(ENCRYPTED_PEM_LABEL, encrypted.as_bytes());
+	}
+
+	return Ok(SecretBytes::from(encrypted.as_bytes().to_vec()));
+}
+
+/// Decrypt a PKCS#8 "EncryptedPrivateKeyInfo" (PEM or DER) and return the PKCS#1 DER key in it
+pub fn decrypt_keyfile(data: &[u8], passphrase: &[u8]) -> Result<SecretBytes, KeyFormatError> {
+	let der = if data.starts_with(b"-----BEGIN ") {
+		let (label, der) =
+			pem::decode_vec(data).map_err(|err| return KeyFormatError::asn1("PEM keyfile", err.into()))?;
+
+		if label != ENCRYPTED_PEM_LABEL {
+			return Err(KeyFormatError::UnexpectedPemLabel(label.to_owned()));
+		}
+
+		der
+	} else {
+		data.to_vec()
+	};
+
+	let encrypted = pkcs8::EncryptedPrivateKeyInfo::from_der(&der)
+		.map_err(|err| return KeyFormatError::asn1("PKCS#8 EncryptedPrivateKeyInfo", err))?;
+
+	let decrypted = encrypted.decrypt(passphrase).map_err(KeyFormatError::Decrypt)?;
+
+	return crate::key::extract_rsa_private_key(decrypted.as_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// A PKCS#1 DER key
+	const KEY: &[u8] = include_bytes!("../tests/fixtures/key.der");
+
+	#[test]
+	fn encode_formats() {
+		assert_eq!(encode_key(KEY, KeyFormat::Der).unwrap().expose(), KEY);
+
+		let hex = encode_key(KEY, KeyFormat::Hex).unwrap();
+		let hex = std::str::from_utf8(hex.expose()).unwrap();
+		assert_eq!(
+			crate::decrypt::decode_hex(hex.strip_suffix('\n').unwrap()).unwrap(),
+			KEY
+		);
+
+		let b64 = encode_key(KEY, KeyFormat::Base64).unwrap();
+		let b64 = std::str::from_utf8(b64.expose()).unwrap();
+		assert_eq!(
+			base64::engine::general_purpose::STANDARD
+				.decode(b64.strip_suffix('\n').unwrap())
+				.unwrap(),
+			KEY
+		);
+
+		let pem = encode_key(KEY, KeyFormat::PemPkcs1).unwrap();
+		let (label, der) = pem::decode_vec(pem.expose()).unwrap();
+		assert_eq!((label, der.as_slice()), ("RSA PRIVATE KEY", KEY));
+
+		let pem = encode_key(KEY, KeyFormat::PemPkcs8).unwrap();
+		let (label, der) = pem::decode_vec(pem.expose()).unwrap();
+		assert_eq!(label, "PRIVATE KEY");
+		assert_eq!(pkcs8::PrivateKeyInfo::from_der(&der).unwrap().private_key, KEY);
+	}
+
+	#[test]
+	fn encode_jwk() {
+		let jwk = encode_key(KEY, KeyFormat::Jwk).unwrap();
+		let jwk: serde_json::Value = serde_json::from_slice(jwk.expose()).unwrap();
+		let rsa_key = pkcs1::RsaPrivateKey::from_der(KEY).unwrap();
+
+		assert_eq!(jwk["kty"], "RSA");
+		assert_eq!(
+			base64::engine::general_purpose::URL_SAFE_NO_PAD
+				.decode(jwk["d"].as_str().unwrap())
+				.unwrap(),
+			rsa_key.private_exponent.as_bytes()
+		);
+	}
+
+	#[test]
+	fn encrypt_round_trip() {
+		for kdf in [Kdf::Scrypt, Kdf::Pbkdf2] {
+			let pem = encrypt_key(KEY, b"passphrase", kdf, true).unwrap();
+			let (label, der) = pem::decode_vec(pem.expose()).unwrap();
+			assert_eq!(label, ENCRYPTED_PEM_LABEL);
+
+			// the output is a standard encrypted PKCS#8 key
+			let encrypted = pkcs8::EncryptedPrivateKeyInfo::from_der(&der).unwrap();
+			let decrypted = encrypted.decrypt(b"passphrase").unwrap();
+			assert_eq!(
+				pkcs8::PrivateKeyInfo::from_der(decrypted.as_bytes())
+					.unwrap()
+					.private_key,
+				KEY
+			);
+
+			assert_eq!(
+				decrypt_keyfile(pem.expose(), b"passphrase").unwrap().expose(),
+				KEY,
+				"{kdf}"
+			);
+			assert_eq!(decrypt_keyfile(&der, b"passphrase").unwrap().expose(), KEY, "{kdf}");
+		}
+
+		// DER output is the same structure without PEM
+		let der = encrypt_key(KEY, b"passphrase", Kdf::Pbkdf2, false).unwrap();
+		assert!(pkcs8::EncryptedPrivateKeyInfo::from_der(der.expose()).is_ok());
+		assert_eq!(decrypt_keyfile(der.expose(), b"passphrase").unwrap().expose(), KEY);
+	}
+
+	#[test]
+	fn encrypt_is_salted() {
+		let first = encrypt_key(KEY, b"passphrase", Kdf::Pbkdf2, false).unwrap();
+		let second = encrypt_key(KEY, b"passphrase", Kdf::Pbkdf2, false).unwrap();
+
+		assert_ne!(first, second);
+	}
+
+	#[test]
+	fn decrypt_invalid() {
+		let pem = encrypt_key(KEY, b"passphrase", Kdf::Pbkdf2, true).unwrap();
+		let (_, der) = pem::decode_vec(pem.expose()).unwrap();
+
+		assert!(matches!(
+			decrypt_keyfile(pem.expose(), b"wrong"),
+			Err(KeyFormatError::Decrypt(_))
+		));
+		assert!(matches!(
+			decrypt_keyfile(&der[..der.len() / 2], b"passphrase"),
+			Err(KeyFormatError::Asn1 { .. })
+		));
+
+		// a flipped bit in the ciphertext (the end of the DER) breaks the padding or the key structure
+		let mut garbled = der.clone();
+		*garbled.last_mut().unwrap() ^= 1;
+		assert!(matches!(
+			decrypt_keyfile(&garbled, b"passphrase"),
+			Err(KeyFormatError::Decrypt(_) | KeyFormatError::Asn1 { .. })
+		));
+
+		assert!(matches!(
+			decrypt_keyfile(b"not a keyfile", b"passphrase"),
+			Err(KeyFormatError::Asn1 { .. })
+		));
+
+		// only encrypted keys are accepted
+		let unencrypted = encode_key(KEY, KeyFormat::PemPkcs8).unwrap();
+		assert!(matches!(
+			decrypt_keyfile(unencrypted.expose(), b"passphrase"),
+			Err(KeyFormatError::UnexpectedPemLabel(v)) if v == "PRIVATE KEY"
+		));
+	}
+}
@@ -0,0 +1,226 @@
+//! Module for gathering everything the extraction uses, for inspection by humans or scripts
+
+use std::fmt::Write;
+
+use crate::decrypt::{
+	self,
+	encode_hex,
+	AdeptInformation,
+	CpuInfo,
+	DriveInfo,
+};
+
+/// Version of the JSON output, increased when fields are removed or change meaning
+pub const INFO_JSON_VERSION: u32 = 1;
+
+use crate::secret::SecretBytes;
+pub use crate::secret::REDACTED;
+
+/// Everything that is gathered before the decryption
+#[derive(Debug)]
+pub struct GatheredInfo {
+	pub drive_info:  DriveInfo,
+	pub cpu_info:    CpuInfo,
+	/// The windows username
+	pub username:    String,
+	/// All activations (accounts)
+	pub adept_infos: Vec<AdeptInformation>,
+	/// The entropy computed from the above
+	pub entropy:     SecretBytes,
+}
+
+/// Gather all information, without decrypting anything
+pub fn gather_info() -> Result<GatheredInfo, crate::Error> {
+	let crate::pipeline::GatherResult {
+		drive_info,
+		cpu_info,
+		username,
+		adept_infos,
+	} = crate::pipeline::gather()?;
+	let entropy = SecretBytes::from(decrypt::setup_entropy(&drive_info, &cpu_info, &username)?);
+
+	return Ok(GatheredInfo {
+		drive_info,
+		cpu_info,
+		username,
+		adept_infos,
+		entropy,
+	});
+}
+
+/// Return "value" if "show_secrets" is "true", otherwise [REDACTED]
+fn secret(value: &str, show_secrets: bool) -> &str {
+	if show_secrets {
+		return value;
+	}
+
+	return REDACTED;
+}
+
+impl GatheredInfo {
+	/// Get the device key, which is the same for all activations
+	fn device_key(&self) -> Option<&str> {
+		return self.adept_infos.first().map(|v| return v.device_key.expose());
+	}
+
+	/// Get everything as JSON, secrets (entropy, device key, private license keys) are redacted unless "show_secrets" is "true"
+	#[must_use]
+	pub fn to_json(&self, show_secrets: bool) -> serde_json::Value {
+		let activations: Vec<serde_json::Value> = self
+			.adept_infos
+			.iter()
+			.map(|v| {
+				return serde_json::json!({
+					"user": v.user,
+					"method": v.username.0,
+					"adobe_id": v.username.1,
+					"private_license_key": secret(v.key.expose(), show_secrets),
+					"license_certificate": v.license_certificate,
+				});
+			})
+			.collect();
+
+		return serde_json::json!({
+			"version": INFO_JSON_VERSION,
+			"drive": {
+				"system_drive": self.drive_info.win_system_drive,
+				"volume_serial": self.drive_info.win_system_volume_serial,
+			},
+			"cpu": {
+				"vendor": self.cpu_info.cpu_vendor,
+				"magic_number": encode_hex(&self.cpu_info.cpu_magic_number),
+			},
+			"username": self.username,
+			"entropy": secret(&encode_hex(self.entropy.expose()), show_secrets),
+			"device_key": self.device_key().map(|v| return secret(v, show_secrets)),
+			"activations": activations,
+		});
+	}
+
+	/// Get everything as human-readable text, secrets are redacted unless "show_secrets" is "true"
+	#[must_use]
+	pub fn to_text(&self, show_secrets: bool) -> String {
+		let mut out = String::new();
+
+		// writing to a String cannot fail
+		let _ = writeln!(out, "System Drive:     {}", self.drive_info.win_system_drive);
+		let _ = writeln!(out, "Volume Serial:    {}", self.drive_info.win_system_volume_serial);
+		let _ = writeln!(out, "CPU Vendor:       {}", self.cpu_info.cpu_vendor);
+		let _ = writeln!(out, "CPU Magic Number: {}", encode_hex(&self.cpu_info.cpu_magic_number));
+		let _ = writeln!(out, "Username:         {}", self.username);
+		let _ = writeln!(
+			out,
+			"Entropy:          {}",
+			secret(&encode_hex(self.entropy.expose()), show_secrets)
+		);
+		let _ = writeln!(
+			out,
+			"Device Key:       {}",
+			self.device_key().map_or("none", |v| return secret(v, show_secrets))
+		);
+		let _ = writeln!(out, "Activations:      {}", self.adept_infos.len());
+
+		for (i, adept_info) in self.adept_infos.iter().enumerate() {
+			let _ = writeln!(
+				out,
+				"  [{}] {} ({})",
+				i + 1,
+				adept_info.username.1,
+				adept_info.username.0
+			);
+			let _ = writeln!(out, "      User:                {}", adept_info.user);
+			let _ = writeln!(
+				out,
+				"      Private License Key: {}",
+				secret(adept_info.key.expose(), show_secrets)
+			);
+			let _ = writeln!(
+				out,
+				"      License Certificate: {}",
+				if adept_info.license_certificate.is_some() {
+					"present"
+				} else {
+					"missing"
+				}
+			);
+		}
+
+		return out;
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::secret::SecretString;
+
+	/// The secrets of [info], which have to be redacted
+	const ENTROPY: &[u8] = &[0xe1, 0xe2, 0xe3];
+	const DEVICE_KEY: &str = "DEVICEKEY0011";
+	const PRIVATE_LICENSE_KEY: &str = "cHJpdmF0ZUxpY2Vuc2VLZXk=";
+
+	/// Get a [GatheredInfo] with known secrets
+	fn info() -> GatheredInfo {
+		return GatheredInfo {
+			drive_info:  DriveInfo {
+				win_system_drive:         String::from("C:"),
+				win_system_volume_serial: 1_124_073_472,
+			},
+			cpu_info:    CpuInfo {
+				cpu_vendor:       String::from("GenuineIntel"),
+				cpu_magic_number: vec![0x08, 0x06, 0xf8],
+			},
+			username:    String::from("winuser"),
+			adept_infos: vec![AdeptInformation {
+				user:                String::from("urn:uuid:0000-1111"),
+				username:            (String::from("AdobeID"), String::from("reader@example.com")),
+				key:                 SecretString::from(PRIVATE_LICENSE_KEY),
+				device_key:          SecretString::from(DEVICE_KEY),
+				license_certificate: None,
+			}],
+			entropy:     SecretBytes::from(ENTROPY.to_vec()),
+		};
+	}
+
+	/// Get if "output" contains any of the secrets of [info]
+	fn contains_secrets(output: &str) -> [bool; 3] {
+		return [
+			output.contains(&encode_hex(ENTROPY)),
+			output.contains(DEVICE_KEY),
+			output.contains(PRIVATE_LICENSE_KEY),
+		];
+	}
+
+	#[test]
+	fn redacted() {
+		let info = info();
+		let json = info.to_json(false).to_string();
+		let text = info.to_text(false);
+
+		assert_eq!(contains_secrets(&json), [false; 3], "{json}");
+		assert_eq!(contains_secrets(&text), [false; 3], "{text}");
+		assert!(json.contains(REDACTED));
+		assert!(text.contains(REDACTED));
+
+		// everything else is still shown
+		for output in [&json, &text] {
+			assert!(output.contains("reader@example.com"));
+			assert!(output.contains("urn:uuid:0000-1111"));
+			assert!(output.contains("winuser"));
+			assert!(output.contains("0806f8"));
+		}
+	}
+
+	#[test]
+	fn show_secrets() {
+		let info = info();
+		let json = info.to_json(true);
+
+		assert_eq!(contains_secrets(&json.to_string()), [true; 3]);
+		assert_eq!(contains_secrets(&info.to_text(true)), [true; 3]);
+		assert_eq!(json["entropy"], "e1e2e3");
+		assert_eq!(json["device_key"], DEVICE_KEY);
+		assert_eq!(json["activations"][0]["private_license_key"], PRIVATE_LICENSE_KEY);
+		assert_eq!(json["version"], INFO_JSON_VERSION);
+	}
+}
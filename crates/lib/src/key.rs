@@ -0,0 +1,311 @@
+//! Module for parsing the decrypted "privateLicenseKey" into the actual RSA private key
+
+use base64::Engine;
+use pkcs8::{
+	der::{
+		asn1::BitStringRef,
+		referenced::OwnedToRef,
+		Decode,
+		Encode,
+	},
+	spki::SubjectPublicKeyInfoRef,
+};
+use rsa::{
+	traits::PublicKeyParts,
+	BigUint,
+	RsaPrivateKey,
+	RsaPublicKey,
+};
+use sha2::{
+	Digest,
+	Sha256,
+};
+
+use crate::{
+	error::KeyFormatError,
+	secret::SecretBytes,
+};
+
+/// Block size of AES, which the padding is applied to
+pub const AES_BLOCK_SIZE: usize = 16;
+
+/// Remove and validate the PKCS#7 padding from "data"
+pub fn unpad_pkcs7(data: &mut Vec<u8>, block_size: usize) -> Result<(), KeyFormatError> {
+	if data.is_empty() || data.len() % block_size != 0 {
+		return Err(KeyFormatError::InvalidPadding(
+			"data length is not a multiple of the block size",
+		));
+	}
+
+	// safe to unwrap, because it was checked above that it is not empty
+	let pad_len = usize::from(*data.last().unwrap());
+
+	if pad_len == 0 || pad_len > block_size {
+		return Err(KeyFormatError::InvalidPadding("padding length is out of range"));
+	}
+
+	let content_len = data.len() - pad_len;
+
+	if data[content_len..].iter().any(|v| return usize::from(*v) != pad_len) {
+		return Err(KeyFormatError::InvalidPadding("padding bytes are inconsistent"));
+	}
+
+	data.truncate(content_len);
+
+	return Ok(());
+}
+
+/// Parse the decrypted (and unpadded) "privateLicenseKey" and return the RSA private key in it
+///
+/// The decrypted data is a PKCS#8 "PrivateKeyInfo", which contains a PKCS#1 "RSAPrivateKey",
+/// which is what DeDRM expects as a key (DER encoded)
+pub fn extract_rsa_private_key(plain: &[u8]) -> Result<SecretBytes, KeyFormatError> {
+	let private_key_info = pkcs8::PrivateKeyInfo::from_der(plain)
+		.map_err(|err| return KeyFormatError::asn1("PKCS#8 PrivateKeyInfo", err))?;
+
+	if private_key_info.algorithm.oid != pkcs1::ALGORITHM_OID {
+		return Err(KeyFormatError::UnexpectedAlgorithm(
+			private_key_info.algorithm.oid.to_string(),
+		));
+	}
+
+	let rsa_key = private_key_info.private_key;
+
+	// parse it once, to make sure it actually is a RSA private key
+	pkcs1::RsaPrivateKey::from_der(rsa_key).map_err(|err| return KeyFormatError::asn1("PKCS#1 RSAPrivateKey", err))?;
+
+	return Ok(SecretBytes::from(rsa_key.to_vec()));
+}
+
+/// Parse the given PKCS#1 DER "key" and check that it is internally consistent (n = p·q, e·d ≡ 1)
+pub fn parse_rsa_private_key(key: &[u8]) -> Result<RsaPrivateKey, KeyFormatError> {
+	let pkcs1_key =
+		pkcs1::RsaPrivateKey::from_der(key).map_err(|err| return KeyFormatError::asn1("PKCS#1 RSAPrivateKey", err))?;
+
+	let n = BigUint::from_bytes_be(pkcs1_key.modulus.as_bytes());
+	let e = BigUint::from_bytes_be(pkcs1_key.public_exponent.as_bytes());
+	let d = BigUint::from_bytes_be(pkcs1_key.private_exponent.as_bytes());
+	let primes = vec![
+		BigUint::from_bytes_be(pkcs1_key.prime1.as_bytes()),
+		BigUint::from_bytes_be(pkcs1_key.prime2.as_bytes()),
+	];
+
+	// "from_components" runs "validate", which checks that the product of the primes is "n" and "e·d ≡ 1 mod (p-1)" for each prime
+	let private_key = RsaPrivateKey::from_components(n, e, d, primes).map_err(KeyFormatError::InconsistentKey)?;
+
+	return Ok(private_key);
+}
+
+/// Parse the public key from the given base64 encoded DER X.509 certificate
+pub fn parse_certificate_public_key(certificate: &str) -> Result<RsaPublicKey, KeyFormatError> {
+	let cert_bytes = base64::engine::general_purpose::STANDARD
+		.decode(certificate.trim())
+		.map_err(KeyFormatError::InvalidCertificateEncoding)?;
+
+	let cert = x509_cert::Certificate::from_der(&cert_bytes)
+		.map_err(|err| return KeyFormatError::asn1("X.509 licenseCertificate", err))?;
+
+	let public_key = RsaPublicKey::try_from(cert.tbs_certificate.subject_public_key_info.owned_to_ref())
+		.map_err(KeyFormatError::InvalidCertificateKey)?;
+
+	return Ok(public_key);
+}
+
+/// The result of verifying a key against the "licenseCertificate"
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyVerification {
+	/// The key matches the public key of the "licenseCertificate"
+	Verified,
+	/// The key does not match the public key of the "licenseCertificate"
+	Mismatch,
+	/// There was no "licenseCertificate" to verify against, only the consistency of the key itself was checked
+	Unverified,
+}
+
+impl std::fmt::Display for KeyVerification {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		return match self {
+			Self::Verified => write!(f, "verified"),
+			Self::Mismatch => write!(f, "mismatch"),
+			Self::Unverified => write!(f, "unverified"),
+		};
+	}
+}
+
+/// Verify the given PKCS#1 DER "key" for consistency and against the public key of the "licenseCertificate" (base64), if available
+/// A "licenseCertificate" that cannot be parsed is treated like a missing one, because it says nothing about the key
+pub fn verify_key(key: &[u8], license_certificate: Option<&str>) -> Result<KeyVerification, KeyFormatError> {
+	let private_key = parse_rsa_private_key(key)?;
+
+	let Some(license_certificate) = license_certificate else {
+		return Ok(KeyVerification::Unverified);
+	};
+
+	let public_key = match parse_certificate_public_key(license_certificate) {
+		Ok(v) => v,
+		Err(err) => {
+			warn!(
+				"Could not use the licenseCertificate to verify the key: {}",
+				crate::error::display_chain(&err)
+			);
+
+			return Ok(KeyVerification::Unverified);
+		},
+	};
+
+	if public_key.n() != private_key.n() || public_key.e() != private_key.e() {
+		return Ok(KeyVerification::Mismatch);
+	}
+
+	return Ok(KeyVerification::Verified);
+}
+
+/// Get the fingerprint of the given PKCS#1 DER "key", which is the hex SHA-256 of its public key (SubjectPublicKeyInfo DER)
+/// Same as "openssl pkey -pubout -outform DER | sha256sum"
+pub fn fingerprint(key: &[u8]) -> Result<String, KeyFormatError> {
+	let pkcs1_key =
+		pkcs1::RsaPrivateKey::from_der(key).map_err(|err| return KeyFormatError::asn1("PKCS#1 RSAPrivateKey", err))?;
+
+	let public_key_der = pkcs1_key
+		.public_key()
+		.to_der()
+		.map_err(|err| return KeyFormatError::encode("PKCS#1 RSAPublicKey", err))?;
+
+	let spki_der = SubjectPublicKeyInfoRef {
+		algorithm:          pkcs1::ALGORITHM_ID,
+		subject_public_key: BitStringRef::from_bytes(&public_key_der)
+			.map_err(|err| return KeyFormatError::encode("SubjectPublicKeyInfo", err))?,
+	}
+	.to_der()
+	.map_err(|err| return KeyFormatError::encode("SubjectPublicKeyInfo", err))?;
+
+	return Ok(crate::decrypt::encode_hex(&Sha256::digest(spki_der)));
+}
+
+#[cfg(test)]
+mod tests {
+	use pkcs8::{
+		spki::AlgorithmIdentifierRef,
+		ObjectIdentifier,
+	};
+
+	use super::*;
+
+	/// A PKCS#1 DER key
+	const KEY: &[u8] = include_bytes!("../tests/fixtures/key.der");
+	/// A self-signed certificate (base64 DER) for [KEY]
+	const KEY_CERT: &str = include_str!("../tests/fixtures/key_cert.b64");
+	/// A self-signed certificate (base64 DER) for another key
+	const OTHER_CERT: &str = include_str!("../tests/fixtures/other_cert.b64");
+
+	/// Get "data" padded to "block_size" with PKCS#7
+	fn pad(data: &[u8], block_size: usize) -> Vec<u8> {
+		let pad_len = block_size - data.len() % block_size;
+		let mut padded = data.to_vec();
+		padded.resize(data.len() + pad_len, u8::try_from(pad_len).unwrap());
+
+		return padded;
+	}
+
+	#[test]
+	fn unpad_valid() {
+		for len in [0, 1, 15, 16, 17] {
+			let data: Vec<u8> = (0..len).map(|v| return u8::try_from(v).unwrap()).collect();
+			let mut padded = pad(&data, AES_BLOCK_SIZE);
+
+			unpad_pkcs7(&mut padded, AES_BLOCK_SIZE).unwrap();
+			assert_eq!(padded, data);
+		}
+	}
+
+	#[test]
+	fn unpad_invalid() {
+		let cases: [(Vec<u8>, &str); 5] = [
+			(Vec::new(), "data length is not a multiple of the block size"),
+			(vec![1; 17], "data length is not a multiple of the block size"),
+			([vec![7; 15], vec![0]].concat(), "padding length is out of range"),
+			(vec![17; 16], "padding length is out of range"),
+			(
+				[vec![7; 12], vec![3, 4, 4, 4]].concat(),
+				"padding bytes are inconsistent",
+			),
+		];
+
+		for (mut data, expected) in cases {
+			let original = data.clone();
+
+			assert!(
+				matches!(unpad_pkcs7(&mut data, AES_BLOCK_SIZE), Err(KeyFormatError::InvalidPadding(v)) if v == expected),
+				"{original:?}"
+			);
+			// nothing is removed on error
+			assert_eq!(data, original);
+		}
+	}
+
+	#[test]
+	fn extract_key() {
+		let private_key_info = pkcs8::PrivateKeyInfo::new(pkcs1::ALGORITHM_ID, KEY).to_der().unwrap();
+
+		assert_eq!(extract_rsa_private_key(&private_key_info).unwrap().expose(), KEY);
+	}
+
+	#[test]
+	fn extract_key_invalid() {
+		// Ed25519 instead of RSA
+		let other_algorithm = pkcs8::PrivateKeyInfo::new(
+			AlgorithmIdentifierRef {
+				oid:        ObjectIdentifier::new_unwrap("1.3.101.112"),
+				parameters: None,
+			},
+			KEY,
+		)
+		.to_der()
+		.unwrap();
+		assert!(matches!(
+			extract_rsa_private_key(&other_algorithm),
+			Err(KeyFormatError::UnexpectedAlgorithm(v)) if v == "1.3.101.112"
+		));
+
+		// a PKCS#1 key is not a PKCS#8 "PrivateKeyInfo"
+		assert!(matches!(extract_rsa_private_key(KEY), Err(KeyFormatError::Asn1 { .. })));
+
+		// RSA, but the content is not a PKCS#1 key
+		let garbage = pkcs8::PrivateKeyInfo::new(pkcs1::ALGORITHM_ID, &[1, 2, 3])
+			.to_der()
+			.unwrap();
+		assert!(matches!(
+			extract_rsa_private_key(&garbage),
+			Err(KeyFormatError::Asn1 { .. })
+		));
+	}
+
+	#[test]
+	fn verify() {
+		assert_eq!(verify_key(KEY, Some(KEY_CERT)).unwrap(), KeyVerification::Verified);
+		assert_eq!(verify_key(KEY, Some(OTHER_CERT)).unwrap(), KeyVerification::Mismatch);
+		assert_eq!(verify_key(KEY, None).unwrap(), KeyVerification::Unverified);
+	}
+
+	#[test]
+	fn verify_invalid() {
+		// a certificate that cannot be used does not say anything about the key
+		assert_eq!(
+			verify_key(KEY, Some("not base64!")).unwrap(),
+			KeyVerification::Unverified
+		);
+		assert_eq!(verify_key(KEY, Some("AAECAw==")).unwrap(), KeyVerification::Unverified);
+		assert!(matches!(
+			parse_certificate_public_key("AAECAw=="),
+			Err(KeyFormatError::Asn1 { .. })
+		));
+
+		// p is replaced by q, so that n != p·q
+		let mut inconsistent = pkcs1::RsaPrivateKey::from_der(KEY).unwrap();
+		inconsistent.prime1 = inconsistent.prime2;
+		assert!(matches!(
+			verify_key(&inconsistent.to_der().unwrap(), Some(KEY_CERT)),
+			Err(KeyFormatError::InconsistentKey(_))
+		));
+	}
+}
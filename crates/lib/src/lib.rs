@@ -6,7 +6,27 @@
 extern crate log;
 
 pub mod decrypt;
+pub mod dedrm;
+pub mod doctor;
+pub mod ebook;
 pub mod error;
+pub mod file;
+pub mod format;
+pub mod info;
+pub mod key;
+pub mod naming;
+pub mod pipeline;
+pub mod prefix;
+pub mod record;
+pub mod regfile;
+pub mod runner;
+pub mod secret;
+pub mod settings;
+pub mod store;
+#[cfg(test)]
+mod test_util;
+pub mod winapi_protocol;
+pub mod wine;
 
 pub type Error = error::ExtractorError;
 
@@ -0,0 +1,189 @@
+//! Module for naming key files after the account they belong to
+
+/// Default template for key file names, in the style of DeDRM's "adobekey_<n>_<uuid>.der"
+pub const DEFAULT_TEMPLATE: &str = "adobekey_{n}_{uuid}.{ext}";
+
+/// Fallback name (without extension) for when nothing about the account is known
+pub const FALLBACK_FILE_STEM: &str = "ade_key";
+
+/// Placeholders available in a template
+pub const PLACEHOLDERS: &[&str] = &["n", "uuid", "adobe_id", "method", "source", "ext"];
+
+/// Everything known about a key that can be used in a file name template
+#[derive(Debug, Clone, Default)]
+pub struct KeyNameInfo<'a> {
+	/// Number of the key in the current run (starting at 1)
+	pub n:        usize,
+	/// The "urn:uuid" of the account (with or without the "urn:uuid:" prefix)
+	pub user:     Option<&'a str>,
+	/// The AdobeID of the account
+	pub adobe_id: Option<&'a str>,
+	/// The method of the account (like "AdobeID")
+	pub method:   Option<&'a str>,
+	/// Name of where the key was extracted from (like the wine prefix directory name)
+	pub source:   Option<&'a str>,
+	/// The file extension without leading dot
+	pub ext:      &'a str,
+}
+
+/// Get the uuid from a "urn:uuid:..." string
+pub fn user_uuid(user: &str) -> &str {
+	return user.strip_prefix("urn:uuid:").unwrap_or(user);
+}
+
+/// Replace all characters that are not safe in file names on common platforms
+/// A value of only dots (like "..") is replaced completely, so that it cannot refer to a directory
+fn sanitize(value: &str) -> String {
+	if !value.is_empty() && value.chars().all(|v| return v == '.') {
+		return "_".repeat(value.len());
+	}
+
+	return value
+		.chars()
+		.map(|v| {
+			if v.is_control() || matches!(v, '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|') {
+				return '_';
+			}
+
+			return v;
+		})
+		.collect();
+}
+
+/// Render the given file name "template" with "info"
+/// Placeholders are written like "{uuid}", see [PLACEHOLDERS] for all available ones,
+/// unknown values are rendered as "unknown"
+pub fn render_template(template: &str, info: &KeyNameInfo) -> Result<String, crate::Error> {
+	let mut out = String::with_capacity(template.len());
+	let mut rest = template;
+
+	while let Some(start) = rest.find('{') {
+		out.push_str(&rest[..start]);
+
+		let end = rest[start..]
+			.find('}')
+			.ok_or_else(|| return crate::Error::usage(format!("Unclosed placeholder in template \"{template}\"")))?;
+		let placeholder = &rest[start + 1..start + end];
+
+		let value = match placeholder {
+			"n" => info.n.to_string(),
+			"uuid" => info.user.map_or("unknown", user_uuid).to_owned(),
+			"adobe_id" => info.adobe_id.unwrap_or("unknown").to_owned(),
+			"method" => info.method.unwrap_or("unknown").to_owned(),
+			"source" => info.source.unwrap_or("unknown").to_owned(),
+			"ext" => info.ext.to_owned(),
+			_ => {
+				return Err(crate::Error::usage(format!(
+					"Unknown placeholder \"{{{placeholder}}}\" in template \"{template}\", available are: {}",
+					PLACEHOLDERS.join(", ")
+				)))
+			},
+		};
+
+		out.push_str(&sanitize(&value));
+		rest = &rest[start + end + 1..];
+	}
+
+	out.push_str(rest);
+
+	if out.is_empty() {
+		return Err(crate::Error::usage(format!(
+			"Template \"{template}\" resulted in a empty file name"
+		)));
+	}
+
+	return Ok(out);
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Get a [KeyNameInfo] where everything is known
+	fn full_info() -> KeyNameInfo<'static> {
+		return KeyNameInfo {
+			n:        2,
+			user:     Some("urn:uuid:0000-1111"),
+			adobe_id: Some("reader@example.com"),
+			method:   Some("AdobeID"),
+			source:   Some("prefix"),
+			ext:      "der",
+		};
+	}
+
+	#[test]
+	fn placeholders() {
+		assert_eq!(
+			render_template(DEFAULT_TEMPLATE, &full_info()).unwrap(),
+			"adobekey_2_0000-1111.der"
+		);
+		assert_eq!(
+			render_template("{source}-{method}-{adobe_id}.{ext}", &full_info()).unwrap(),
+			"prefix-AdobeID-reader@example.com.der"
+		);
+		// a user without the "urn:uuid:" prefix is used as it is
+		assert_eq!(
+			render_template(
+				"{uuid}",
+				&KeyNameInfo {
+					user: Some("0000-2222"),
+					..full_info()
+				}
+			)
+			.unwrap(),
+			"0000-2222"
+		);
+		assert_eq!(
+			render_template("no placeholder", &full_info()).unwrap(),
+			"no placeholder"
+		);
+	}
+
+	#[test]
+	fn unknown_values() {
+		let info = KeyNameInfo {
+			n: 1,
+			ext: "pem",
+			..Default::default()
+		};
+
+		assert_eq!(
+			render_template("{uuid}_{adobe_id}_{method}_{source}_{n}.{ext}", &info).unwrap(),
+			"unknown_unknown_unknown_unknown_1.pem"
+		);
+	}
+
+	#[test]
+	fn invalid_templates() {
+		for template in ["{unknown}", "key_{uuid", "{}", ""] {
+			let err = render_template(template, &full_info()).unwrap_err();
+
+			assert_eq!(err.category(), crate::error::ErrorCategory::Usage, "{template}");
+		}
+
+		let err = render_template("{user}", &full_info()).unwrap_err();
+		assert!(err.to_string().contains("available are: n, uuid"), "{err}");
+	}
+
+	#[test]
+	fn sanitized_values() {
+		let render = |adobe_id: &'static str| {
+			return render_template(
+				"{adobe_id}",
+				&KeyNameInfo {
+					adobe_id: Some(adobe_id),
+					..full_info()
+				},
+			)
+			.unwrap();
+		};
+
+		assert_eq!(render("../../etc/passwd"), ".._.._etc_passwd");
+		assert_eq!(render("a\\b:c*d?e\"f<g>h|i\nj"), "a_b_c_d_e_f_g_h_i_j");
+		assert_eq!(render(".."), "__");
+		assert_eq!(render("."), "_");
+		assert_eq!(render("a.b"), "a.b");
+		// the template itself is not sanitized, it is chosen by the user
+		assert_eq!(render_template("keys/{n}", &full_info()).unwrap(), "keys/2");
+	}
+}
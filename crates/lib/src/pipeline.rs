@@ -0,0 +1,600 @@
+//! Module for running the extraction as named stages, whose results are kept in a state that can be saved and resumed
+
+use std::path::Path;
+
+use serde::{
+	Deserialize,
+	Serialize,
+};
+use zeroize::Zeroizing;
+
+use crate::{
+	decrypt::{
+		self,
+		encode_hex,
+		AdeptInformation,
+		CpuInfo,
+		DriveInfo,
+	},
+	secret::{
+		SecretBytes,
+		SecretString,
+	},
+};
+
+/// Version of the state file format, to reject state files from incompatible versions
+const STATE_VERSION: u32 = 1;
+
+/// All stages of the extraction, in order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Stage {
+	/// Gather the drive, cpu, username and activation information
+	Gather,
+	/// Compute the entropy from the gathered information
+	Entropy,
+	/// Decrypt the device key with DPAPI (via the winapi-bin)
+	Dpapi,
+	/// Decrypt the "privateLicenseKey" of each activation with the decrypted device key
+	Aes,
+}
+
+impl std::fmt::Display for Stage {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		return match self {
+			Self::Gather => write!(f, "gather"),
+			Self::Entropy => write!(f, "entropy"),
+			Self::Dpapi => write!(f, "dpapi"),
+			Self::Aes => write!(f, "aes"),
+		};
+	}
+}
+
+/// The results of the "gather" stage
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GatherResult {
+	pub drive_info:  DriveInfo,
+	pub cpu_info:    CpuInfo,
+	/// The windows username
+	pub username:    String,
+	/// All activations (accounts)
+	pub adept_infos: Vec<AdeptInformation>,
+}
+
+/// The results of all completed stages, except "aes", whose results are the keys that are returned
+/// Contains secrets (like the decrypted device key), so it should be handled like a key
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PipelineState {
+	version:     u32,
+	/// Result of [Stage::Gather]
+	pub gather:  Option<GatherResult>,
+	/// Result of [Stage::Entropy] (hex)
+	pub entropy: Option<SecretString>,
+	/// Result of [Stage::Dpapi], the decrypted device key (hex)
+	pub dpapi:   Option<SecretString>,
+}
+
+impl Default for PipelineState {
+	fn default() -> Self {
+		return Self {
+			version: STATE_VERSION,
+			gather:  None,
+			entropy: None,
+			dpapi:   None,
+		};
+	}
+}
+
+impl PipelineState {
+	/// Load a state from the file at "path"
+	pub fn load(path: &Path) -> Result<Self, crate::Error> {
+		let content = Zeroizing::new(
+			std::fs::read(path)
+				.map_err(|err| return crate::Error::io(format!("Failed to read \"{}\"", path.display()), err))?,
+		);
+		let state: Self = serde_json::from_slice(&content)
+			.map_err(|err| return crate::Error::parse(format!("state file \"{}\"", path.display()), err))?;
+
+		if state.version != STATE_VERSION {
+			return Err(crate::Error::usage(format!(
+				"Unsupported state file version {} in \"{}\"",
+				state.version,
+				path.display()
+			)));
+		}
+
+		return Ok(state);
+	}
+
+	/// Save the state to the file at "path"
+	pub fn save(&self, path: &Path) -> Result<(), crate::Error> {
+		let mut content = Zeroizing::new(
+			serde_json::to_vec_pretty(self)
+				.map_err(|source| return crate::Error::Serialize { what: "state", source })?,
+		);
+		content.push(b'\n');
+
+		crate::file::write_atomic(path, &content)?;
+
+		return Ok(());
+	}
+
+	/// Get the last stage that was completed, if any
+	#[must_use]
+	pub fn last_completed(&self) -> Option<Stage> {
+		if self.dpapi.is_some() {
+			return Some(Stage::Dpapi);
+		}
+
+		if self.entropy.is_some() {
+			return Some(Stage::Entropy);
+		}
+
+		if self.gather.is_some() {
+			return Some(Stage::Gather);
+		}
+
+		return None;
+	}
+
+	/// Run all stages that are not completed yet and return the keys in the same order as the activations
+	/// "on_stage_done" is called after each completed stage (except "aes"), like to save the state
+	pub fn run<F>(&mut self, mut on_stage_done: F) -> Result<Vec<SecretBytes>, crate::Error>
+	where
+		F: FnMut(&Self, Stage) -> Result<(), crate::Error>,
+	{
+		if self.gather.is_none() {
+			self.gather = Some(gather()?);
+			on_stage_done(self, Stage::Gather)?;
+		}
+
+		// safe to unwrap, because it was set above if it was not already
+		let gather = self.gather.as_ref().unwrap();
+
+		if self.entropy.is_none() {
+			let entropy = Zeroizing::new(decrypt::setup_entropy(
+				&gather.drive_info,
+				&gather.cpu_info,
+				&gather.username,
+			)?);
+			self.entropy = Some(SecretString::from(encode_hex(&entropy)));
+			on_stage_done(self, Stage::Entropy)?;
+		}
+
+		if self.dpapi.is_none() {
+			let gather = self.gather.as_ref().unwrap();
+			let device_key_hex = &gather
+				.adept_infos
+				.first()
+				.ok_or_else(|| return crate::Error::no_adept_reg_key("Activation"))?
+				.device_key;
+
+			// safe to unwrap, because it was set above if it was not already
+			self.dpapi = Some(decrypt::dpapi_decrypt(
+				self.entropy.as_ref().unwrap().expose(),
+				device_key_hex.expose(),
+			)?);
+			on_stage_done(self, Stage::Dpapi)?;
+		}
+
+		return self.run_aes();
+	}
+
+	/// Run only the "aes" stage, requires the "gather" and "dpapi" stages to be completed
+	pub fn run_aes(&self) -> Result<Vec<SecretBytes>, crate::Error> {
+		let (Some(gather), Some(dpapi)) = (&self.gather, &self.dpapi) else {
+			return Err(crate::Error::usage(
+				"The \"aes\" stage requires the \"gather\" and \"dpapi\" stages to be completed",
+			));
+		};
+
+		return gather
+			.adept_infos
+			.iter()
+			.map(|adept_info| return decrypt::aes_decrypt(dpapi.expose(), adept_info.key.expose()))
+			.collect();
+	}
+}
+
+/// Keep the value of "res", or add its error to "failures" as the error of "input"
+fn collect<T>(
+	failures: &mut Vec<(&'static str, crate::Error)>,
+	input: &'static str,
+	res: Result<T, crate::Error>,
+) -> Option<T> {
+	return match res {
+		Ok(v) => Some(v),
+		Err(err) => {
+			failures.push((input, err));
+
+			None
+		},
+	};
+}
+
+/// Run the "gather" stage, the cpu information is gathered while wine runs
+/// All inputs that could not be gathered are reported at once, see [crate::Error::Gather]
+pub fn gather() -> Result<GatherResult, crate::Error> {
+	let (snapshot, cpu_info) = std::thread::scope(|scope| {
+		let cpu_thread = scope.spawn(decrypt::get_cpu_info);
+
+		// the wine commands stay in order, so that "--replay" sees them like they were recorded
+		#[cfg(not(windows))]
+		{
+			crate::wine::check_version();
+			crate::wine::start_server();
+		}
+
+		// a single wine start for everything, the winapi-bin then reuses the running wineserver
+		let snapshot = decrypt::WineSnapshot::take();
+		let cpu_info = cpu_thread.join().unwrap_or_else(|_| {
+			return Err(crate::Error::other("Thread for the cpu information panicked"));
+		});
+
+		return (snapshot, cpu_info);
+	});
+
+	return match snapshot {
+		Ok(snapshot) => collect_gather(Ok(&snapshot), cpu_info),
+		Err(err) => collect_gather(Err(err), cpu_info),
+	};
+}
+
+/// Run the "gather" stage with a snapshot that was already taken (like by [crate::doctor::run_checks_with_snapshot]), without starting wine again
+pub fn gather_from_snapshot(snapshot: &decrypt::WineSnapshot) -> Result<GatherResult, crate::Error> {
+	return collect_gather(Ok(snapshot), decrypt::get_cpu_info());
+}
+
+/// Get the [GatherResult] from the "snapshot" and "cpu_info", or the error(s) of everything that failed
+fn collect_gather(
+	snapshot: Result<&decrypt::WineSnapshot, crate::Error>,
+	cpu_info: Result<CpuInfo, crate::Error>,
+) -> Result<GatherResult, crate::Error> {
+	let mut failures = Vec::new();
+
+	let cpu_info = collect(&mut failures, "cpu information", cpu_info);
+	let (drive_info, username, adept_infos) = match collect(&mut failures, "wine", snapshot) {
+		Some(snapshot) => (
+			collect(&mut failures, "drive information", snapshot.drive_info()),
+			collect(&mut failures, "windows username", snapshot.win_username()),
+			collect(&mut failures, "ADE activations", snapshot.all_adept_information()),
+		),
+		None => (None, None, None),
+	};
+
+	if let (Some(drive_info), Some(cpu_info), Some(username), Some(adept_infos)) =
+		(drive_info, cpu_info, username, adept_infos)
+	{
+		return Ok(GatherResult {
+			drive_info,
+			cpu_info,
+			username,
+			adept_infos,
+		});
+	}
+
+	// a single error is kept as it is, for its context and category
+	if failures.len() == 1 {
+		// safe to unwrap, because the length was checked
+		return Err(failures.pop().unwrap().1);
+	}
+
+	return Err(crate::Error::Gather(failures));
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::Arc;
+
+	use base64::Engine;
+	use pkcs8::der::Encode;
+
+	use super::*;
+	use crate::{
+		file::TempDir,
+		runner::{
+			CommandOutput,
+			FakeRunner,
+		},
+		test_util::{
+			success,
+			use_runner,
+			ADEPT_EXPORT,
+			SNAPSHOT_OUTPUT,
+		},
+	};
+
+	#[test]
+	fn gather_with_fake_runner() {
+		let _lock = crate::runner::lock_for_test();
+		let runner = use_runner(
+			FakeRunner::new()
+				.respond("--version", success("wine-9.0\n"))
+				.respond("wineserver", success(""))
+				.respond_with_file("reg export", success(SNAPSHOT_OUTPUT), ADEPT_EXPORT),
+		);
+
+		let res = gather();
+		crate::runner::set(Arc::new(crate::runner::ProcessRunner));
+		let res = res.unwrap();
+
+		assert_eq!(res.drive_info.win_system_drive, "C:");
+		assert_eq!(res.drive_info.win_system_volume_serial, 0x4300_0000);
+		// the username of Adept is preferred over the one of windows
+		assert_eq!(res.username, "adobeuser");
+		assert_eq!(res.adept_infos.len(), 1);
+
+		let info = &res.adept_infos[0];
+		assert_eq!(info.user, "urn:uuid:00000000-0000-0000-0000-000000000001");
+		assert_eq!(
+			info.username,
+			(String::from("AdobeID"), String::from("reader@example.com"))
+		);
+		assert_eq!(info.key.expose(), "cHJpdmF0ZQ==");
+		assert_eq!(info.device_key.expose(), "00112233ABCD");
+		assert_eq!(info.license_certificate, None);
+
+		let calls = runner.calls();
+		assert_eq!(calls.len(), 3, "{calls:?}");
+		assert!(calls[0].ends_with(" --version"), "{calls:?}");
+		assert!(calls[1].contains("wineserver"), "{calls:?}");
+		assert!(calls[2].contains(" cmd /c echo ade-extract-key-root="), "{calls:?}");
+	}
+
+	#[test]
+	fn gather_from_taken_snapshot() {
+		let _lock = crate::runner::lock_for_test();
+		let runner =
+			use_runner(FakeRunner::new().respond_with_file("reg export", success(SNAPSHOT_OUTPUT), ADEPT_EXPORT));
+
+		let snapshot = decrypt::WineSnapshot::take();
+		let res = snapshot.and_then(|v| return gather_from_snapshot(&v));
+		crate::runner::set(Arc::new(crate::runner::ProcessRunner));
+		let res = res.unwrap();
+
+		assert_eq!(res.username, "adobeuser");
+		assert_eq!(res.adept_infos.len(), 1);
+		// only the snapshot itself started wine
+		assert_eq!(runner.calls().len(), 1);
+	}
+
+	#[test]
+	fn gather_without_adept() {
+		let _lock = crate::runner::lock_for_test();
+		// "reg export" fails and writes no file, like when ADE was never started
+		let runner = use_runner(
+			FakeRunner::new()
+				.respond("--version", success("wine-9.0\n"))
+				.respond("wineserver", success(""))
+				.respond(
+					"reg export",
+					CommandOutput {
+						code:   Some(1),
+						stdout: SNAPSHOT_OUTPUT.to_owned(),
+						stderr: String::from("reg: Unable to find the specified registry key\n"),
+					},
+				),
+		);
+
+		let res = gather();
+		crate::runner::set(Arc::new(crate::runner::ProcessRunner));
+		let err = res.unwrap_err();
+
+		// the other inputs are still gathered, so only the activations are missing
+		assert!(
+			matches!(err, crate::Error::RegistryKeyNotFound("Adept", "key")),
+			"{err:?}"
+		);
+		assert_eq!(err.category(), crate::error::ErrorCategory::Registry);
+		assert_eq!(runner.calls().len(), 3);
+	}
+
+	#[test]
+	fn gather_wine_failure() {
+		let _lock = crate::runner::lock_for_test();
+		// the snapshot command is answered with a error and no markers
+		let runner = use_runner(FakeRunner::new().respond("wineserver", success("")));
+
+		let res = gather();
+		crate::runner::set(Arc::new(crate::runner::ProcessRunner));
+		let err = res.unwrap_err();
+
+		assert!(matches!(err, crate::Error::CommandStatus { .. }), "{err:?}");
+		assert_eq!(err.category(), crate::error::ErrorCategory::Environment);
+		assert_eq!(runner.calls().len(), 3);
+	}
+
+	/// A PKCS#1 DER key
+	const KEY: &[u8] = include_bytes!("../tests/fixtures/key.der");
+	/// The decrypted device key (hex), as the "dpapi" stage would return it
+	const DEVICE_KEY: &str = "000102030405060708090a0b0c0d0e0f";
+
+	/// Get a state with all stages except "aes" completed, whose activation decrypts to [KEY]
+	fn completed_state() -> PipelineState {
+		let private_key_info = pkcs8::PrivateKeyInfo::new(pkcs1::ALGORITHM_ID, KEY).to_der().unwrap();
+		let device_key = decrypt::decode_hex(DEVICE_KEY).unwrap();
+		let adept_key =
+			libaes::Cipher::new_128(device_key.as_slice().try_into().unwrap()).cbc_encrypt(&[0; 16], &private_key_info);
+
+		return PipelineState {
+			gather: Some(GatherResult {
+				drive_info:  DriveInfo {
+					win_system_drive:         String::from("C:"),
+					win_system_volume_serial: 0x4300_0000,
+				},
+				cpu_info:    CpuInfo {
+					cpu_vendor:       String::from("GenuineIntel"),
+					cpu_magic_number: vec![0x08, 0x06, 0xf8],
+				},
+				username:    String::from("winuser"),
+				adept_infos: vec![AdeptInformation {
+					user:                String::from("urn:uuid:0000-1111"),
+					username:            (String::from("AdobeID"), String::from("reader@example.com")),
+					key:                 SecretString::from(
+						base64::engine::general_purpose::STANDARD.encode(adept_key),
+					),
+					device_key:          SecretString::from("00112233ABCD"),
+					license_certificate: None,
+				}],
+			}),
+			entropy: Some(SecretString::from("e1e2e3")),
+			dpapi: Some(SecretString::from(DEVICE_KEY)),
+			..Default::default()
+		};
+	}
+
+	#[test]
+	fn save_load_resume() {
+		let dir = TempDir::new("ade-extract-key-test").unwrap();
+		let path = dir.path().join("state.json");
+
+		completed_state().save(&path).unwrap();
+
+		#[cfg(unix)]
+		{
+			use std::os::unix::fs::PermissionsExt;
+			// the state contains secrets, so only the current user may read it
+			assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+		}
+
+		let mut state = PipelineState::load(&path).unwrap();
+		assert_eq!(state.last_completed(), Some(Stage::Dpapi));
+		assert_eq!(state.dpapi.as_ref().unwrap().expose(), DEVICE_KEY);
+
+		// only the "aes" stage is left, which does not run any command
+		let keys = state
+			.run(|_, stage| panic!("Expected no stage to run, but \"{stage}\" did"))
+			.unwrap();
+		assert_eq!(keys.len(), 1);
+		assert_eq!(keys[0].expose(), KEY);
+
+		// the "aes" stage alone needs the "dpapi" stage
+		let mut state = completed_state();
+		state.dpapi = None;
+		assert_eq!(state.last_completed(), Some(Stage::Entropy));
+		assert_eq!(
+			state.run_aes().unwrap_err().category(),
+			crate::error::ErrorCategory::Usage
+		);
+	}
+
+	#[test]
+	fn load_invalid() {
+		let dir = TempDir::new("ade-extract-key-test").unwrap();
+		let path = dir.path().join("state.json");
+
+		// a state file of a other version
+		completed_state().save(&path).unwrap();
+		let content = std::fs::read_to_string(&path).unwrap();
+		std::fs::write(&path, content.replace("\"version\": 1", "\"version\": 2")).unwrap();
+		let err = PipelineState::load(&path).unwrap_err();
+		assert!(err.to_string().contains("Unsupported state file version 2"), "{err}");
+		assert_eq!(err.category(), crate::error::ErrorCategory::Usage);
+
+		std::fs::write(&path, "{").unwrap();
+		assert_eq!(
+			PipelineState::load(&path).unwrap_err().category(),
+			crate::error::ErrorCategory::Format
+		);
+
+		assert_eq!(
+			PipelineState::load(&dir.path().join("missing.json"))
+				.unwrap_err()
+				.category(),
+			crate::error::ErrorCategory::Io
+		);
+	}
+
+	/// Take a snapshot whose command prints "stdout" and exports "export" (if any), the runner lock has to be held
+	fn take_snapshot(stdout: &str, export: Option<&str>) -> decrypt::WineSnapshot {
+		let runner = match export {
+			Some(export) => FakeRunner::new().respond_with_file("reg export", success(stdout), export),
+			None => FakeRunner::new().respond("reg export", success(stdout)),
+		};
+		use_runner(runner);
+
+		let snapshot = decrypt::WineSnapshot::take();
+		crate::runner::set(Arc::new(crate::runner::ProcessRunner));
+
+		return snapshot.unwrap();
+	}
+
+	/// Get the cpu information gather would get
+	fn cpu_info() -> CpuInfo {
+		return CpuInfo {
+			cpu_vendor:       String::from("GenuineIntel"),
+			cpu_magic_number: vec![0x08, 0x06, 0xf8],
+		};
+	}
+
+	/// Get the inputs (in order) of the [crate::Error::Gather] "err"
+	fn failed_inputs(err: &crate::Error) -> Vec<&'static str> {
+		let crate::Error::Gather(failures) = err else {
+			panic!("Expected a Gather error, got {err:?}");
+		};
+
+		return failures.iter().map(|(input, _)| return *input).collect();
+	}
+
+	#[test]
+	fn gather_single_failure() {
+		let _lock = crate::runner::lock_for_test();
+		let snapshot = take_snapshot(SNAPSHOT_OUTPUT, Some(ADEPT_EXPORT));
+
+		// a single failure is passed through unchanged, with its own category
+		let err = collect_gather(Ok(&snapshot), Err(crate::Error::environment("no cpuid"))).unwrap_err();
+		assert!(
+			matches!(&err, crate::Error::Environment(msg) if msg == "no cpuid"),
+			"{err:?}"
+		);
+
+		let err = collect_gather(Err(crate::Error::no_adept_reg_key("key")), Ok(cpu_info())).unwrap_err();
+		assert!(
+			matches!(err, crate::Error::RegistryKeyNotFound("Adept", "key")),
+			"{err:?}"
+		);
+
+		assert_eq!(
+			collect_gather(Ok(&snapshot), Ok(cpu_info())).unwrap().username,
+			"adobeuser"
+		);
+	}
+
+	#[test]
+	fn gather_multiple_failures() {
+		let _lock = crate::runner::lock_for_test();
+		// only the system root, everything else is missing
+		let snapshot = take_snapshot("ade-extract-key-root=C:\\windows\r\n", None);
+
+		let err = collect_gather(Ok(&snapshot), Err(crate::Error::environment("no cpuid"))).unwrap_err();
+		assert_eq!(
+			failed_inputs(&err),
+			[
+				"cpu information",
+				"drive information",
+				"windows username",
+				"ADE activations"
+			]
+		);
+		// the first failure decides the category
+		assert_eq!(err.category(), crate::error::ErrorCategory::Environment);
+		let message = err.to_string();
+		assert!(message.starts_with("Failed to gather 4 inputs:"), "{message}");
+		assert!(message.contains("no cpuid"), "{message}");
+
+		// without the cpu failure, the order stays the same
+		let err = collect_gather(Ok(&snapshot), Ok(cpu_info())).unwrap_err();
+		assert_eq!(
+			failed_inputs(&err),
+			["drive information", "windows username", "ADE activations"]
+		);
+
+		// if wine failed, nothing from the snapshot is tried
+		let err = collect_gather(
+			Err(crate::Error::environment("no wine")),
+			Err(crate::Error::environment("no cpuid")),
+		)
+		.unwrap_err();
+		assert_eq!(failed_inputs(&err), ["cpu information", "wine"]);
+	}
+}
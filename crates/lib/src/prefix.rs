@@ -0,0 +1,126 @@
+//! Module for finding wine prefixes, to be able to choose the one ADE is installed in
+
+#[cfg(not(windows))]
+use std::path::Path;
+use std::path::PathBuf;
+
+/// Key in "user.reg" that exists once ADE was started in the prefix (backslashes are escaped in the file)
+#[cfg(not(windows))]
+const ADE_USER_REG_KEY: &str = r"[Software\\Adobe\\Adept\\Device]";
+
+/// A wine prefix that was found
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrefixCandidate {
+	pub path:    PathBuf,
+	/// If ADE was started in the prefix, only checked via "user.reg" without running wine
+	pub has_ade: bool,
+}
+
+/// Directories (relative to "HOME") that contain prefixes of common prefix managers
+#[cfg(not(windows))]
+const PREFIX_PARENT_DIRS: &[&str] = &[
+	// winetricks
+	".local/share/wineprefixes",
+	// PlayOnLinux
+	".PlayOnLinux/wineprefix",
+	// Bottles
+	".local/share/bottles/bottles",
+	// CrossOver on linux
+	".cxoffice",
+	// CrossOver on macos
+	"Library/Application Support/CrossOver/Bottles",
+];
+
+/// Get if "path" is a initialized wine prefix
+#[cfg(not(windows))]
+fn is_prefix(path: &Path) -> bool {
+	return path.join("system.reg").is_file();
+}
+
+/// Get if ADE was started in the prefix at "path"
+#[cfg(not(windows))]
+fn prefix_has_ade(path: &Path) -> bool {
+	return std::fs::read(path.join("user.reg")).is_ok_and(|v| {
+		return v
+			.windows(ADE_USER_REG_KEY.len())
+			.any(|v| return v == ADE_USER_REG_KEY.as_bytes());
+	});
+}
+
+/// Find wine prefixes in the common locations (the current prefix, "~/.wine" and the directories of prefix managers)
+/// Always empty on windows, because there are no prefixes
+pub fn discover_prefixes() -> Vec<PrefixCandidate> {
+	#[cfg(windows)]
+	{
+		return Vec::new();
+	}
+
+	#[cfg(not(windows))]
+	{
+		let mut paths: Vec<PathBuf> = Vec::new();
+
+		if let Some(prefix) = crate::decrypt::wine_prefix() {
+			paths.push(prefix);
+		}
+
+		if let Some(home) = std::env::var_os("HOME").map(PathBuf::from) {
+			paths.push(home.join(".wine"));
+
+			for parent in PREFIX_PARENT_DIRS {
+				let Ok(entries) = std::fs::read_dir(home.join(parent)) else {
+					continue;
+				};
+
+				let mut children: Vec<PathBuf> =
+					entries.filter_map(|v| return v.ok().map(|v| return v.path())).collect();
+				children.sort();
+				paths.extend(children);
+			}
+		}
+
+		let mut found: Vec<PrefixCandidate> = Vec::new();
+
+		for path in paths {
+			if !is_prefix(&path) || found.iter().any(|v| return v.path == path) {
+				continue;
+			}
+
+			found.push(PrefixCandidate {
+				has_ade: prefix_has_ade(&path),
+				path,
+			});
+		}
+
+		return found;
+	}
+}
+
+// the prefix detection only exists outside of windows
+#[cfg(test)]
+#[cfg(not(windows))]
+mod tests {
+	use super::*;
+	use crate::file::TempDir;
+
+	#[test]
+	fn prefix_detection() {
+		let dir = TempDir::new("ade-extract-key-test").unwrap();
+		let prefix = dir.path();
+
+		// a directory without "system.reg" is not initialized yet
+		assert!(!is_prefix(prefix));
+		assert!(!prefix_has_ade(prefix));
+
+		std::fs::write(prefix.join("system.reg"), "WINE REGISTRY Version 2\n").unwrap();
+		std::fs::write(prefix.join("user.reg"), "WINE REGISTRY Version 2\n[Software\\\\Wine]\n").unwrap();
+		assert!(is_prefix(prefix));
+		assert!(!prefix_has_ade(prefix));
+
+		std::fs::write(
+			prefix.join("user.reg"),
+			"WINE REGISTRY Version 2\n[Software\\\\Adobe\\\\Adept\\\\Device] 1700000000\n\"key\"=hex:00,11\n",
+		)
+		.unwrap();
+		assert!(prefix_has_ade(prefix));
+	}
+}
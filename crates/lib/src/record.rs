@@ -0,0 +1,366 @@
+//! Module for recording all commands of a run into a bundle (see [RecordingRunner]) and running against a bundle again without running anything (see [ReplayRunner])
+//! Bundles are JSON, so that they can be checked (and further redacted) before being shared
+
+use std::{
+	collections::BTreeMap,
+	path::Path,
+	process::Command,
+	sync::Mutex,
+};
+
+use base64::Engine;
+use serde::{
+	Deserialize,
+	Serialize,
+};
+use zeroize::Zeroizing;
+
+use crate::runner::{
+	command_line,
+	CommandOutput,
+	CommandRunner,
+};
+
+/// Version of the bundle format, bundles with a different version are rejected
+pub const BUNDLE_VERSION: u32 = 1;
+
+/// Replacement for redacted secrets
+const REDACTED: &str = "<redacted>";
+
+/// Replacement for the home directory, so that bundles do not contain the user name and replay on other machines
+const HOME_PLACEHOLDER: &str = "$HOME";
+
+/// Replace the home directory in "value" with [HOME_PLACEHOLDER]
+fn normalize_home(value: &str) -> String {
+	let home = std::env::var("HOME").unwrap_or_default();
+
+	// a home of "/" would replace every path separator
+	if home.len() <= 1 {
+		return value.to_owned();
+	}
+
+	return value.replace(home.trim_end_matches('/'), HOME_PLACEHOLDER);
+}
+
+/// Check if the output of "command" carries a secret:
+/// the stdout of the winapi-bin (the decrypted device key, its stdin has the device key and the entropy),
+/// the registry export (the device key, the private license keys and the certificates)
+/// and the stdout of the snapshot (the volume serial and the windows username, which are part of the entropy)
+fn has_secret_output(command: &RecordedCommand) -> bool {
+	let stdout = command
+		.output
+		.as_ref()
+		.map(|v| return v.stdout.as_str())
+		.unwrap_or_default();
+
+	return command.input.is_some()
+		|| command.output_file.is_some()
+		|| stdout.contains(crate::decrypt::SYSTEM_ROOT_MARKER)
+		|| stdout.contains(crate::decrypt::USERNAME_MARKER);
+}
+
+/// A single command that was run
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordedCommand {
+	/// The command line, see [command_line]
+	pub cmd:         String,
+	/// The environment variables set ("None" for removed) for the command, on top of the environment of the run
+	#[serde(default)]
+	pub env:         BTreeMap<String, Option<String>>,
+	/// What was written to stdin
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub input:       Option<String>,
+	/// The output, "None" if the command did not run to completion (see "error")
+	#[serde(default)]
+	pub output:      Option<CommandOutput>,
+	/// Why the command did not run to completion (like a timeout)
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub error:       Option<String>,
+	/// The content (base64) of the file the command wrote, see [CommandRunner::run_with_output_file]
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub output_file: Option<String>,
+	/// If stdin and stdout were removed, because they contain secrets
+	#[serde(default)]
+	pub redacted:    bool,
+}
+
+impl RecordedCommand {
+	/// Get the output of the command like it was recorded, or its error
+	fn replay_output(&self) -> Result<CommandOutput, crate::Error> {
+		return match (&self.output, &self.error) {
+			(Some(output), _) => Ok(output.clone()),
+			(None, Some(error)) => Err(crate::Error::environment(error.clone())),
+			(None, None) => Err(crate::Error::usage(format!(
+				"\"{}\" has neither a output nor a error in the bundle",
+				self.cmd
+			))),
+		};
+	}
+}
+
+/// All commands of a run, in the order they were run
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Bundle {
+	pub version:  u32,
+	pub commands: Vec<RecordedCommand>,
+}
+
+impl Default for Bundle {
+	fn default() -> Self {
+		return Self {
+			version:  BUNDLE_VERSION,
+			commands: Vec::new(),
+		};
+	}
+}
+
+impl Bundle {
+	/// Load a bundle from the file at "path"
+	pub fn load(path: &Path) -> Result<Self, crate::Error> {
+		let content = Zeroizing::new(
+			std::fs::read(path)
+				.map_err(|err| return crate::Error::io(format!("Failed to read \"{}\"", path.display()), err))?,
+		);
+		let bundle: Self = serde_json::from_slice(&content)
+			.map_err(|err| return crate::Error::parse(format!("bundle \"{}\"", path.display()), err))?;
+
+		if bundle.version != BUNDLE_VERSION {
+			return Err(crate::Error::usage(format!(
+				"Unsupported bundle version {} in \"{}\"",
+				bundle.version,
+				path.display()
+			)));
+		}
+
+		return Ok(bundle);
+	}
+
+	/// Save the bundle to the file at "path", which is only readable by the current user, because it may contain secrets
+	pub fn save(&self, path: &Path) -> Result<(), crate::Error> {
+		let mut content = Zeroizing::new(
+			serde_json::to_vec_pretty(self)
+				.map_err(|source| return crate::Error::Serialize { what: "bundle", source })?,
+		);
+		content.push(b'\n');
+
+		crate::file::write_atomic(path, &content)?;
+
+		return Ok(());
+	}
+
+	/// Remove the secrets from the bundle: stdin, stdout and the written file of commands whose output carries a secret (see [has_secret_output]) and the home directory
+	/// A redacted bundle can only be replayed up to the first redacted command
+	pub fn redact(&mut self) {
+		for command in &mut self.commands {
+			command.cmd = normalize_home(&command.cmd);
+
+			for value in command.env.values_mut().flatten() {
+				*value = normalize_home(value);
+			}
+
+			if let Some(output) = &mut command.output {
+				output.stdout = normalize_home(&output.stdout);
+				output.stderr = normalize_home(&output.stderr);
+			}
+
+			if !has_secret_output(command) {
+				continue;
+			}
+
+			if command.input.is_some() {
+				command.input = Some(String::from(REDACTED));
+			}
+
+			if command.output_file.is_some() {
+				command.output_file = Some(String::from(REDACTED));
+			}
+
+			if let Some(output) = &mut command.output {
+				output.stdout = String::from(REDACTED);
+			}
+
+			command.redacted = true;
+		}
+	}
+}
+
+/// Runs commands with another runner and records them into a [Bundle]
+#[derive(Debug, Default)]
+pub struct RecordingRunner<R> {
+	inner:    R,
+	commands: Mutex<Vec<RecordedCommand>>,
+}
+
+impl<R: CommandRunner> RecordingRunner<R> {
+	#[must_use]
+	pub fn new(inner: R) -> Self {
+		return Self {
+			inner,
+			commands: Mutex::new(Vec::new()),
+		};
+	}
+
+	/// Get all commands recorded so far as a [Bundle]
+	#[must_use]
+	pub fn bundle(&self) -> Bundle {
+		return Bundle {
+			commands: self
+				.commands
+				.lock()
+				.expect("Expected commands lock to not be poisoned")
+				.clone(),
+			..Default::default()
+		};
+	}
+
+	/// Run "cmd" with "run" and record it, with the content of the output file if "run" returns one
+	fn record<F>(
+		&self,
+		cmd: Command,
+		input: Option<&str>,
+		run: F,
+	) -> Result<(CommandOutput, Option<Vec<u8>>), crate::Error>
+	where
+		F: FnOnce(Command) -> Result<(CommandOutput, Option<Vec<u8>>), crate::Error>,
+	{
+		let cmd_line = command_line(&cmd);
+		let env = cmd
+			.get_envs()
+			.map(|(key, value)| {
+				return (
+					key.to_string_lossy().into_owned(),
+					value.map(|v| return v.to_string_lossy().into_owned()),
+				);
+			})
+			.collect();
+
+		let res = run(cmd);
+
+		self.commands
+			.lock()
+			.expect("Expected commands lock to not be poisoned")
+			.push(RecordedCommand {
+				cmd: cmd_line,
+				env,
+				input: input.map(ToOwned::to_owned),
+				output: res.as_ref().ok().map(|(v, _)| return v.clone()),
+				error: res.as_ref().err().map(|v| return crate::error::display_chain(v)),
+				output_file: res
+					.as_ref()
+					.ok()
+					.and_then(|(_, v)| return v.as_ref())
+					.map(|v| return base64::engine::general_purpose::STANDARD.encode(v)),
+				redacted: false,
+			});
+
+		return res;
+	}
+}
+
+impl<R: CommandRunner> CommandRunner for RecordingRunner<R> {
+	fn run(&self, cmd: Command, input: Option<&str>) -> Result<CommandOutput, crate::Error> {
+		return self
+			.record(cmd, input, |cmd| return Ok((self.inner.run(cmd, input)?, None)))
+			.map(|(v, _)| return v);
+	}
+
+	fn run_with_output_file(
+		&self,
+		cmd: Command,
+		input: Option<&str>,
+		output_file: &Path,
+	) -> Result<(CommandOutput, Option<Vec<u8>>), crate::Error> {
+		return self.record(cmd, input, |cmd| {
+			return self.inner.run_with_output_file(cmd, input, output_file);
+		});
+	}
+
+	fn runs_processes(&self) -> bool {
+		return self.inner.runs_processes();
+	}
+}
+
+/// Runs nothing, but answers every command with the next command of a [Bundle]
+/// The command lines have to be the same as in the bundle (apart from the home directory), otherwise the replay has diverged
+#[derive(Debug)]
+pub struct ReplayRunner {
+	commands: Vec<RecordedCommand>,
+	/// Index of the next command in "commands"
+	next:     Mutex<usize>,
+}
+
+impl ReplayRunner {
+	#[must_use]
+	pub fn new(bundle: Bundle) -> Self {
+		return Self {
+			commands: bundle.commands,
+			next:     Mutex::new(0),
+		};
+	}
+
+	/// Get the next recorded command, which has to be "cmd"
+	fn next(&self, cmd: &Command) -> Result<&RecordedCommand, crate::Error> {
+		let cmd_line = command_line(cmd);
+		let mut next = self.next.lock().expect("Expected next lock to not be poisoned");
+
+		let Some(recorded) = self.commands.get(*next) else {
+			return Err(crate::Error::usage(format!(
+				"Replay diverged: \"{cmd_line}\" was run, but all {} recorded commands were already used",
+				self.commands.len()
+			)));
+		};
+
+		if normalize_home(&recorded.cmd) != normalize_home(&cmd_line) {
+			return Err(crate::Error::usage(format!(
+				"Replay diverged at command {}: expected \"{}\", but \"{cmd_line}\" was run",
+				*next + 1,
+				recorded.cmd
+			)));
+		}
+
+		*next += 1;
+		trace!("Replaying \"{cmd_line}\"");
+
+		if recorded.redacted {
+			return Err(crate::Error::usage(format!(
+				"\"{cmd_line}\" was redacted in the bundle and cannot be replayed"
+			)));
+		}
+
+		return Ok(recorded);
+	}
+}
+
+impl CommandRunner for ReplayRunner {
+	fn run(&self, cmd: Command, _input: Option<&str>) -> Result<CommandOutput, crate::Error> {
+		return self.next(&cmd)?.replay_output();
+	}
+
+	fn run_with_output_file(
+		&self,
+		cmd: Command,
+		_input: Option<&str>,
+		_output_file: &Path,
+	) -> Result<(CommandOutput, Option<Vec<u8>>), crate::Error> {
+		let recorded = self.next(&cmd)?;
+
+		let content = recorded
+			.output_file
+			.as_ref()
+			.map(|v| {
+				return base64::engine::general_purpose::STANDARD.decode(v).map_err(|err| {
+					return crate::Error::usage(format!(
+						"Invalid output file of \"{}\" in the bundle: {err}",
+						recorded.cmd
+					));
+				});
+			})
+			.transpose()?;
+
+		return Ok((recorded.replay_output()?, content));
+	}
+
+	fn runs_processes(&self) -> bool {
+		return false;
+	}
+}
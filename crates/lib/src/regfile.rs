@@ -0,0 +1,385 @@
+//! Module for parsing registry exports (".reg" files, like written by "reg export")
+//! Only what is needed to read values is supported, deletions ("[-KEY]" and "=-") are ignored
+
+use std::collections::BTreeMap;
+
+/// A value of a registry key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RegValue {
+	/// "REG_SZ", like "\"value\""
+	String(String),
+	/// "REG_DWORD", like "dword:0000002a"
+	Dword(u32),
+	/// "REG_BINARY", like "hex:00,ff"
+	Binary(Vec<u8>),
+	/// Any other type, like "hex(7):..." for "REG_MULTI_SZ"
+	Other { kind: u32, data: Vec<u8> },
+}
+
+/// A single registry key with its values
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RegKey {
+	/// The full path, like "HKEY_CURRENT_USER\Software\Adobe\Adept"
+	pub path: String,
+	/// The values in the order of the export, the default value has the name ""
+	values:   Vec<(String, RegValue)>,
+}
+
+impl RegKey {
+	/// Get the last component of the path, like "0000"
+	#[must_use]
+	pub fn name(&self) -> &str {
+		return self.path.rsplit('\\').next().unwrap_or_default();
+	}
+
+	/// Get the value "name" (case-insensitive), "" for the default value
+	#[must_use]
+	pub fn value(&self, name: &str) -> Option<&RegValue> {
+		return self
+			.values
+			.iter()
+			.find(|(v, _)| return v.eq_ignore_ascii_case(name))
+			.map(|(_, v)| return v);
+	}
+
+	/// Get the value "name" if it is a "REG_SZ"
+	#[must_use]
+	pub fn string(&self, name: &str) -> Option<&str> {
+		return match self.value(name)? {
+			RegValue::String(v) => Some(v),
+			_ => None,
+		};
+	}
+
+	/// Get the value "name" if it is a "REG_BINARY"
+	#[must_use]
+	pub fn binary(&self, name: &str) -> Option<&[u8]> {
+		return match self.value(name)? {
+			RegValue::Binary(v) => Some(v),
+			_ => None,
+		};
+	}
+}
+
+/// A parsed registry export
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RegExport {
+	/// All keys, by their lowercase path (paths are case-insensitive)
+	keys: BTreeMap<String, RegKey>,
+}
+
+impl RegExport {
+	/// Parse a export from its raw bytes, which are UTF-16LE with a BOM ("reg export" of windows and wine) or UTF-8
+	pub fn from_bytes(bytes: &[u8]) -> Result<Self, crate::Error> {
+		if let Some(utf16) = bytes.strip_prefix(&[0xff, 0xfe]) {
+			let units: Vec<u16> = utf16
+				.chunks_exact(2)
+				.map(|v| return u16::from_le_bytes([v[0], v[1]]))
+				.collect();
+			let content = zeroize::Zeroizing::new(
+				String::from_utf16(&units).map_err(|_| return malformed("Export is not valid UTF-16"))?,
+			);
+
+			return Self::parse(&content);
+		}
+
+		let bytes = bytes.strip_prefix(&[0xef, 0xbb, 0xbf]).unwrap_or(bytes);
+
+		return Self::parse(std::str::from_utf8(bytes).map_err(|_| return malformed("Export is not valid UTF-8"))?);
+	}
+
+	/// Parse a export from its text
+	pub fn parse(content: &str) -> Result<Self, crate::Error> {
+		let mut lines = content.lines();
+
+		match lines.next().map(|v| return v.trim_start_matches('\u{feff}').trim()) {
+			Some("Windows Registry Editor Version 5.00" | "REGEDIT4") => (),
+			_ => return Err(malformed("Missing the export header")),
+		}
+
+		let mut export = Self::default();
+		let mut current: Option<String> = None;
+
+		while let Some(line) = lines.next() {
+			let mut line = line.trim().to_owned();
+
+			// long values (mostly "hex:") are continued on the next line
+			while line.ends_with('\\') {
+				line.pop();
+				line.push_str(lines.next().unwrap_or_default().trim());
+			}
+
+			if line.is_empty() || line.starts_with(';') {
+				continue;
+			}
+
+			if let Some(path) = line.strip_prefix('[').and_then(|v| return v.strip_suffix(']')) {
+				if path.starts_with('-') {
+					current = None;
+					continue;
+				}
+
+				let lower = path.to_lowercase();
+				export.keys.entry(lower.clone()).or_insert_with(|| {
+					return RegKey {
+						path:   path.to_owned(),
+						values: Vec::new(),
+					};
+				});
+				current = Some(lower);
+
+				continue;
+			}
+
+			let Some(key) = current.as_ref().and_then(|v| return export.keys.get_mut(v)) else {
+				// values of deleted keys, or before the first key
+				continue;
+			};
+
+			let (name, data) = parse_value_line(&line)?;
+
+			if let Some(value) = parse_data(data)? {
+				key.values.push((name, value));
+			}
+		}
+
+		return Ok(export);
+	}
+
+	/// Get the key at "path" (case-insensitive)
+	#[must_use]
+	pub fn key(&self, path: &str) -> Option<&RegKey> {
+		return self.keys.get(&path.to_lowercase());
+	}
+
+	/// Get the direct sub-keys of "path", sorted by their path
+	pub fn subkeys<'a>(&'a self, path: &str) -> impl Iterator<Item = &'a RegKey> + 'a {
+		let prefix = format!("{}\\", path.to_lowercase());
+
+		return self
+			.keys
+			.iter()
+			.filter(move |(v, _)| return v.strip_prefix(&prefix).is_some_and(|v| return !v.contains('\\')))
+			.map(|(_, v)| return v);
+	}
+}
+
+/// Error for a malformed export
+fn malformed(msg: impl Into<String>) -> crate::Error {
+	return crate::Error::RegistryExport(msg.into());
+}
+
+/// Parse a quoted string at the start of "input", returns the unescaped string and the rest after the closing quote
+fn parse_quoted(input: &str) -> Result<(String, &str), crate::Error> {
+	let Some(input) = input.strip_prefix('"') else {
+		return Err(malformed(format!("Expected a quoted string at \"{input}\"")));
+	};
+
+	let mut out = String::new();
+	let mut chars = input.char_indices();
+
+	while let Some((i, c)) = chars.next() {
+		match c {
+			'"' => return Ok((out, &input[i + 1..])),
+			'\\' => match chars.next() {
+				Some((_, 'n')) => out.push('\n'),
+				Some((_, 'r')) => out.push('\r'),
+				Some((_, 't')) => out.push('\t'),
+				Some((_, '0')) => out.push('\0'),
+				Some((_, c)) => out.push(c),
+				None => break,
+			},
+			c => out.push(c),
+		}
+	}
+
+	return Err(malformed("Unterminated quoted string"));
+}
+
+/// Split a value line (like "\"name\"=dword:00000001") into the name ("" for "@") and the data
+fn parse_value_line(line: &str) -> Result<(String, &str), crate::Error> {
+	let (name, rest) = if let Some(rest) = line.strip_prefix('@') {
+		(String::new(), rest)
+	} else {
+		parse_quoted(line)?
+	};
+
+	let Some(data) = rest.trim_start().strip_prefix('=') else {
+		return Err(malformed(format!("Expected \"=\" after value \"{name}\"")));
+	};
+
+	return Ok((name, data.trim_start()));
+}
+
+/// Parse comma separated hex bytes, like "00,ff"
+fn parse_hex_bytes(input: &str) -> Result<Vec<u8>, crate::Error> {
+	return input
+		.split(',')
+		.map(str::trim)
+		.filter(|v| return !v.is_empty())
+		.map(|v| return u8::from_str_radix(v, 16).map_err(|_| return malformed(format!("Invalid hex byte \"{v}\""))))
+		.collect();
+}
+
+/// Parse the data of a value, "None" for a deletion ("-")
+fn parse_data(data: &str) -> Result<Option<RegValue>, crate::Error> {
+	if data == "-" {
+		return Ok(None);
+	}
+
+	if data.starts_with('"') {
+		return Ok(Some(RegValue::String(parse_quoted(data)?.0)));
+	}
+
+	if let Some(dword) = data.strip_prefix("dword:") {
+		return u32::from_str_radix(dword.trim(), 16)
+			.map(|v| return Some(RegValue::Dword(v)))
+			.map_err(|_| return malformed(format!("Invalid dword \"{dword}\"")));
+	}
+
+	if let Some(hex) = data.strip_prefix("hex:") {
+		return Ok(Some(RegValue::Binary(parse_hex_bytes(hex)?)));
+	}
+
+	if let Some(rest) = data.strip_prefix("hex(") {
+		let Some((kind, hex)) = rest.split_once("):") else {
+			return Err(malformed(format!("Invalid value \"{data}\"")));
+		};
+		let kind = u32::from_str_radix(kind, 16).map_err(|_| return malformed(format!("Invalid type \"{kind}\"")))?;
+
+		return Ok(Some(RegValue::Other {
+			kind,
+			data: parse_hex_bytes(hex)?,
+		}));
+	}
+
+	return Err(malformed(format!("Unknown value \"{data}\"")));
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const EXPORT: &str = r#"Windows Registry Editor Version 5.00
+
+[HKEY_CURRENT_USER\Software\Adobe\Adept]
+
+[HKEY_CURRENT_USER\Software\Adobe\Adept\Device]
+"username"="user \"quoted\" \\ name"
+"key"=hex:00,01,02,\
+  03,04
+@="default"
+"count"=dword:0000002a
+
+[HKEY_CURRENT_USER\Software\Adobe\Adept\Activation\0000]
+@="credentials"
+"multi"=hex(7):61,00,00,00
+"removed"=-
+
+[-HKEY_CURRENT_USER\Software\Adobe\Adept\Deleted]
+"ignored"="value"
+
+[HKEY_CURRENT_USER\Software\Adobe\Adept\Activation\0000\0001]
+@="user"
+"#;
+
+	/// Encode "content" like "reg export" does, UTF-16LE with a BOM
+	fn utf16(content: &str) -> Vec<u8> {
+		return [0xff, 0xfe]
+			.into_iter()
+			.chain(content.encode_utf16().flat_map(u16::to_le_bytes))
+			.collect();
+	}
+
+	#[test]
+	fn utf16_and_utf8() {
+		let expected = RegExport::parse(EXPORT).unwrap();
+
+		assert_eq!(RegExport::from_bytes(&utf16(EXPORT)).unwrap(), expected);
+		assert_eq!(RegExport::from_bytes(EXPORT.as_bytes()).unwrap(), expected);
+		// with a UTF-8 BOM
+		assert_eq!(
+			RegExport::from_bytes(&[&[0xef, 0xbb, 0xbf], EXPORT.as_bytes()].concat()).unwrap(),
+			expected
+		);
+	}
+
+	#[test]
+	fn values() {
+		let export = RegExport::parse(EXPORT).unwrap();
+		let device = export.key(r"hkey_current_user\software\adobe\adept\device").unwrap();
+
+		assert_eq!(device.name(), "Device");
+		assert_eq!(device.string("USERNAME"), Some(r#"user "quoted" \ name"#));
+		assert_eq!(device.string(""), Some("default"));
+		assert_eq!(device.value("count"), Some(&RegValue::Dword(42)));
+		assert_eq!(device.string("count"), None);
+	}
+
+	#[test]
+	fn line_continuation() {
+		let export = RegExport::parse(EXPORT).unwrap();
+		let device = export.key(r"HKEY_CURRENT_USER\Software\Adobe\Adept\Device").unwrap();
+
+		assert_eq!(device.binary("key"), Some([0, 1, 2, 3, 4].as_slice()));
+	}
+
+	#[test]
+	fn other_type_and_deleted_value() {
+		let export = RegExport::parse(EXPORT).unwrap();
+		let activation = export
+			.key(r"HKEY_CURRENT_USER\Software\Adobe\Adept\Activation\0000")
+			.unwrap();
+
+		assert_eq!(
+			activation.value("multi"),
+			Some(&RegValue::Other {
+				kind: 7,
+				data: vec![0x61, 0, 0, 0],
+			})
+		);
+		assert_eq!(activation.value("removed"), None);
+	}
+
+	#[test]
+	fn deleted_key() {
+		let export = RegExport::parse(EXPORT).unwrap();
+
+		assert!(export.key(r"HKEY_CURRENT_USER\Software\Adobe\Adept\Deleted").is_none());
+		assert_eq!(
+			export
+				.subkeys(r"HKEY_CURRENT_USER\Software\Adobe\Adept")
+				.map(RegKey::name)
+				.collect::<Vec<_>>(),
+			["Device"]
+		);
+		assert_eq!(
+			export
+				.subkeys(r"HKEY_CURRENT_USER\Software\Adobe\Adept\Activation\0000")
+				.map(RegKey::name)
+				.collect::<Vec<_>>(),
+			["0001"]
+		);
+	}
+
+	#[test]
+	fn malformed() {
+		for content in [
+			"",
+			"[HKEY_CURRENT_USER\\Software]\n",
+			"REGEDIT4\n[KEY]\n\"unterminated=\"value\n",
+			"REGEDIT4\n[KEY]\n\"name\"\"value\"\n",
+			"REGEDIT4\n[KEY]\n\"name\"=hex:0g\n",
+			"REGEDIT4\n[KEY]\n\"name\"=dword:xyz\n",
+			"REGEDIT4\n[KEY]\n\"name\"=unknown\n",
+		] {
+			assert!(
+				matches!(RegExport::parse(content), Err(crate::Error::RegistryExport(_))),
+				"{content:?}"
+			);
+		}
+
+		assert!(RegExport::from_bytes(&[0xff, 0xfe, 0x00, 0xd8]).is_err());
+		assert!(RegExport::from_bytes(&[0xc3, 0x28]).is_err());
+	}
+}
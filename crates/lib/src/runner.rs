@@ -0,0 +1,589 @@
+//! Module for running external commands (wine, "reg", the winapi-bin), with a timeout, exit status checks and the stderr kept for errors
+//! The runner is process-wide like [crate::settings], so that it can be replaced (like with [FakeRunner])
+
+use std::{
+	io::{
+		Read,
+		Write,
+	},
+	path::Path,
+	process::{
+		Command,
+		Stdio,
+	},
+	sync::{
+		mpsc,
+		Arc,
+		Mutex,
+		RwLock,
+	},
+	time::{
+		Duration,
+		Instant,
+	},
+};
+
+use once_cell::sync::Lazy;
+use serde::{
+	Deserialize,
+	Serialize,
+};
+
+/// Timeout for a single command if none is configured, long enough for wine to set up a new prefix
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// How often a running command is checked for having exited
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How many lines of stderr are kept for errors, wine can be very verbose
+const STDERR_MAX_LINES: usize = 10;
+
+/// How long stdout / stderr are read after a command exited, processes it started (like "wineserver") may keep them open
+const PIPE_GRACE: Duration = Duration::from_millis(500);
+
+/// The output of a command that ran to completion
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CommandOutput {
+	/// The exit code, "None" if the command was terminated by a signal
+	pub code:   Option<i32>,
+	pub stdout: String,
+	pub stderr: String,
+}
+
+impl CommandOutput {
+	/// Get if the command exited with code "0"
+	#[must_use]
+	pub fn success(&self) -> bool {
+		return self.code == Some(0);
+	}
+}
+
+/// Get the command line of "cmd" (program and arguments), for logs and errors
+#[must_use]
+pub fn command_line(cmd: &Command) -> String {
+	return std::iter::once(cmd.get_program())
+		.chain(cmd.get_args())
+		.map(|v| return v.to_string_lossy())
+		.collect::<Vec<_>>()
+		.join(" ");
+}
+
+/// Get the last lines of "stderr", which are the most likely to contain the reason of a failure
+pub(crate) fn stderr_tail(stderr: &str) -> String {
+	let lines: Vec<&str> = stderr.trim().lines().collect();
+
+	return lines[lines.len().saturating_sub(STDERR_MAX_LINES)..].join("\n");
+}
+
+/// Something that can run external commands
+pub trait CommandRunner: Send + Sync {
+	/// Run "cmd" to completion, with "input" written to its stdin (otherwise stdin is empty)
+	/// Only fails if the command could not be run at all, see [CommandRunner::run_checked] to also check the exit code
+	fn run(&self, cmd: Command, input: Option<&str>) -> Result<CommandOutput, crate::Error>;
+
+	/// Like [CommandRunner::run], but a exit code other than "0" is a error, which contains the end of stderr
+	fn run_checked(&self, cmd: Command, input: Option<&str>) -> Result<CommandOutput, crate::Error> {
+		let cmd_line = command_line(&cmd);
+		let output = self.run(cmd, input)?;
+
+		if !output.success() {
+			return Err(crate::Error::CommandStatus {
+				cmd:    cmd_line,
+				code:   output.code,
+				stderr: stderr_tail(&output.stderr),
+			});
+		}
+
+		return Ok(output);
+	}
+
+	/// Like [CommandRunner::run], but also get the content of "output_file", which the command is expected to write (like "reg export")
+	/// The content is "None" if the command did not write the file
+	fn run_with_output_file(
+		&self,
+		cmd: Command,
+		input: Option<&str>,
+		output_file: &Path,
+	) -> Result<(CommandOutput, Option<Vec<u8>>), crate::Error> {
+		let output = self.run(cmd, input)?;
+
+		let content = match std::fs::read(output_file) {
+			Ok(v) => Some(v),
+			Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+			Err(err) => {
+				return Err(crate::Error::OutputFile {
+					path:   output_file.to_owned(),
+					source: err,
+				})
+			},
+		};
+
+		return Ok((output, content));
+	}
+
+	/// Get if commands are actually run, otherwise files they need (like the winapi-bin) do not have to exist
+	fn runs_processes(&self) -> bool {
+		return true;
+	}
+}
+
+/// A pipe (stdout / stderr) that is read in a separate thread, see [spawn_reader]
+struct PipeReader {
+	/// Everything that was read so far
+	buf:  Arc<Mutex<Vec<u8>>>,
+	/// Receives the result once the pipe is closed (EOF) or reading it failed
+	done: mpsc::Receiver<std::io::Result<()>>,
+}
+
+impl PipeReader {
+	/// Wait until "deadline" for the pipe to be closed and get everything read from it
+	/// The returned "bool" is "false" if the pipe was still open at the "deadline", then the output is only what was read so far
+	fn finish(self, deadline: Instant) -> std::io::Result<(Vec<u8>, bool)> {
+		let closed = match self
+			.done
+			.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+		{
+			Ok(res) => {
+				res?;
+				true
+			},
+			Err(_) => false,
+		};
+
+		// the lock cannot be poisoned, because the reader thread cannot panic while holding it
+		let buf = std::mem::take(&mut *self.buf.lock().expect("Expected pipe buffer lock to not be poisoned"));
+
+		return Ok((buf, closed));
+	}
+}
+
+/// Read all of "source" in a separate thread, everything read is kept in the buffer of the returned [PipeReader]
+/// Reading in threads keeps a command from blocking on a full stdout / stderr pipe
+fn spawn_reader<R: Read + Send + 'static>(mut source: R) -> PipeReader {
+	let buf = Arc::new(Mutex::new(Vec::new()));
+	let (sender, done) = mpsc::channel();
+	let thread_buf = Arc::clone(&buf);
+
+	std::thread::spawn(move || {
+		let mut chunk = [0u8; 8192];
+
+		let res = loop {
+			match source.read(&mut chunk) {
+				Ok(0) => break Ok(()),
+				Ok(n) => thread_buf
+					.lock()
+					.expect("Expected pipe buffer lock to not be poisoned")
+					.extend_from_slice(&chunk[..n]),
+				Err(err) if err.kind() == std::io::ErrorKind::Interrupted => (),
+				Err(err) => break Err(err),
+			}
+		};
+
+		// the receiver may already be gone after a timeout
+		let _ = sender.send(res);
+	});
+
+	return PipeReader { buf, done };
+}
+
+/// Runs commands as actual processes, with the timeout from [crate::settings]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProcessRunner;
+
+impl ProcessRunner {
+	/// Get the timeout for a single command, "None" if there is none
+	fn timeout() -> Option<Duration> {
+		let timeout = crate::settings::get().timeout.unwrap_or(DEFAULT_TIMEOUT);
+
+		if timeout.is_zero() {
+			return None;
+		}
+
+		return Some(timeout);
+	}
+}
+
+impl CommandRunner for ProcessRunner {
+	fn run(&self, mut cmd: Command, input: Option<&str>) -> Result<CommandOutput, crate::Error> {
+		let timeout = Self::timeout();
+		let cmd_line = command_line(&cmd);
+		trace!("Running \"{cmd_line}\"");
+
+		let mut child = cmd
+			.stdin(if input.is_some() { Stdio::piped() } else { Stdio::null() })
+			.stdout(Stdio::piped())
+			.stderr(Stdio::piped())
+			.spawn()
+			.map_err(|err| return crate::Error::command_failed(&cmd, err))?;
+
+		// safe to unwrap, because they were set to "piped" above
+		let stdout = spawn_reader(child.stdout.take().unwrap());
+		let stderr = spawn_reader(child.stderr.take().unwrap());
+
+		if let Some(input) = input {
+			// scoped, so that stdin gets closed after writing and the command sees EOF
+			let mut stdin = child.stdin.take().unwrap();
+			stdin
+				.write_all(input.as_bytes())
+				.map_err(|err| return crate::Error::command_failed(&cmd, err))?;
+		}
+
+		let deadline = timeout.map(|v| return Instant::now() + v);
+
+		let status = loop {
+			if let Some(status) = child
+				.try_wait()
+				.map_err(|err| return crate::Error::command_failed(&cmd, err))?
+			{
+				break status;
+			}
+
+			if deadline.is_some_and(|v| return Instant::now() >= v) {
+				// the command may wait for a dialog nobody sees, like on the first run of wine
+				let _ = child.kill();
+				let _ = child.wait();
+
+				return Err(crate::Error::CommandTimeout {
+					cmd:     cmd_line,
+					// safe to unwrap, because there is only a deadline with a timeout
+					timeout: timeout.unwrap(),
+				});
+			}
+
+			std::thread::sleep(POLL_INTERVAL);
+		};
+
+		// the pipes may be kept open by processes the command started (like "wineserver"), so dont wait forever
+		let pipe_deadline = Instant::now() + PIPE_GRACE;
+		let receive = |reader: PipeReader, name: &str| {
+			let (buf, closed) = reader
+				.finish(pipe_deadline)
+				.map_err(|err| return crate::Error::command_failed(&cmd, err))?;
+
+			if !closed {
+				warn!(
+					"The {name} of \"{cmd_line}\" was still kept open by a process it started, using the output read so far"
+				);
+			}
+
+			return Ok::<_, crate::Error>(buf);
+		};
+
+		let stdout = String::from_utf8(receive(stdout, "stdout")?).map_err(|_| {
+			return crate::Error::environment(format!("Output of \"{cmd_line}\" is not valid UTF-8"));
+		})?;
+		let stderr = String::from_utf8_lossy(&receive(stderr, "stderr")?).into_owned();
+
+		trace!("\"{cmd_line}\" exited with {status}");
+
+		return Ok(CommandOutput {
+			code: status.code(),
+			stdout,
+			stderr,
+		});
+	}
+}
+
+/// A response of the [FakeRunner]
+#[derive(Debug, Clone)]
+struct FakeResponse {
+	/// Used for commands whose command line contains this
+	pattern:     String,
+	output:      CommandOutput,
+	/// The content of the file the command writes, see [CommandRunner::run_with_output_file]
+	output_file: Option<Vec<u8>>,
+}
+
+/// Runs nothing, but answers with prepared outputs and records all command lines
+/// Commands without a matching response fail with exit code "1"
+#[derive(Debug, Default)]
+pub struct FakeRunner {
+	responses: Vec<FakeResponse>,
+	calls:     Mutex<Vec<FakeCall>>,
+}
+
+/// A command that was run by the [FakeRunner]
+#[derive(Debug, Clone)]
+struct FakeCall {
+	cmd_line: String,
+	/// The environment variables set on the command, removed ones are not included
+	env:      Vec<(String, String)>,
+}
+
+impl FakeRunner {
+	#[must_use]
+	pub fn new() -> Self {
+		return Self::default();
+	}
+
+	/// Answer commands whose command line contains "pattern" with "output", the first added matching response is used
+	#[must_use]
+	pub fn respond(mut self, pattern: impl Into<String>, output: CommandOutput) -> Self {
+		self.responses.push(FakeResponse {
+			pattern: pattern.into(),
+			output,
+			output_file: None,
+		});
+
+		return self;
+	}
+
+	/// Like [FakeRunner::respond], but the command also writes a file with "content" (like "reg export")
+	#[must_use]
+	pub fn respond_with_file(
+		mut self,
+		pattern: impl Into<String>,
+		output: CommandOutput,
+		content: impl Into<Vec<u8>>,
+	) -> Self {
+		self.responses.push(FakeResponse {
+			pattern: pattern.into(),
+			output,
+			output_file: Some(content.into()),
+		});
+
+		return self;
+	}
+
+	/// Get the command lines of all commands that were run, in order
+	#[must_use]
+	pub fn calls(&self) -> Vec<String> {
+		return self
+			.calls
+			.lock()
+			.expect("Expected calls lock to not be poisoned")
+			.iter()
+			.map(|v| return v.cmd_line.clone())
+			.collect();
+	}
+
+	/// Get the environment variables set on all commands that were run, in order, removed ones are not included
+	#[must_use]
+	pub fn call_envs(&self) -> Vec<Vec<(String, String)>> {
+		return self
+			.calls
+			.lock()
+			.expect("Expected calls lock to not be poisoned")
+			.iter()
+			.map(|v| return v.env.clone())
+			.collect();
+	}
+
+	/// Record "cmd" and get the response for it
+	fn answer(&self, cmd: &Command) -> (CommandOutput, Option<Vec<u8>>) {
+		let cmd_line = command_line(cmd);
+		let env = cmd
+			.get_envs()
+			.filter_map(|(key, value)| {
+				return value.map(|v| return (key.to_string_lossy().into_owned(), v.to_string_lossy().into_owned()));
+			})
+			.collect();
+
+		let answer = self
+			.responses
+			.iter()
+			.find(|v| return cmd_line.contains(&v.pattern))
+			.map_or_else(
+				|| {
+					let output = CommandOutput {
+						code:   Some(1),
+						stdout: String::new(),
+						stderr: format!("No fake response for \"{cmd_line}\""),
+					};
+
+					return (output, None);
+				},
+				|v| return (v.output.clone(), v.output_file.clone()),
+			);
+
+		self.calls
+			.lock()
+			.expect("Expected calls lock to not be poisoned")
+			.push(FakeCall { cmd_line, env });
+
+		return answer;
+	}
+}
+
+impl CommandRunner for FakeRunner {
+	fn run(&self, cmd: Command, _input: Option<&str>) -> Result<CommandOutput, crate::Error> {
+		return Ok(self.answer(&cmd).0);
+	}
+
+	fn run_with_output_file(
+		&self,
+		cmd: Command,
+		_input: Option<&str>,
+		_output_file: &Path,
+	) -> Result<(CommandOutput, Option<Vec<u8>>), crate::Error> {
+		return Ok(self.answer(&cmd));
+	}
+
+	fn runs_processes(&self) -> bool {
+		return false;
+	}
+}
+
+/// Lock for the tests that replace the process-wide runner or depend on the default one, so that they do not run at the same time
+#[cfg(test)]
+pub(crate) fn lock_for_test() -> std::sync::MutexGuard<'static, ()> {
+	static LOCK: Mutex<()> = Mutex::new(());
+
+	return LOCK.lock().unwrap_or_else(std::sync::PoisonError::into_inner);
+}
+
+/// The current runner
+static RUNNER: Lazy<RwLock<Arc<dyn CommandRunner>>> = Lazy::new(|| return RwLock::new(Arc::new(ProcessRunner)));
+
+/// Replace the current runner, should be done once before anything is run
+pub fn set(runner: Arc<dyn CommandRunner>) {
+	// the lock cannot be poisoned, because nothing can panic while holding it
+	*RUNNER.write().expect("Expected runner lock to not be poisoned") = runner;
+}
+
+/// Get the current runner
+pub fn get() -> Arc<dyn CommandRunner> {
+	return Arc::clone(&RUNNER.read().expect("Expected runner lock to not be poisoned"));
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Get a "sh" command that runs "script"
+	fn sh(script: &str) -> Command {
+		let mut cmd = Command::new("sh");
+		cmd.args(["-c", script]);
+
+		return cmd;
+	}
+
+	#[test]
+	#[cfg(unix)]
+	fn output_and_input() {
+		let _lock = lock_for_test();
+
+		let output = ProcessRunner
+			.run(sh("cat; echo err >&2; exit 3"), Some("input\n"))
+			.unwrap();
+
+		assert_eq!(
+			output,
+			CommandOutput {
+				code:   Some(3),
+				stdout: String::from("input\n"),
+				stderr: String::from("err\n"),
+			}
+		);
+	}
+
+	#[test]
+	#[cfg(unix)]
+	fn timeout() {
+		let _lock = lock_for_test();
+		crate::settings::set(crate::settings::Settings {
+			timeout: Some(Duration::from_millis(200)),
+			..Default::default()
+		});
+
+		let start = Instant::now();
+		let res = ProcessRunner.run(sh("sleep 10"), None);
+		crate::settings::set(crate::settings::Settings::default());
+
+		assert!(
+			matches!(&res, Err(crate::Error::CommandTimeout { cmd, timeout }) if cmd == "sh -c sleep 10" && timeout.as_millis() == 200),
+			"{res:?}"
+		);
+		assert!(start.elapsed() < Duration::from_secs(5));
+	}
+
+	#[test]
+	#[cfg(unix)]
+	fn exit_status_with_stderr() {
+		let _lock = lock_for_test();
+
+		let err = ProcessRunner
+			.run_checked(sh("for i in $(seq 1 15); do echo \"line $i\" >&2; done; exit 4"), None)
+			.unwrap_err();
+
+		let crate::Error::CommandStatus { code, stderr, .. } = &err else {
+			panic!("Expected a CommandStatus error, got {err:?}");
+		};
+		assert_eq!(*code, Some(4));
+		// only the last lines are kept
+		assert_eq!(
+			stderr.lines().collect::<Vec<_>>(),
+			(6..=15).map(|v| return format!("line {v}")).collect::<Vec<_>>()
+		);
+		assert!(err.to_string().contains("exited with code 4, stderr:\nline 6\n"));
+		assert_eq!(err.category(), crate::error::ErrorCategory::Environment);
+	}
+
+	#[test]
+	#[cfg(unix)]
+	fn pipe_kept_open() {
+		let _lock = lock_for_test();
+
+		// the background "sleep" keeps stdout open after "sh" exited, like a "wineserver" started by wine
+		let start = Instant::now();
+		let output = ProcessRunner
+			.run(sh("echo out; echo err >&2; sleep 10 &"), None)
+			.unwrap();
+
+		assert_eq!(output.code, Some(0));
+		assert_eq!(output.stdout, "out\n");
+		assert_eq!(output.stderr, "err\n");
+		assert!(
+			start.elapsed() < PIPE_GRACE + Duration::from_secs(2),
+			"{:?}",
+			start.elapsed()
+		);
+	}
+
+	#[test]
+	fn spawn_failure() {
+		let _lock = lock_for_test();
+
+		let err = ProcessRunner
+			.run(Command::new("/nonexistent/ade-extract-key-test"), None)
+			.unwrap_err();
+
+		assert!(matches!(err, crate::Error::CommandFailed { .. }), "{err:?}");
+	}
+
+	#[test]
+	fn fake_runner() {
+		let runner = FakeRunner::new()
+			.respond(
+				"--version",
+				CommandOutput {
+					code:   Some(0),
+					stdout: String::from("wine-9.0\n"),
+					stderr: String::new(),
+				},
+			)
+			.respond_with_file("reg export", CommandOutput::default(), b"content".to_vec());
+
+		let mut version_cmd = Command::new("wine");
+		version_cmd.arg("--version");
+		assert_eq!(runner.run_checked(version_cmd, None).unwrap().stdout, "wine-9.0\n");
+
+		assert!(matches!(
+			runner.run_checked(Command::new("unknown"), None),
+			Err(crate::Error::CommandStatus { code: Some(1), .. })
+		));
+
+		let mut export_cmd = Command::new("reg");
+		export_cmd.args(["export", "KEY"]);
+		assert_eq!(
+			runner
+				.run_with_output_file(export_cmd, None, Path::new("unused"))
+				.unwrap()
+				.1
+				.as_deref(),
+			Some(b"content".as_slice())
+		);
+
+		assert_eq!(runner.calls(), ["wine --version", "unknown", "reg export KEY"]);
+	}
+}
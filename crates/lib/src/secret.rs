@@ -0,0 +1,77 @@
+//! Module for types holding secrets (keys), which are wiped from memory on drop and redacted in [Debug] output
+
+use serde::{
+	Deserialize,
+	Serialize,
+};
+use zeroize::Zeroize;
+
+/// Placeholder for secrets that are not shown
+pub const REDACTED: &str = "<redacted>";
+
+/// A secret string (like a hex or base64 encoded key), wiped from memory on drop
+#[derive(Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct SecretString(String);
+
+impl SecretString {
+	/// Get the secret, only use this where the secret is actually needed
+	#[must_use]
+	pub fn expose(&self) -> &str {
+		return &self.0;
+	}
+}
+
+impl From<String> for SecretString {
+	fn from(value: String) -> Self {
+		return Self(value);
+	}
+}
+
+impl From<&str> for SecretString {
+	fn from(value: &str) -> Self {
+		return Self(value.to_owned());
+	}
+}
+
+impl std::fmt::Debug for SecretString {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		return write!(f, "SecretString({REDACTED})");
+	}
+}
+
+impl Drop for SecretString {
+	fn drop(&mut self) {
+		self.0.zeroize();
+	}
+}
+
+/// Secret bytes (like the PKCS#1 DER key), wiped from memory on drop
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct SecretBytes(Vec<u8>);
+
+impl SecretBytes {
+	/// Get the secret, only use this where the secret is actually needed
+	#[must_use]
+	pub fn expose(&self) -> &[u8] {
+		return &self.0;
+	}
+}
+
+impl From<Vec<u8>> for SecretBytes {
+	fn from(value: Vec<u8>) -> Self {
+		return Self(value);
+	}
+}
+
+impl std::fmt::Debug for SecretBytes {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		return write!(f, "SecretBytes({REDACTED})");
+	}
+}
+
+impl Drop for SecretBytes {
+	fn drop(&mut self) {
+		self.0.zeroize();
+	}
+}
@@ -0,0 +1,41 @@
+//! Module for the process-wide settings of how wine and the winapi-bin are run
+
+use std::{
+	path::PathBuf,
+	sync::RwLock,
+	time::Duration,
+};
+
+use once_cell::sync::Lazy;
+
+/// How wine and the winapi-bin are run, unset values use the defaults
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Settings {
+	/// The wine binary (or a installation directory, like of Proton), defaults to "wine" from "PATH"
+	pub wine:       Option<PathBuf>,
+	/// The CrossOver bottle, used instead of the prefix
+	pub bottle:     Option<String>,
+	/// The wine prefix, defaults to "WINEPREFIX" or wine's default "~/.wine"
+	pub prefix:     Option<PathBuf>,
+	/// Path of the winapi-bin, defaults to "ade-extract-winapi-bin.exe" in the current directory
+	pub winapi_bin: Option<PathBuf>,
+	/// How long a single command may run, defaults to [crate::runner::DEFAULT_TIMEOUT], "0" disables the timeout
+	pub timeout:    Option<Duration>,
+}
+
+/// The current settings
+static SETTINGS: Lazy<RwLock<Settings>> = Lazy::new(|| return RwLock::new(Settings::default()));
+
+/// Replace the current settings, should be done once before anything is run
+pub fn set(settings: Settings) {
+	// the lock cannot be poisoned, because nothing can panic while holding it
+	*SETTINGS.write().expect("Expected settings lock to not be poisoned") = settings;
+}
+
+/// Get a copy of the current settings
+pub fn get() -> Settings {
+	return SETTINGS
+		.read()
+		.expect("Expected settings lock to not be poisoned")
+		.clone();
+}
@@ -0,0 +1,443 @@
+//! Module for the local key store, a directory of extracted keys with a index of their metadata
+
+use std::{
+	path::{
+		Path,
+		PathBuf,
+	},
+	time::SystemTime,
+};
+
+use serde::{
+	Deserialize,
+	Serialize,
+};
+
+use crate::secret::SecretBytes;
+
+/// Name of the index file in the store directory
+const INDEX_FILE_NAME: &str = "index.json";
+
+/// Version of the index format, to be able to migrate it later
+const INDEX_VERSION: u32 = 1;
+
+/// Find the default key store directory
+/// Order: the platform data directory, then "HOME" based fallbacks
+pub fn find_store_dir() -> Option<PathBuf> {
+	#[cfg(windows)]
+	{
+		return std::env::var_os("APPDATA").map(|v| return PathBuf::from(v).join("ade-extract-key").join("keys"));
+	}
+
+	#[cfg(target_os = "macos")]
+	{
+		return std::env::var_os("HOME")
+			.map(|v| return PathBuf::from(v).join("Library/Application Support/ade-extract-key/keys"));
+	}
+
+	#[cfg(not(any(windows, target_os = "macos")))]
+	{
+		if let Some(dir) = std::env::var_os("XDG_DATA_HOME").filter(|v| return !v.is_empty()) {
+			return Some(PathBuf::from(dir).join("ade-extract-key").join("keys"));
+		}
+
+		return std::env::var_os("HOME").map(|v| return PathBuf::from(v).join(".local/share/ade-extract-key/keys"));
+	}
+}
+
+/// A single key in the store
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoredKey {
+	/// Fingerprint of the key, see [crate::key::fingerprint]
+	pub fingerprint:   String,
+	/// The AdobeID of the account, if known
+	pub adobe_id:      Option<String>,
+	/// The method of the account (like "AdobeID"), if known
+	pub method:        Option<String>,
+	/// The "urn:uuid" of the account, if known
+	pub user:          Option<String>,
+	/// The wine prefix the key was extracted from, if known
+	pub source_prefix: Option<PathBuf>,
+	/// When the key was added to the store (RFC 3339)
+	pub extracted_at:  String,
+	/// File name of the key (PKCS#1 DER) in the store directory
+	pub file:          String,
+}
+
+/// Metadata to store with a key, see [StoredKey] for the meaning of the fields
+#[derive(Debug, Clone, Default)]
+pub struct KeyMetadata {
+	pub adobe_id:      Option<String>,
+	pub method:        Option<String>,
+	pub user:          Option<String>,
+	pub source_prefix: Option<PathBuf>,
+}
+
+/// The outcome of adding a key to the store
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StoreAdd {
+	/// The key was added with the contained fingerprint
+	Added(String),
+	/// A key with the same fingerprint was already in the store, only missing metadata was added
+	AlreadyPresent(String),
+}
+
+/// The on-disk format of the index
+#[derive(Debug, Serialize, Deserialize)]
+struct Index {
+	version: u32,
+	keys:    Vec<StoredKey>,
+}
+
+/// A opened key store
+#[derive(Debug)]
+pub struct KeyStore {
+	dir:  PathBuf,
+	keys: Vec<StoredKey>,
+}
+
+impl KeyStore {
+	/// Open the key store at "dir", the directory does not need to exist yet
+	pub fn open(dir: &Path) -> Result<Self, crate::Error> {
+		let index_path = dir.join(INDEX_FILE_NAME);
+
+		let keys = if index_path.exists() {
+			let content = std::fs::read(&index_path)
+				.map_err(|err| return crate::Error::io(format!("Failed to read \"{}\"", index_path.display()), err))?;
+			let index: Index = serde_json::from_slice(&content)
+				.map_err(|err| return crate::Error::parse(format!("\"{}\"", index_path.display()), err))?;
+
+			if index.version != INDEX_VERSION {
+				return Err(crate::Error::parse(
+					format!("\"{}\"", index_path.display()),
+					format!("Unsupported key store index version {}", index.version),
+				));
+			}
+
+			index.keys
+		} else {
+			Vec::new()
+		};
+
+		return Ok(Self {
+			dir: dir.to_path_buf(),
+			keys,
+		});
+	}
+
+	/// Get the directory of the store
+	#[must_use]
+	pub fn dir(&self) -> &Path {
+		return &self.dir;
+	}
+
+	/// Get all keys in the store, in the order they were added
+	#[must_use]
+	pub fn keys(&self) -> &[StoredKey] {
+		return &self.keys;
+	}
+
+	/// Find a key by "query", which can be a (unique) fingerprint prefix, the AdobeID or the user uuid
+	pub fn find(&self, query: &str) -> Result<&StoredKey, crate::Error> {
+		let query_lower = query.to_ascii_lowercase();
+		let uuid_query = crate::naming::user_uuid(query);
+
+		let matches: Vec<&StoredKey> = self
+			.keys
+			.iter()
+			.filter(|v| {
+				return v.fingerprint.starts_with(&query_lower)
+					|| v.adobe_id.as_deref() == Some(query)
+					|| v.user.as_deref().map(crate::naming::user_uuid) == Some(uuid_query);
+			})
+			.collect();
+
+		return match matches.as_slice() {
+			[] => Err(crate::Error::usage(format!(
+				"No key matching \"{query}\" in the key store"
+			))),
+			[single] => Ok(single),
+			_ => Err(crate::Error::usage(format!(
+				"\"{query}\" matches multiple keys in the key store: {}",
+				matches
+					.iter()
+					.map(|v| return v.fingerprint.as_str())
+					.collect::<Vec<_>>()
+					.join(", ")
+			))),
+		};
+	}
+
+	/// Find all keys that belong to the account with the "user" ("urn:uuid", with or without prefix)
+	#[must_use]
+	pub fn find_by_user(&self, user: &str) -> Vec<&StoredKey> {
+		let uuid = crate::naming::user_uuid(user);
+
+		return self
+			.keys
+			.iter()
+			.filter(|v| return v.user.as_deref().map(crate::naming::user_uuid) == Some(uuid))
+			.collect();
+	}
+
+	/// Read the PKCS#1 DER key of "entry" from the store
+	pub fn read_key(&self, entry: &StoredKey) -> Result<SecretBytes, crate::Error> {
+		let path = self.dir.join(&entry.file);
+
+		return std::fs::read(&path)
+			.map(SecretBytes::from)
+			.map_err(|err| return crate::Error::io(format!("Failed to read \"{}\"", path.display()), err));
+	}
+
+	/// Add the PKCS#1 DER "key" with "metadata" to the store, keys are deduplicated by fingerprint
+	pub fn add(&mut self, key: &[u8], metadata: KeyMetadata) -> Result<StoreAdd, crate::Error> {
+		let fingerprint = crate::key::fingerprint(key)?;
+
+		if let Some(existing) = self.keys.iter_mut().find(|v| return v.fingerprint == fingerprint) {
+			let before = existing.clone();
+
+			// fill in what was not known when the key was first added, like when it was added via "aes"
+			existing.adobe_id = existing.adobe_id.take().or(metadata.adobe_id);
+			existing.method = existing.method.take().or(metadata.method);
+			existing.user = existing.user.take().or(metadata.user);
+			existing.source_prefix = existing.source_prefix.take().or(metadata.source_prefix);
+
+			if *existing != before {
+				self.save()?;
+			}
+
+			return Ok(StoreAdd::AlreadyPresent(fingerprint));
+		}
+
+		self.create_dir()?;
+
+		let file = format!("{fingerprint}.der");
+		crate::file::write_atomic(&self.dir.join(&file), key)?;
+
+		self.keys.push(StoredKey {
+			fingerprint: fingerprint.clone(),
+			adobe_id: metadata.adobe_id,
+			method: metadata.method,
+			user: metadata.user,
+			source_prefix: metadata.source_prefix,
+			extracted_at: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+			file,
+		});
+
+		self.save()?;
+
+		return Ok(StoreAdd::Added(fingerprint));
+	}
+
+	/// Remove the key with "fingerprint" (full) from the store, including its file
+	pub fn remove(&mut self, fingerprint: &str) -> Result<StoredKey, crate::Error> {
+		let pos = self
+			.keys
+			.iter()
+			.position(|v| return v.fingerprint == fingerprint)
+			.ok_or_else(|| {
+				return crate::Error::usage(format!("No key with fingerprint \"{fingerprint}\" in the key store"));
+			})?;
+
+		let entry = self.keys.remove(pos);
+
+		// save the index first, so that a failure does not leave a index entry without a file
+		self.save()?;
+
+		let path = self.dir.join(&entry.file);
+		if path.exists() {
+			std::fs::remove_file(&path)
+				.map_err(|err| return crate::Error::io(format!("Failed to remove \"{}\"", path.display()), err))?;
+		}
+
+		return Ok(entry);
+	}
+
+	/// Create the store directory, only accessible by the current user on unix
+	fn create_dir(&self) -> Result<(), crate::Error> {
+		if self.dir.is_dir() {
+			return Ok(());
+		}
+
+		let mut builder = std::fs::DirBuilder::new();
+		builder.recursive(true);
+
+		#[cfg(unix)]
+		{
+			use std::os::unix::fs::DirBuilderExt;
+			builder.mode(0o700);
+		}
+
+		builder.create(&self.dir).map_err(|err| {
+			return crate::Error::io(
+				format!("Failed to create key store directory \"{}\"", self.dir.display()),
+				err,
+			);
+		})?;
+
+		return Ok(());
+	}
+
+	/// Write the index to disk
+	fn save(&self) -> Result<(), crate::Error> {
+		self.create_dir()?;
+
+		let mut content = serde_json::to_vec_pretty(&Index {
+			version: INDEX_VERSION,
+			keys:    self.keys.clone(),
+		})
+		.map_err(|source| {
+			return crate::Error::Serialize {
+				what: "key store index",
+				source,
+			};
+		})?;
+		content.push(b'\n');
+
+		crate::file::write_atomic(&self.dir.join(INDEX_FILE_NAME), &content)?;
+
+		return Ok(());
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::file::TempDir;
+
+	/// A PKCS#1 DER key
+	const KEY: &[u8] = include_bytes!("../tests/fixtures/key.der");
+	/// Fingerprint of [KEY], from "openssl pkey -pubout -outform DER | sha256sum"
+	const KEY_FINGERPRINT: &str = "90816049466b16587a519574f13b4f51442cb26173f191d9f325765b49ae1476";
+
+	/// Get a additional PKCS#1 DER key
+	fn other_key() -> Vec<u8> {
+		let key = rsa::RsaPrivateKey::new(&mut pkcs8::rand_core::OsRng, 512).unwrap();
+
+		return pkcs1::EncodeRsaPrivateKey::to_pkcs1_der(&key)
+			.unwrap()
+			.as_bytes()
+			.to_vec();
+	}
+
+	/// Get the metadata of a account with "adobe_id"
+	fn metadata(adobe_id: &str) -> KeyMetadata {
+		return KeyMetadata {
+			adobe_id: Some(adobe_id.to_owned()),
+			method: Some(String::from("AdobeID")),
+			user: Some(format!("urn:uuid:{adobe_id}-uuid")),
+			..Default::default()
+		};
+	}
+
+	#[test]
+	fn fingerprint_is_stable() {
+		assert_eq!(crate::key::fingerprint(KEY).unwrap(), KEY_FINGERPRINT);
+	}
+
+	#[test]
+	fn add_and_reopen() {
+		let dir = TempDir::new("ade-extract-key-test").unwrap();
+		let store_dir = dir.path().join("store");
+		let mut store = KeyStore::open(&store_dir).unwrap();
+
+		assert_eq!(
+			store.add(KEY, metadata("reader")).unwrap(),
+			StoreAdd::Added(KEY_FINGERPRINT.to_owned())
+		);
+
+		#[cfg(unix)]
+		{
+			use std::os::unix::fs::PermissionsExt;
+
+			assert_eq!(
+				std::fs::metadata(&store_dir).unwrap().permissions().mode() & 0o777,
+				0o700
+			);
+		}
+
+		let store = KeyStore::open(&store_dir).unwrap();
+		assert_eq!(store.keys().len(), 1);
+		assert_eq!(store.keys()[0].adobe_id.as_deref(), Some("reader"));
+		assert_eq!(store.read_key(&store.keys()[0]).unwrap().expose(), KEY);
+	}
+
+	#[test]
+	fn dedup_and_fill_in() {
+		let dir = TempDir::new("ade-extract-key-test").unwrap();
+		let mut store = KeyStore::open(dir.path()).unwrap();
+		let index_path = dir.path().join(INDEX_FILE_NAME);
+
+		// like added via "aes", where nothing about the account is known
+		store.add(KEY, KeyMetadata::default()).unwrap();
+		let index = std::fs::read(&index_path).unwrap();
+
+		assert_eq!(
+			store.add(KEY, metadata("reader")).unwrap(),
+			StoreAdd::AlreadyPresent(KEY_FINGERPRINT.to_owned())
+		);
+		assert_eq!(store.keys().len(), 1);
+		assert_eq!(store.keys()[0].adobe_id.as_deref(), Some("reader"));
+		assert_ne!(std::fs::read(&index_path).unwrap(), index);
+
+		// known metadata is not replaced, and the index is not written without a change
+		std::fs::remove_file(&index_path).unwrap();
+		assert_eq!(
+			store.add(KEY, metadata("other")).unwrap(),
+			StoreAdd::AlreadyPresent(KEY_FINGERPRINT.to_owned())
+		);
+		assert_eq!(store.keys()[0].adobe_id.as_deref(), Some("reader"));
+		assert!(!index_path.exists());
+	}
+
+	#[test]
+	fn find_and_remove() {
+		let dir = TempDir::new("ade-extract-key-test").unwrap();
+		let mut store = KeyStore::open(dir.path()).unwrap();
+		let other_key = other_key();
+
+		store.add(KEY, metadata("reader")).unwrap();
+		let StoreAdd::Added(other_fingerprint) = store.add(&other_key, metadata("other")).unwrap() else {
+			panic!("Expected the other key to be added");
+		};
+
+		assert_eq!(store.find(&KEY_FINGERPRINT[..8]).unwrap().fingerprint, KEY_FINGERPRINT);
+		assert_eq!(
+			store.find(&KEY_FINGERPRINT.to_ascii_uppercase()).unwrap().fingerprint,
+			KEY_FINGERPRINT
+		);
+		assert_eq!(store.find("other").unwrap().fingerprint, other_fingerprint);
+		assert_eq!(store.find("urn:uuid:reader-uuid").unwrap().fingerprint, KEY_FINGERPRINT);
+		assert_eq!(store.find("reader-uuid").unwrap().fingerprint, KEY_FINGERPRINT);
+		assert_eq!(store.find_by_user("reader-uuid").len(), 1);
+
+		// the empty prefix matches every key
+		let err = store.find("").unwrap_err();
+		assert_eq!(err.category(), crate::error::ErrorCategory::Usage);
+		assert!(err.to_string().contains("matches multiple keys"), "{err}");
+		assert!(store.find("unknown").is_err());
+
+		let key_file = dir.path().join(&store.find("reader").unwrap().file);
+		assert!(key_file.is_file());
+		assert_eq!(
+			store.remove(KEY_FINGERPRINT).unwrap().adobe_id.as_deref(),
+			Some("reader")
+		);
+		assert!(!key_file.exists());
+		// only full fingerprints can be removed
+		assert!(store.remove(&other_fingerprint[..8]).is_err());
+
+		let store = KeyStore::open(dir.path()).unwrap();
+		assert_eq!(store.keys().len(), 1);
+		assert_eq!(store.keys()[0].fingerprint, other_fingerprint);
+	}
+
+	#[test]
+	fn unsupported_version() {
+		let dir = TempDir::new("ade-extract-key-test").unwrap();
+		std::fs::write(dir.path().join(INDEX_FILE_NAME), r#"{"version": 2, "keys": []}"#).unwrap();
+
+		let err = KeyStore::open(dir.path()).unwrap_err();
+		assert_eq!(err.category(), crate::error::ErrorCategory::Format);
+	}
+}
@@ -0,0 +1,54 @@
+//! Module for the fixtures and helpers shared by the tests of multiple modules
+
+use std::sync::Arc;
+
+use crate::runner::{
+	CommandOutput,
+	FakeRunner,
+};
+
+/// Registry export of the Adept key with a device key and one activation
+pub(crate) const ADEPT_EXPORT: &str = r#"Windows Registry Editor Version 5.00
+
+[HKEY_CURRENT_USER\Software\Adobe\Adept\Device]
+"key"=hex:00,11,22,33,ab,cd
+"username"="adobeuser"
+
+[HKEY_CURRENT_USER\Software\Adobe\Adept\Activation]
+
+[HKEY_CURRENT_USER\Software\Adobe\Adept\Activation\0000]
+@="credentials"
+
+[HKEY_CURRENT_USER\Software\Adobe\Adept\Activation\0000\0001]
+@="user"
+"value"="urn:uuid:00000000-0000-0000-0000-000000000001"
+
+[HKEY_CURRENT_USER\Software\Adobe\Adept\Activation\0000\0002]
+@="username"
+"method"="AdobeID"
+"value"="reader@example.com"
+
+[HKEY_CURRENT_USER\Software\Adobe\Adept\Activation\0000\0003]
+@="privateLicenseKey"
+"value"="cHJpdmF0ZQ=="
+"#;
+
+/// Output of the snapshot command, as "cmd" would print it
+pub(crate) const SNAPSHOT_OUTPUT: &str = "ade-extract-key-root=C:\\windows\r\n Volume in drive C has no label.\r\n Volume Serial Number is 4300-0000\r\nade-extract-key-user=winuser\r\n";
+
+/// Get a successful [CommandOutput] with "stdout"
+pub(crate) fn success(stdout: &str) -> CommandOutput {
+	return CommandOutput {
+		code:   Some(0),
+		stdout: stdout.to_owned(),
+		stderr: String::new(),
+	};
+}
+
+/// Replace the runner with "runner" for the rest of the test, the runner lock has to be held
+pub(crate) fn use_runner(runner: FakeRunner) -> Arc<FakeRunner> {
+	let runner = Arc::new(runner);
+	crate::runner::set(runner.clone());
+
+	return runner;
+}
@@ -0,0 +1,178 @@
+//! Module for the stdin / stdout protocol spoken with "ade-extract-winapi-bin.exe"
+//!
+//! The secrets are not passed as arguments anymore, because arguments are readable by any local user (like via `ps`).
+//!
+//! Protocol (version 1), all lines are terminated by "\n", values are encoded in hex:
+//!
+//! Request (stdin of the helper):
+//! ```txt
+//! ade-extract-winapi 1
+//! entropy <hex>
+//! data <hex>
+//! ```
+//!
+//! Response (stdout of the helper), either:
+//! ```txt
+//! ade-extract-winapi 1
+//! decrypted <hex>
+//! ```
+//! or:
+//! ```txt
+//! ade-extract-winapi 1
+//! error <kind> <message>
+//! ```
+
+use crate::error::WinapiProtocolError;
+
+/// Header line that starts every request and response
+pub const PROTOCOL_HEADER: &str = "ade-extract-winapi";
+/// Version of the protocol that is spoken
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Encode a request for the helper
+/// "entropy_hex" and "data_hex" are expected to already be hex-encoded
+pub fn encode_request(entropy_hex: &str, data_hex: &str) -> String {
+	return format!("{PROTOCOL_HEADER} {PROTOCOL_VERSION}\nentropy {entropy_hex}\ndata {data_hex}\n");
+}
+
+/// Parse the response (stdout) of the helper, returns the decrypted data as hex
+pub fn parse_response(output: &str) -> Result<String, WinapiProtocolError> {
+	// ignore everything before the header, in case wine or the helper prints something unrelated
+	let mut lines = output
+		.lines()
+		.map(str::trim_end)
+		.skip_while(|v| return !v.starts_with(PROTOCOL_HEADER));
+
+	let Some(header) = lines.next() else {
+		if output.trim().is_empty() {
+			return Err(WinapiProtocolError::EmptyResponse);
+		}
+
+		return Err(WinapiProtocolError::MissingHeader);
+	};
+
+	let version = header[PROTOCOL_HEADER.len()..].trim();
+	if version != PROTOCOL_VERSION.to_string() {
+		return Err(WinapiProtocolError::UnsupportedVersion(version.to_owned()));
+	}
+
+	for line in lines {
+		if line.is_empty() {
+			continue;
+		}
+
+		let (field, value) = line.split_once(' ').unwrap_or((line, ""));
+
+		match field {
+			"decrypted" => {
+				let value = value.trim();
+
+				if value.is_empty() || value.len() % 2 != 0 || !value.chars().all(|v| return v.is_ascii_hexdigit()) {
+					return Err(WinapiProtocolError::InvalidHex);
+				}
+
+				return Ok(value.to_owned());
+			},
+			"error" => {
+				let (kind, msg) = value.split_once(' ').unwrap_or((value, ""));
+
+				return Err(WinapiProtocolError::HelperError {
+					kind: kind.to_owned(),
+					msg:  msg.to_owned(),
+				});
+			},
+			_ => return Err(WinapiProtocolError::UnexpectedLine(line.to_owned())),
+		}
+	}
+
+	return Err(WinapiProtocolError::MissingResult);
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn request() {
+		assert_eq!(
+			encode_request("0a0b", "ff"),
+			"ade-extract-winapi 1\nentropy 0a0b\ndata ff\n"
+		);
+	}
+
+	#[test]
+	fn decrypted() {
+		assert_eq!(
+			parse_response("ade-extract-winapi 1\r\ndecrypted 00FFab\r\n").unwrap(),
+			"00FFab"
+		);
+	}
+
+	#[test]
+	fn output_before_header() {
+		assert_eq!(
+			parse_response("wine: something\nade-extract-winapi 1\n\ndecrypted 0011\n").unwrap(),
+			"0011"
+		);
+	}
+
+	#[test]
+	fn empty() {
+		assert!(matches!(parse_response(" \n"), Err(WinapiProtocolError::EmptyResponse)));
+	}
+
+	#[test]
+	fn missing_header() {
+		assert!(matches!(
+			parse_response("decrypted 0011\n"),
+			Err(WinapiProtocolError::MissingHeader)
+		));
+	}
+
+	#[test]
+	fn version_mismatch() {
+		assert!(matches!(
+			parse_response("ade-extract-winapi 2\ndecrypted 0011\n"),
+			Err(WinapiProtocolError::UnsupportedVersion(v)) if v == "2"
+		));
+	}
+
+	#[test]
+	fn unknown_line() {
+		assert!(matches!(
+			parse_response("ade-extract-winapi 1\nresult 0011\n"),
+			Err(WinapiProtocolError::UnexpectedLine(v)) if v == "result 0011"
+		));
+	}
+
+	#[test]
+	fn helper_error() {
+		assert!(matches!(
+			parse_response("ade-extract-winapi 1\nerror decrypt CryptUnprotectData failed\n"),
+			Err(WinapiProtocolError::HelperError { kind, msg }) if kind == "decrypt" && msg == "CryptUnprotectData failed"
+		));
+	}
+
+	#[test]
+	fn missing_decrypted() {
+		assert!(matches!(
+			parse_response("ade-extract-winapi 1\n\n"),
+			Err(WinapiProtocolError::MissingResult)
+		));
+	}
+
+	#[test]
+	fn bad_hex() {
+		for response in [
+			"ade-extract-winapi 1\ndecrypted\n",
+			"ade-extract-winapi 1\ndecrypted 001\n",
+			"ade-extract-winapi 1\ndecrypted 00gg\n",
+			"ade-extract-winapi 1\ndecrypted 0\u{e9}\n",
+		] {
+			assert!(
+				matches!(parse_response(response), Err(WinapiProtocolError::InvalidHex)),
+				"{response:?}"
+			);
+		}
+	}
+}
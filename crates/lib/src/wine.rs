@@ -0,0 +1,326 @@
+//! Module for how wine is launched: which binary (wine, wine64, Proton or CrossOver), its environment and its version
+
+use std::{
+	path::{
+		Path,
+		PathBuf,
+	},
+	process::Command,
+};
+
+/// Environment variables set for every wine command, unless they are already set
+/// "WINEDEBUG" keeps wine's debug output out of stderr, "WINEDLLOVERRIDES" disables mono and gecko, whose install dialogs would block unattended runs
+const DEFAULT_ENV: [(&str, &str); 2] = [("WINEDEBUG", "-all"), ("WINEDLLOVERRIDES", "mscoree=;mshtml=")];
+
+/// Where the wine binary is in a installation directory, like Proton ("files", older versions "dist") or CrossOver
+const INSTALL_DIR_BINARIES: [&str; 3] = ["files/bin/wine", "dist/bin/wine", "bin/wine"];
+
+/// The oldest wine version (major, minor) that is supported, older ones fail to run ADE 2.5 or the winapi-bin
+pub const MIN_VERSION: (u32, u32) = (5, 0);
+
+/// Seconds the wineserver started by [start_server] keeps running after the last wine process exited
+const SERVER_PERSIST_SECS: u32 = 30;
+
+/// Get the wine binary in "path", which is either the binary itself or a installation directory (like of Proton)
+#[must_use]
+pub fn resolve_binary(path: &Path) -> PathBuf {
+	if path.is_dir() {
+		if let Some(binary) = INSTALL_DIR_BINARIES
+			.iter()
+			.map(|v| return path.join(v))
+			.find(|v| return v.is_file())
+		{
+			return binary;
+		}
+	}
+
+	return path.to_owned();
+}
+
+/// Get the wine binary to use, the configured one (see [resolve_binary]) or "wine" from "PATH"
+#[must_use]
+pub fn binary() -> PathBuf {
+	return crate::settings::get()
+		.wine
+		.map_or_else(|| return PathBuf::from("wine"), |v| return resolve_binary(&v));
+}
+
+/// Get the wineserver that belongs to the wine [binary], "wineserver" from "PATH" if there is none next to it
+fn server_binary() -> PathBuf {
+	let wine = binary();
+
+	return wine
+		.parent()
+		.filter(|v| return !v.as_os_str().is_empty())
+		.map(|v| return v.join("wineserver"))
+		.filter(|v| return v.is_file())
+		.unwrap_or_else(|| return PathBuf::from("wineserver"));
+}
+
+/// Start a wineserver for the prefix that keeps running for a while, so that all wine commands of a run share it,
+/// instead of each one starting (and waiting for) a new one
+/// Not done for CrossOver bottles, which manage their own, failing to start it is not a error (like when one is already running)
+pub fn start_server() {
+	let settings = crate::settings::get();
+
+	if settings.bottle.is_some() {
+		return;
+	}
+
+	let mut server_cmd = Command::new(server_binary());
+	server_cmd.arg(format!("-p{SERVER_PERSIST_SECS}"));
+	set_env(&mut server_cmd, settings.prefix.as_deref());
+
+	match crate::runner::get().run_checked(server_cmd, None) {
+		Ok(_) => debug!("Started a wineserver for this run"),
+		Err(err) => debug!("Did not start a wineserver: {}", crate::error::display_chain(&err)),
+	}
+}
+
+/// Get the directory of the CrossOver bottle "bottle"
+#[must_use]
+pub fn bottle_dir(bottle: &str) -> Option<PathBuf> {
+	let home = PathBuf::from(std::env::var_os("HOME")?);
+
+	if cfg!(target_os = "macos") {
+		return Some(home.join("Library/Application Support/CrossOver/Bottles").join(bottle));
+	}
+
+	return Some(home.join(".cxoffice").join(bottle));
+}
+
+/// Create a new wine [Command] with the configured binary, in the configured prefix (or CrossOver bottle)
+#[must_use]
+pub fn command() -> Command {
+	let settings = crate::settings::get();
+
+	let mut cmd = Command::new(binary());
+
+	// CrossOver selects the prefix by its bottle
+	if let Some(bottle) = &settings.bottle {
+		cmd.args(["--bottle", bottle]);
+		set_env(&mut cmd, None);
+	} else {
+		set_env(&mut cmd, settings.prefix.as_deref());
+	}
+
+	return cmd;
+}
+
+/// Set the environment of a wine (or wineserver) command: "WINEPREFIX" if a "prefix" is configured (otherwise the inherited one is used) and [DEFAULT_ENV]
+fn set_env(cmd: &mut Command, prefix: Option<&Path>) {
+	if let Some(prefix) = prefix {
+		cmd.env("WINEPREFIX", prefix);
+	}
+
+	for (key, value) in DEFAULT_ENV {
+		if std::env::var_os(key).is_none() {
+			cmd.env(key, value);
+		}
+	}
+}
+
+/// Get the version of wine, like "wine-9.0"
+pub fn version() -> Result<String, crate::Error> {
+	let mut version_cmd = command();
+	version_cmd.arg("--version");
+
+	let version_out = crate::runner::get().run_checked(version_cmd, None)?.stdout;
+
+	return Ok(version_out.trim().to_owned());
+}
+
+/// Parse the major and minor version from the output of "wine --version", like "wine-8.0.1 (CrossOver FOSS 23.7.1)"
+#[must_use]
+pub fn parse_version(version: &str) -> Option<(u32, u32)> {
+	let number = version.trim().strip_prefix("wine-")?.split_whitespace().next()?;
+	let mut parts = number.split(['.', '-']);
+
+	let major = parts.next()?.parse().ok()?;
+	let minor = parts.next().map_or(Some(0), |v| return v.parse().ok())?;
+
+	return Some((major, minor));
+}
+
+/// Get why the wine "version" is incompatible, "None" if it is compatible or unknown
+#[must_use]
+pub fn incompatibility(version: &str) -> Option<String> {
+	let parsed = parse_version(version)?;
+
+	if parsed < MIN_VERSION {
+		return Some(format!(
+			"{version} is older than wine-{}.{}, the oldest supported version",
+			MIN_VERSION.0, MIN_VERSION.1
+		));
+	}
+
+	return None;
+}
+
+/// Log a warning if the used wine version is incompatible, failing to get the version is not a error here
+pub fn check_version() {
+	match version() {
+		Ok(version) => {
+			debug!("Using {version}");
+
+			if let Some(reason) = incompatibility(&version) {
+				warn!("Wine version is incompatible: {reason}");
+			}
+		},
+		Err(err) => debug!("Could not get the wine version: {}", crate::error::display_chain(&err)),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::Arc;
+
+	use super::*;
+	use crate::{
+		file::TempDir,
+		runner::{
+			command_line,
+			FakeRunner,
+		},
+		settings::Settings,
+		test_util::{
+			success,
+			use_runner,
+		},
+	};
+
+	#[test]
+	fn versions() {
+		assert_eq!(parse_version("wine-9.0"), Some((9, 0)));
+		assert_eq!(parse_version("wine-9.0\n"), Some((9, 0)));
+		assert_eq!(parse_version("wine-8.0-rc2 (Staging)"), Some((8, 0)));
+		assert_eq!(parse_version("wine-8.0.1 (CrossOver FOSS 23.7.1)"), Some((8, 0)));
+		assert_eq!(parse_version("wine-10.5"), Some((10, 5)));
+		assert_eq!(parse_version("wine-7"), Some((7, 0)));
+
+		for garbage in ["", "wine", "wine-", "wine-x.y", "9.0", "Wine 9.0", "wine-9.x"] {
+			assert_eq!(parse_version(garbage), None, "{garbage}");
+		}
+	}
+
+	#[test]
+	fn min_version() {
+		assert_eq!(MIN_VERSION, (5, 0));
+
+		assert!(incompatibility("wine-4.21").is_some());
+		assert!(incompatibility("wine-4.0-rc1").is_some());
+		assert!(incompatibility("wine-5.0").is_none());
+		assert!(incompatibility("wine-5.0-rc1").is_none());
+		assert!(incompatibility("wine-9.0 (Staging)").is_none());
+		// a unknown version is not reported as incompatible
+		assert!(incompatibility("something else").is_none());
+
+		assert_eq!(
+			incompatibility("wine-4.0").unwrap(),
+			"wine-4.0 is older than wine-5.0, the oldest supported version"
+		);
+	}
+
+	#[test]
+	fn install_dir_binaries() {
+		let dir = TempDir::new("ade-extract-key-test").unwrap();
+
+		// in the order Proton ("files", older versions "dist"), then CrossOver ("bin")
+		for (i, layout) in ["bin/wine", "dist/bin/wine", "files/bin/wine"].iter().enumerate() {
+			let install_dir = dir.path().join(format!("install-{i}"));
+			std::fs::create_dir_all(install_dir.join(layout).parent().unwrap()).unwrap();
+			std::fs::write(install_dir.join(layout), "").unwrap();
+
+			assert_eq!(resolve_binary(&install_dir), install_dir.join(layout));
+		}
+
+		// with multiple layouts, the newest Proton one wins
+		let install_dir = dir.path().join("install-2");
+		std::fs::create_dir_all(install_dir.join("bin")).unwrap();
+		std::fs::write(install_dir.join("bin/wine"), "").unwrap();
+		assert_eq!(resolve_binary(&install_dir), install_dir.join("files/bin/wine"));
+
+		// a directory without a known layout or a binary is used as it is
+		let empty_dir = dir.path().join("empty");
+		std::fs::create_dir(&empty_dir).unwrap();
+		assert_eq!(resolve_binary(&empty_dir), empty_dir);
+		assert_eq!(resolve_binary(Path::new("wine64")), Path::new("wine64"));
+	}
+
+	/// Get if "env" has "key" set to "value"
+	fn has_env(env: &[(String, String)], key: &str, value: &str) -> bool {
+		return env.iter().any(|(k, v)| return k == key && v == value);
+	}
+
+	#[test]
+	fn server_in_prefix() {
+		let _lock = crate::runner::lock_for_test();
+		let dir = TempDir::new("ade-extract-key-test").unwrap();
+		std::fs::create_dir(dir.path().join("bin")).unwrap();
+		std::fs::write(dir.path().join("bin/wine"), "").unwrap();
+		std::fs::write(dir.path().join("bin/wineserver"), "").unwrap();
+
+		crate::settings::set(Settings {
+			wine: Some(dir.path().to_path_buf()),
+			prefix: Some(PathBuf::from("/some/prefix")),
+			..Default::default()
+		});
+		let runner = use_runner(FakeRunner::new().respond("wineserver", success("")));
+		start_server();
+		// the "wine" commands get the same environment
+		let wine_env: Vec<(String, String)> = command()
+			.get_envs()
+			.filter_map(|(k, v)| return Some((k.to_string_lossy().into_owned(), v?.to_string_lossy().into_owned())))
+			.collect();
+
+		crate::settings::set(Settings::default());
+		crate::runner::set(Arc::new(crate::runner::ProcessRunner));
+
+		// the wineserver next to the configured wine is used
+		assert_eq!(
+			runner.calls(),
+			[format!(
+				"{} -p{SERVER_PERSIST_SECS}",
+				dir.path().join("bin/wineserver").display()
+			)]
+		);
+
+		let server_env = &runner.call_envs()[0];
+		assert!(has_env(server_env, "WINEPREFIX", "/some/prefix"), "{server_env:?}");
+
+		for (key, value) in DEFAULT_ENV {
+			if std::env::var_os(key).is_none() {
+				assert!(has_env(server_env, key, value), "{server_env:?}");
+			}
+		}
+
+		assert_eq!(server_env, &wine_env);
+	}
+
+	#[test]
+	fn server_without_prefix() {
+		let _lock = crate::runner::lock_for_test();
+
+		// "WINEPREFIX" is inherited, like by the wine commands
+		let runner = use_runner(FakeRunner::new().respond("wineserver", success("")));
+		start_server();
+
+		assert_eq!(runner.calls(), [format!("wineserver -p{SERVER_PERSIST_SECS}")]);
+		assert!(!runner.call_envs()[0].iter().any(|(k, _)| return k == "WINEPREFIX"));
+
+		// CrossOver bottles manage their own wineserver
+		crate::settings::set(Settings {
+			bottle: Some(String::from("ade")),
+			..Default::default()
+		});
+		let runner = use_runner(FakeRunner::new());
+		start_server();
+		let bottle_cmd = command_line(&command());
+
+		crate::settings::set(Settings::default());
+		crate::runner::set(Arc::new(crate::runner::ProcessRunner));
+
+		assert!(runner.calls().is_empty());
+		assert_eq!(bottle_cmd, "wine --bottle ade");
+	}
+}
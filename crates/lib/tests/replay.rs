@@ -0,0 +1,79 @@
+//! Replays the bundle recorded with "--record" in "fixtures" through the whole pipeline
+
+use std::{
+	path::{
+		Path,
+		PathBuf,
+	},
+	sync::{
+		Arc,
+		Mutex,
+		PoisonError,
+	},
+};
+
+use libade_extract_key::{
+	error::ErrorCategory,
+	pipeline::{
+		PipelineState,
+		Stage,
+	},
+	record::{
+		Bundle,
+		ReplayRunner,
+	},
+};
+
+/// The runner is process-wide, so tests that set it cannot run at the same time
+static RUNNER_LOCK: Mutex<()> = Mutex::new(());
+
+/// Get the path of the fixture "name"
+fn fixture(name: &str) -> PathBuf {
+	return Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name);
+}
+
+#[test]
+fn replay_bundle() {
+	let _lock = RUNNER_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+
+	let bundle = Bundle::load(&fixture("bundle.json")).expect("Expected the bundle to load");
+	libade_extract_key::runner::set(Arc::new(ReplayRunner::new(bundle)));
+
+	let mut stages = Vec::new();
+	let keys = PipelineState::default()
+		.run(|_state, stage| {
+			stages.push(stage);
+
+			return Ok(());
+		})
+		.expect("Expected the replay to succeed");
+
+	assert_eq!(stages, [Stage::Gather, Stage::Entropy, Stage::Dpapi]);
+	assert_eq!(keys.len(), 1);
+	assert_eq!(keys[0].expose(), std::fs::read(fixture("key.der")).unwrap());
+}
+
+#[test]
+fn replay_redacted_bundle() {
+	let _lock = RUNNER_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+
+	let mut bundle = Bundle::load(&fixture("bundle.json")).expect("Expected the bundle to load");
+	bundle.redact();
+
+	// only the wine version and the wineserver are left
+	assert_eq!(
+		bundle.commands.iter().map(|v| return v.redacted).collect::<Vec<_>>(),
+		[false, false, true, true]
+	);
+	let serialized = serde_json::to_string(&bundle).unwrap();
+	assert!(!serialized.contains("Serial Number"));
+	assert!(!serialized.contains("decrypted"));
+
+	libade_extract_key::runner::set(Arc::new(ReplayRunner::new(bundle)));
+
+	let err = PipelineState::default()
+		.run(|_state, _stage| return Ok(()))
+		.expect_err("Expected the replay to stop at the registry export");
+
+	assert_eq!(err.category(), ErrorCategory::Usage);
+}
@@ -1,9 +1,22 @@
 /// Decode the given "input" from hex into a [Vec<u8>] Array
+/// Checked per byte, so that non-ASCII input is a error instead of a panic
 #[cfg(windows)]
-fn decode_hex(input: &str) -> Result<Vec<u8>, std::num::ParseIntError> {
-	return (0..input.len())
+fn decode_hex(input: &str) -> Result<Vec<u8>, String> {
+	let bytes = input.as_bytes();
+
+	if bytes.len() % 2 != 0 {
+		return Err(format!("odd length {}", bytes.len()));
+	}
+
+	let digit = |i: usize| {
+		return char::from(bytes[i])
+			.to_digit(16)
+			.ok_or_else(|| return format!("invalid character at byte {i}"));
+	};
+
+	return (0..bytes.len())
 		.step_by(2)
-		.map(|i| return u8::from_str_radix(&input[i..i + 2], 16))
+		.map(|i| return Ok((digit(i)? * 16 + digit(i + 1)?) as u8))
 		.collect();
 }
 
@@ -19,36 +32,136 @@ fn encode_hex(bytes: &[u8]) -> String {
 	return s;
 }
 
-// Exit Codes:
+// Exit Codes (the same as "ade-extract-key", see "libade_extract_key::error::ErrorCategory"):
 // 0 - Everything is fine
-// -1 - Expected exactly 2 arguments
-// -2 - Decryption failed
-// -100 - Not compiled for windows
+// 2 - Invalid request on stdin (usage)
+// 3 - Not compiled for windows (environment)
+// 5 - Decryption failed (dpapi)
+
+/// Exit code for a invalid request
+#[cfg(windows)]
+const EXIT_USAGE: i32 = 2;
+/// Exit code for when not compiled for windows
+#[cfg(not(windows))]
+const EXIT_ENVIRONMENT: i32 = 3;
+/// Exit code for when the decryption failed
+#[cfg(windows)]
+const EXIT_DPAPI: i32 = 5;
+
+// Protocol (version 1), all lines are terminated by "\n", values are encoded in hex:
+// Request (stdin, read until EOF or a empty line):
+// ade-extract-winapi 1
+// entropy <hex>
+// data <hex>
+// Response (stdout):
+// ade-extract-winapi 1
+// decrypted <hex>
+// or on failure:
+// ade-extract-winapi 1
+// error <kind> <message>
+// where "kind" is one of "request", "decrypt" or "unsupported"
+// keep this in sync with "libade_extract_key::winapi_protocol"
+
+/// Header line that starts every request and response
+const PROTOCOL_HEADER: &str = "ade-extract-winapi";
+/// Version of the protocol this binary speaks
+const PROTOCOL_VERSION: u32 = 1;
+
+/// Print a protocol error response to stdout and exit with the given code
+fn respond_error(kind: &str, msg: &str, code: i32) -> ! {
+	println!("{PROTOCOL_HEADER} {PROTOCOL_VERSION}");
+	println!("error {kind} {msg}");
+	std::process::exit(code);
+}
 
 #[cfg(not(windows))]
 fn main() {
 	eprintln!("Binary only works on windows target");
-	std::process::exit(-100);
+	respond_error("unsupported", "Binary only works on windows target", EXIT_ENVIRONMENT);
 }
 
+/// The parsed request read from stdin
 #[cfg(windows)]
-fn main() {
-	let args: Vec<String> = std::env::args().collect();
+struct Request {
+	/// The entropy to pass to CryptUnprotectData
+	entropy: Vec<u8>,
+	/// The data to decrypt
+	data:    Vec<u8>,
+}
+
+/// Read and parse the request from the given input
+#[cfg(windows)]
+fn read_request<R: std::io::BufRead>(input: R) -> Result<Request, String> {
+	let mut lines = input.lines();
+
+	let header = lines
+		.next()
+		.ok_or_else(|| return String::from("Expected a header line, got EOF"))?
+		.map_err(|err| return format!("Failed to read header line: {err}"))?;
+	let expected_header = format!("{PROTOCOL_HEADER} {PROTOCOL_VERSION}");
+	if header.trim_end() != expected_header {
+		return Err(format!(
+			"Expected header \"{expected_header}\", got \"{}\"",
+			header.trim_end()
+		));
+	}
+
+	let mut entropy: Option<Vec<u8>> = None;
+	let mut data: Option<Vec<u8>> = None;
+
+	for line in lines {
+		let line = line.map_err(|err| return format!("Failed to read line: {err}"))?;
+		let line = line.trim_end();
+
+		if line.is_empty() {
+			break;
+		}
+
+		let (field, value) = line
+			.split_once(' ')
+			.ok_or_else(|| return format!("Expected \"<field> <value>\", got \"{line}\""))?;
+
+		let decoded =
+			decode_hex(value).map_err(|err| return format!("Failed to decode \"{field}\" from hex: {err}"))?;
 
-	if args.len() < 3 || args.len() > 3 {
-		eprintln!("Expected 2 arguments");
-		std::process::exit(-1);
+		match field {
+			"entropy" => entropy = Some(decoded),
+			"data" => data = Some(decoded),
+			_ => return Err(format!("Unknown field \"{field}\"")),
+		}
 	}
 
-	let entropy = &args[1];
-	let device_key = &args[2];
+	let Some(entropy) = entropy else {
+		return Err(String::from("Missing field \"entropy\""));
+	};
+	let Some(data) = data else {
+		return Err(String::from("Missing field \"data\""));
+	};
+
+	return Ok(Request { entropy, data });
+}
+
+#[cfg(windows)]
+fn main() {
+	if std::env::args().len() > 1 {
+		respond_error(
+			"request",
+			"Expected no arguments, the request has to be given on stdin",
+			EXIT_USAGE,
+		);
+	}
 
-	let mut entropy = decode_hex(entropy).expect("Expected to successfully decode entropy from hex");
-	let mut device_key_string = decode_hex(device_key).expect("Expected to successfully decode data from hex");
+	let Request {
+		mut entropy,
+		data: mut device_key_string,
+	} = match read_request(std::io::stdin().lock()) {
+		Ok(v) => v,
+		Err(msg) => respond_error("request", &msg, EXIT_USAGE),
+	};
 
 	let out_string: String = unsafe {
 		let mut blob_in = winapi::um::wincrypt::CRYPTOAPI_BLOB {
-			cbData: (device_key_string.len() + 1) as u32,
+			cbData: device_key_string.len() as u32,
 			pbData: device_key_string.as_mut_ptr(),
 		};
 		let mut blob_out = winapi::um::wincrypt::CRYPTOAPI_BLOB::default();
@@ -57,8 +170,6 @@ fn main() {
 			pbData: entropy.as_mut_ptr(),
 		};
 
-		eprintln!("before CryptUnprotectData");
-
 		let res = winapi::um::dpapi::CryptUnprotectData(
 			&mut blob_in,
 			std::ptr::null_mut(),
@@ -70,21 +181,22 @@ fn main() {
 		);
 
 		if res == 0 {
-			eprintln!("Decryption failed");
-			std::process::exit(-2);
+			respond_error("decrypt", "CryptUnprotectData failed", EXIT_DPAPI);
 		}
 
-		eprintln!("res {res}");
-
-		eprintln!("out1 {:#?}", blob_out.cbData);
-		eprintln!("out2 {:#?}", blob_out.pbData);
-
 		let out_bytes = std::slice::from_raw_parts(blob_out.pbData, blob_out.cbData as usize);
+		let out_hex = encode_hex(out_bytes);
+
+		// wipe the decrypted device key, it is only needed as hex from here on
+		std::ptr::write_bytes(blob_out.pbData, 0, blob_out.cbData as usize);
+		// the output buffer is allocated by CryptUnprotectData
+		winapi::um::winbase::LocalFree(blob_out.pbData.cast());
 
-		encode_hex(out_bytes)
+		out_hex
 	};
 
-	println!("decrypted {:#?}", out_string);
+	println!("{PROTOCOL_HEADER} {PROTOCOL_VERSION}");
+	println!("decrypted {out_string}");
 
 	std::process::exit(0);
 }